@@ -0,0 +1,264 @@
+//! Derive macros for the [`racetime`](https://docs.rs/racetime) crate. Use them via `racetime::command` with the `derive` feature enabled.
+
+#![deny(rust_2018_idioms, unused, unused_crate_dependencies, unused_import_braces, unused_qualifications, warnings)]
+#![forbid(unsafe_code)]
+
+use {
+    proc_macro::TokenStream,
+    proc_macro2::TokenStream as TokenStream2,
+    quote::quote,
+    syn::{
+        Attribute,
+        Data,
+        DeriveInput,
+        Expr,
+        ExprLit,
+        Field,
+        Fields,
+        GenericArgument,
+        Ident,
+        Lit,
+        LitStr,
+        Meta,
+        PathArguments,
+        Type,
+        parse_macro_input,
+    },
+};
+
+/// See `racetime::command::RaceCommand` for documentation.
+#[proc_macro_derive(RaceCommand, attributes(command))]
+pub fn race_command(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    race_command_impl(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// See `racetime::command::FromArg` for documentation.
+#[proc_macro_derive(FromArg, attributes(command))]
+pub fn from_arg(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_arg_impl(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[derive(Default)]
+struct VariantAttrs {
+    name: Option<String>,
+    aliases: Vec<String>,
+    permission: Option<Ident>,
+}
+
+impl VariantAttrs {
+    fn parse(attrs: &[Attribute], allow_permission: bool) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs {
+            if !attr.path().is_ident("command") { continue }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    parsed.name = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("alias") {
+                    parsed.aliases.push(meta.value()?.parse::<LitStr>()?.value());
+                } else if allow_permission && meta.path.is_ident("anyone") {
+                    parsed.permission = Some(Ident::new("Anyone", meta.path.require_ident()?.span()));
+                } else if allow_permission && meta.path.is_ident("entrant") {
+                    parsed.permission = Some(Ident::new("Entrant", meta.path.require_ident()?.span()));
+                } else if allow_permission && meta.path.is_ident("monitor") {
+                    parsed.permission = Some(Ident::new("Monitor", meta.path.require_ident()?.span()));
+                } else if allow_permission && meta.path.is_ident("moderator") {
+                    parsed.permission = Some(Ident::new("Moderator", meta.path.require_ident()?.span()));
+                } else {
+                    return Err(meta.error("unsupported command attribute"))
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    name: Option<String>,
+    rest: bool,
+}
+
+impl FieldAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs {
+            if !attr.path().is_ident("command") { continue }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    parsed.name = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("rest") {
+                    parsed.rest = true;
+                } else {
+                    return Err(meta.error("unsupported command attribute"))
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines = attrs.iter()
+        .filter_map(|attr| if let Meta::NameValue(ref meta) = attr.meta {
+            if !meta.path.is_ident("doc") { return None }
+            if let Expr::Lit(ExprLit { lit: Lit::Str(ref lit), .. }) = meta.value { Some(lit.value().trim().to_owned()) } else { None }
+        } else {
+            None
+        })
+        .collect::<Vec<_>>();
+    let doc = lines.join(" ").trim().to_owned();
+    (!doc.is_empty()).then_some(doc)
+}
+
+/// Returns the `T` in `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(ref path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" { return None }
+    let PathArguments::AngleBracketed(ref args) = segment.arguments else { return None };
+    if args.args.len() != 1 { return None }
+    if let Some(GenericArgument::Type(inner)) = args.args.first() { Some(inner) } else { None }
+}
+
+/// Returns the expression parsing a field and the expression generating its usage text.
+fn field_parser(field: &Field, default_name: String, is_last: bool) -> syn::Result<(TokenStream2, TokenStream2)> {
+    let FieldAttrs { name, rest } = FieldAttrs::parse(&field.attrs)?;
+    let name = name.unwrap_or(default_name);
+    let inner = option_inner(&field.ty);
+    Ok(if rest {
+        if !is_last {
+            return Err(syn::Error::new_spanned(field, "#[command(rest)] can only be used on the last field"))
+        }
+        if inner.is_some() {
+            (quote!(parser.optional_rest(#name)?), quote!(::racetime::command::ArgParser::rest_usage(#name, true)))
+        } else {
+            (quote!(parser.rest(#name)?), quote!(::racetime::command::ArgParser::rest_usage(#name, false)))
+        }
+    } else if let Some(inner) = inner {
+        (quote!(parser.optional::<#inner>(#name)?), quote!(::racetime::command::ArgParser::usage::<#inner>(#name, true)))
+    } else {
+        let ty = &field.ty;
+        (quote!(parser.required::<#ty>(#name)?), quote!(::racetime::command::ArgParser::usage::<#ty>(#name, false)))
+    })
+}
+
+fn race_command_impl(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(ref data) = input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "RaceCommand can only be derived for enums"))
+    };
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut infos = Vec::default();
+    let mut arms = Vec::default();
+    for variant in &data.variants {
+        let VariantAttrs { name, aliases, permission } = VariantAttrs::parse(&variant.attrs, true)?;
+        let name = name.unwrap_or_else(|| variant.ident.to_string().to_lowercase());
+        let description = match doc_comment(&variant.attrs) {
+            Some(description) => quote!(::std::option::Option::Some(::std::string::String::from(#description))),
+            None => quote!(::std::option::Option::None),
+        };
+        let permission = permission.unwrap_or_else(|| Ident::new("Anyone", variant.ident.span()));
+        let variant_ident = &variant.ident;
+        let (construct, usages) = match variant.fields {
+            Fields::Unit => (quote!(Self::#variant_ident), Vec::default()),
+            Fields::Named(ref fields) => {
+                let mut inits = Vec::default();
+                let mut usages = Vec::default();
+                for (idx, field) in fields.named.iter().enumerate() {
+                    let field_ident = field.ident.as_ref().expect("named field without name");
+                    let (parse, usage) = field_parser(field, field_ident.to_string(), idx == fields.named.len() - 1)?;
+                    inits.push(quote!(#field_ident: #parse));
+                    usages.push(usage);
+                }
+                (quote!(Self::#variant_ident { #(#inits,)* }), usages)
+            }
+            Fields::Unnamed(ref fields) => {
+                let mut values = Vec::default();
+                let mut usages = Vec::default();
+                for (idx, field) in fields.unnamed.iter().enumerate() {
+                    let (parse, usage) = field_parser(field, format!("arg{}", idx + 1), idx == fields.unnamed.len() - 1)?;
+                    values.push(parse);
+                    usages.push(usage);
+                }
+                (quote!(Self::#variant_ident(#(#values,)*)), usages)
+            }
+        };
+        infos.push(quote! {
+            ::racetime::command::CommandInfo {
+                name: ::std::string::String::from(#name),
+                aliases: ::std::vec![#(::std::string::String::from(#aliases),)*],
+                description: #description,
+                usage: {
+                    let mut usage = ::std::format!("!{}", #name);
+                    #(
+                        usage.push(' ');
+                        usage.push_str(&#usages);
+                    )*
+                    usage
+                },
+                permission: ::racetime::command::Permission::#permission,
+            }
+        });
+        arms.push(quote!(#name => #construct,));
+    }
+    Ok(quote! {
+        impl #impl_generics ::racetime::command::RaceCommand for #ident #ty_generics #where_clause {
+            fn commands() -> ::std::vec::Vec<::racetime::command::CommandInfo> {
+                ::std::vec![#(#infos,)*]
+            }
+
+            #[allow(unused_mut)]
+            fn parse_args(race_data: &::racetime::model::RaceData, name: &str, args: ::std::vec::Vec<::std::string::String>) -> ::std::result::Result<Self, ::std::string::String> {
+                let mut parser = ::racetime::command::ArgParser::new(race_data, args);
+                let command = match name {
+                    #(#arms)*
+                    _ => return ::std::result::Result::Err(::std::format!("unknown command !{name}")),
+                };
+                parser.finish()?;
+                ::std::result::Result::Ok(command)
+            }
+        }
+    })
+}
+
+fn from_arg_impl(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(ref data) = input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "FromArg can only be derived for enums"))
+    };
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut names = Vec::default();
+    let mut arms = Vec::default();
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new_spanned(&variant.fields, "FromArg can only be derived for enums without fields"))
+        }
+        let VariantAttrs { name, aliases, .. } = VariantAttrs::parse(&variant.attrs, false)?;
+        let name = name.unwrap_or_else(|| variant.ident.to_string().to_lowercase());
+        let variant_ident = &variant.ident;
+        arms.push(quote! {
+            if arg.eq_ignore_ascii_case(#name) #(|| arg.eq_ignore_ascii_case(#aliases))* {
+                return ::std::result::Result::Ok(Self::#variant_ident)
+            }
+        });
+        names.push(name);
+    }
+    let choices = names.join(", ");
+    let usage = names.join("|");
+    Ok(quote! {
+        impl #impl_generics ::racetime::command::FromArg for #ident #ty_generics #where_clause {
+            fn from_arg(_: &::racetime::model::RaceData, arg: &str) -> ::std::result::Result<Self, ::std::string::String> {
+                #(#arms)*
+                ::std::result::Result::Err(::std::format!("{arg:?} is not one of {}", #choices))
+            }
+
+            fn usage() -> ::std::option::Option<::std::string::String> {
+                ::std::option::Option::Some(::std::string::String::from(#usage))
+            }
+        }
+    })
+}
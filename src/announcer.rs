@@ -0,0 +1,229 @@
+//! Automatic announcements of the countdown, elapsed time, and time limit of a race.
+//!
+//! Build an [`Announcer`] once and return it from [`RaceHandler::announcer`](crate::RaceHandler::announcer):
+//!
+//! ```ignore
+//! let announcer = Announcer::new()
+//!     .countdown([10, 5, 3, 2, 1].map(Duration::from_secs))
+//!     .elapsed_every(Duration::from_secs(30 * 60))
+//!     .time_limit_warnings([Duration::from_secs(60 * 60), Duration::from_secs(10 * 60)]);
+//! ```
+//!
+//! Announcements are sent as chat messages based on the race's [`started_at`](crate::model::RaceData::started_at) and [`time_limit`](crate::model::RaceData::time_limit) fields, so they stay correct if the race data changes. Announcements which were due while the bot wasn't connected are not sent, and if several are due at once, only the latest one is sent.
+
+use {
+    std::time::Duration as StdDuration,
+    chrono::{
+        Duration,
+        prelude::*,
+    },
+    crate::model::*,
+};
+
+/// A set of automatic race announcements. See the [module-level documentation](self) for details.
+///
+/// Message templates can contain the placeholders `{remaining}` and `{elapsed}`, which are replaced with durations like `1h 30m`.
+pub struct Announcer {
+    countdown: Vec<Duration>,
+    countdown_message: String,
+    elapsed_interval: Option<Duration>,
+    elapsed_message: String,
+    time_limit_warnings: Vec<Duration>,
+    time_limit_message: String,
+}
+
+impl Announcer {
+    /// Creates an announcer which doesn't announce anything.
+    pub fn new() -> Self {
+        Self {
+            countdown: Vec::default(),
+            countdown_message: "The race starts in {remaining}…".to_owned(),
+            elapsed_interval: None,
+            elapsed_message: "{elapsed} elapsed.".to_owned(),
+            time_limit_warnings: Vec::default(),
+            time_limit_message: "{remaining} left until the time limit.".to_owned(),
+        }
+    }
+
+    /// Announces the countdown when the given amounts of time are left before the race starts.
+    pub fn countdown(mut self, remaining: impl IntoIterator<Item = StdDuration>) -> Self {
+        self.countdown.extend(remaining.into_iter().map(to_chrono));
+        self
+    }
+
+    /// Sets the message template for countdown announcements. `{remaining}` is replaced with the time until the race starts. Defaults to `The race starts in {remaining}…`.
+    pub fn countdown_message(mut self, template: impl Into<String>) -> Self {
+        self.countdown_message = template.into();
+        self
+    }
+
+    /// Announces the elapsed time at multiples of `interval` after the race starts, until it ends.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is shorter than a millisecond.
+    pub fn elapsed_every(mut self, interval: StdDuration) -> Self {
+        assert!(interval.as_millis() > 0, "elapsed time announcement interval must be at least a millisecond");
+        self.elapsed_interval = Some(to_chrono(interval));
+        self
+    }
+
+    /// Sets the message template for elapsed time announcements. `{elapsed}` is replaced with the time since the race started. Defaults to `{elapsed} elapsed.`.
+    pub fn elapsed_message(mut self, template: impl Into<String>) -> Self {
+        self.elapsed_message = template.into();
+        self
+    }
+
+    /// Warns when the given amounts of time are left before the race's time limit is reached.
+    pub fn time_limit_warnings(mut self, remaining: impl IntoIterator<Item = StdDuration>) -> Self {
+        self.time_limit_warnings.extend(remaining.into_iter().map(to_chrono));
+        self
+    }
+
+    /// Sets the message template for time limit warnings. `{remaining}` is replaced with the time until the time limit is reached, and `{elapsed}` with the time since the race started. Defaults to `{remaining} left until the time limit.`.
+    pub fn time_limit_message(mut self, template: impl Into<String>) -> Self {
+        self.time_limit_message = template.into();
+        self
+    }
+
+    /// Returns the first announcement for the given race scheduled strictly after `after`, along with its scheduled time.
+    pub(crate) fn next(&self, race: &RaceData, after: DateTime<Utc>) -> Option<(DateTime<Utc>, String)> {
+        let started_at = race.started_at?;
+        let mut announcements = Vec::default();
+        match race.status.value {
+            RaceStatusValue::Pending => for &remaining in &self.countdown {
+                if let Some(at) = started_at.checked_sub_signed(remaining) {
+                    announcements.push((at, self.countdown_message.replace("{remaining}", &format_duration(remaining))));
+                }
+            },
+            RaceStatusValue::InProgress => {
+                if let Some(interval) = self.elapsed_interval {
+                    let count = if after < started_at { 1 } else { (after - started_at).num_milliseconds() / interval.num_milliseconds() + 1 };
+                    let elapsed = Duration::milliseconds(interval.num_milliseconds().saturating_mul(count));
+                    if let Some(at) = started_at.checked_add_signed(elapsed) {
+                        announcements.push((at, self.elapsed_message.replace("{elapsed}", &format_duration(elapsed))));
+                    }
+                }
+                for &remaining in &self.time_limit_warnings {
+                    if remaining < race.time_limit {
+                        let elapsed = race.time_limit - remaining;
+                        let Some(at) = started_at.checked_add_signed(elapsed) else { continue };
+                        announcements.push((at, self.time_limit_message.replace("{remaining}", &format_duration(remaining)).replace("{elapsed}", &format_duration(elapsed))));
+                    }
+                }
+            }
+            RaceStatusValue::Open | RaceStatusValue::Invitational | RaceStatusValue::Finished | RaceStatusValue::Cancelled => {}
+        }
+        announcements.into_iter().filter(|&(at, _)| at > after).min_by_key(|&(at, _)| at)
+    }
+}
+
+impl Default for Announcer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn to_chrono(duration: StdDuration) -> Duration {
+    Duration::from_std(duration).unwrap_or(Duration::MAX)
+}
+
+/// Formats a duration like `1h 5m 30s`, rounded to the second and omitting zero components.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_milliseconds().saturating_add(500) / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let parts = [(hours, "h"), (minutes, "m"), (seconds, "s")].into_iter()
+        .filter(|&(value, _)| value > 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect::<Vec<_>>();
+    if parts.is_empty() { "0s".to_owned() } else { parts.join(" ") }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::test_util,
+        super::*,
+    };
+
+    fn started_race(status: RaceStatusValue) -> (RaceData, DateTime<Utc>) {
+        let mut race = test_util::race(status);
+        let started_at = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        race.started_at = Some(started_at);
+        (race, started_at)
+    }
+
+    #[test]
+    fn not_scheduled() {
+        let announcer = Announcer::new()
+            .countdown([StdDuration::from_secs(10)])
+            .elapsed_every(StdDuration::from_secs(30 * 60))
+            .time_limit_warnings([StdDuration::from_secs(60 * 60)]);
+        let race = test_util::race(RaceStatusValue::Pending);
+        assert_eq!(announcer.next(&race, Utc::now()), None);
+        for status in [RaceStatusValue::Open, RaceStatusValue::Invitational, RaceStatusValue::Finished, RaceStatusValue::Cancelled] {
+            let (race, started_at) = started_race(status);
+            assert_eq!(announcer.next(&race, started_at - Duration::minutes(1)), None);
+        }
+        let (race, started_at) = started_race(RaceStatusValue::InProgress);
+        assert_eq!(Announcer::new().next(&race, started_at), None);
+    }
+
+    #[test]
+    fn countdown() {
+        let announcer = Announcer::new().countdown([10, 5, 1].map(StdDuration::from_secs));
+        let (race, started_at) = started_race(RaceStatusValue::Pending);
+        assert_eq!(announcer.next(&race, started_at - Duration::minutes(1)), Some((started_at - Duration::seconds(10), "The race starts in 10s…".to_owned())));
+        assert_eq!(announcer.next(&race, started_at - Duration::seconds(10)), Some((started_at - Duration::seconds(5), "The race starts in 5s…".to_owned())));
+        assert_eq!(announcer.next(&race, started_at - Duration::seconds(1)), None);
+        let announcer = announcer.countdown_message("Starting in {remaining}");
+        assert_eq!(announcer.next(&race, started_at - Duration::seconds(5)), Some((started_at - Duration::seconds(1), "Starting in 1s".to_owned())));
+    }
+
+    #[test]
+    fn elapsed() {
+        let announcer = Announcer::new().elapsed_every(StdDuration::from_secs(30 * 60));
+        let (race, started_at) = started_race(RaceStatusValue::InProgress);
+        assert_eq!(announcer.next(&race, started_at - Duration::seconds(1)), Some((started_at + Duration::minutes(30), "30m elapsed.".to_owned())));
+        assert_eq!(announcer.next(&race, started_at), Some((started_at + Duration::minutes(30), "30m elapsed.".to_owned())));
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(30)), Some((started_at + Duration::hours(1), "1h elapsed.".to_owned())));
+        let announcer = announcer.elapsed_message("{elapsed} in");
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(60)), Some((started_at + Duration::minutes(90), "1h 30m in".to_owned())));
+    }
+
+    #[test]
+    fn time_limit_warnings() {
+        let announcer = Announcer::new()
+            .elapsed_every(StdDuration::from_secs(45 * 60))
+            .time_limit_warnings([60 * 60, 10 * 60, 48 * 60 * 60].map(StdDuration::from_secs));
+        let (race, started_at) = started_race(RaceStatusValue::InProgress);
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(22 * 60 + 50)), Some((started_at + Duration::hours(23), "1h left until the time limit.".to_owned())));
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(23 * 60 + 30)), Some((started_at + Duration::minutes(23 * 60 + 50), "10m left until the time limit.".to_owned())));
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(23 * 60 + 50)), Some((started_at + Duration::hours(24), "24h elapsed.".to_owned())));
+        let announcer = announcer.time_limit_message("{remaining} left after {elapsed}");
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(23 * 60 + 30)), Some((started_at + Duration::minutes(23 * 60 + 50), "10m left after 23h 50m".to_owned())));
+    }
+
+    #[test]
+    fn skips_past_due() {
+        let announcer = Announcer::new()
+            .countdown([10, 5, 3, 2, 1].map(StdDuration::from_secs))
+            .elapsed_every(StdDuration::from_secs(30 * 60))
+            .time_limit_warnings([StdDuration::from_secs(60 * 60)]);
+        let (race, started_at) = started_race(RaceStatusValue::Pending);
+        // announcements due between the previous one and now were missed and are not sent late
+        assert_eq!(announcer.next(&race, started_at - Duration::milliseconds(4_500)), Some((started_at - Duration::seconds(3), "The race starts in 3s…".to_owned())));
+        let (race, started_at) = started_race(RaceStatusValue::InProgress);
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(95)), Some((started_at + Duration::hours(2), "2h elapsed.".to_owned())));
+        assert_eq!(announcer.next(&race, started_at + Duration::minutes(23 * 60 + 5)), Some((started_at + Duration::minutes(23 * 60 + 30), "23h 30m elapsed.".to_owned())));
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_duration(Duration::zero()), "0s");
+        assert_eq!(format_duration(Duration::milliseconds(1_499)), "1s");
+        assert_eq!(format_duration(Duration::milliseconds(1_500)), "2s");
+        assert_eq!(format_duration(Duration::seconds(3_600 + 5 * 60 + 30)), "1h 5m 30s");
+        assert_eq!(format_duration(Duration::seconds(2 * 3_600 + 30)), "2h 30s");
+    }
+}
@@ -0,0 +1,104 @@
+//! Access tokens for the racetime.gg API.
+//!
+//! A [`TokenProvider`] can be shared between a [`Bot`](crate::Bot) (see [`Bot::builder_with_token_provider`](crate::Bot::builder_with_token_provider), [`Bot::add_category_with_token_provider`](crate::Bot::add_category_with_token_provider), and [`Bot::token_provider`](crate::Bot::token_provider)) and other code using the API, such as [`StartRace::start_with_token_provider`](crate::StartRace::start_with_token_provider), so that they don't each request their own tokens:
+//!
+//! ```ignore
+//! let token_provider = Arc::new(ClientCredentials::new(client_id, client_secret, client.clone()));
+//! let bot = Bot::builder_with_token_provider(category_slug, token_provider.clone(), state).build().await?;
+//! let slug = race.start_with_token_provider(&*token_provider, &client, category_slug).await?;
+//! ```
+
+use {
+    std::time::Duration,
+    async_trait::async_trait,
+    tokio::{
+        sync::Mutex,
+        time::Instant,
+    },
+    crate::{
+        Error,
+        HostInfo,
+        authorize_with_host,
+    },
+};
+
+/// If refreshing a token fails with a server or network error, the current token keeps being used as long as it's valid for at least this long.
+const MIN_REMAINING_LIFETIME: Duration = Duration::from_secs(30);
+
+/// A source of OAuth access tokens.
+///
+/// This trait should be implemented using the [`macro@async_trait`] attribute.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    /// Returns a valid access token, refreshing it first if necessary.
+    async fn access_token(&self) -> Result<String, Error>;
+}
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+    refresh_at: Instant,
+}
+
+/// A [`TokenProvider`] which gets tokens using the OAuth client credentials flow.
+///
+/// The token is cached and refreshed once half of its lifetime has passed. Concurrent calls share a single refresh. If refreshing fails with a server or network error, the current token keeps being used and refreshing is retried after half of its remaining lifetime, until it's about to expire.
+pub struct ClientCredentials {
+    host_info: HostInfo,
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    token: Mutex<Option<CachedToken>>,
+}
+
+impl ClientCredentials {
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>, client: reqwest::Client) -> Self {
+        Self::new_with_host(HostInfo::default(), client_id, client_secret, client)
+    }
+
+    pub fn new_with_host(host_info: HostInfo, client_id: impl Into<String>, client_secret: impl Into<String>, client: reqwest::Client) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            token: Mutex::default(),
+            host_info, client,
+        }
+    }
+
+    pub(crate) fn matches(&self, client_id: &str, client_secret: &str) -> bool {
+        self.client_id == client_id && self.client_secret == client_secret
+    }
+}
+
+#[async_trait]
+impl TokenProvider for ClientCredentials {
+    async fn access_token(&self) -> Result<String, Error> {
+        let mut token = self.token.lock().await;
+        let now = Instant::now();
+        if let Some(ref mut token) = *token {
+            if now < token.refresh_at {
+                return Ok(token.access_token.clone())
+            }
+        }
+        match authorize_with_host(&self.host_info, &self.client_id, &self.client_secret, &self.client).await {
+            Ok((access_token, lifetime)) => {
+                *token = Some(CachedToken {
+                    expires_at: now + lifetime,
+                    refresh_at: now + lifetime / 2,
+                    access_token: access_token.clone(),
+                });
+                Ok(access_token)
+            }
+            Err(Error::Reqwest(e)) if e.status().is_none_or(|status| status.is_server_error()) => match *token {
+                // racetime.gg's auth endpoint has been known to return server errors intermittently, and we should also resist intermittent network errors.
+                Some(ref mut token) if token.expires_at.saturating_duration_since(now) >= MIN_REMAINING_LIFETIME => {
+                    log!(warn, "failed to refresh access token, retrying later: {e}");
+                    token.refresh_at = now + token.expires_at.saturating_duration_since(now) / 2;
+                    Ok(token.access_token.clone())
+                }
+                _ => Err(Error::Reqwest(e)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
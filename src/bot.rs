@@ -1,14 +1,19 @@
 use {
     std::{
-        collections::HashSet,
+        collections::{
+            HashMap,
+            HashSet,
+            VecDeque,
+        },
         convert::Infallible as Never,
         fmt,
         mem,
+        pin::Pin,
         sync::Arc,
         time::Duration,
     },
+    chrono::prelude::*,
     futures::{
-        SinkExt as _,
         future::{
             self,
             Future,
@@ -16,99 +21,719 @@ use {
         },
         stream::StreamExt as _,
     },
+    rand::Rng as _,
+    serde_json::{
+        Value as Json,
+        json,
+    },
     tokio::{
         io,
-        net::TcpStream,
         sync::{
             Mutex,
             RwLock,
             mpsc,
+            oneshot,
+            watch,
         },
+        task::AbortHandle,
         time::{
             Instant,
             MissedTickBehavior,
             interval,
-            interval_at,
             sleep,
+            sleep_until,
+            timeout_at,
         },
     },
-    tokio_tungstenite::tungstenite::{
-        self,
-        client::IntoClientRequest as _,
+    tokio_tungstenite::{
+        tungstenite::{
+            self,
+            client::IntoClientRequest as _,
+            protocol::CloseFrame,
+        },
     },
     crate::{
         Error,
         HostInfo,
-        authorize_with_host,
+        auth::{
+            ClientCredentials,
+            TokenProvider,
+        },
+        event::RaceEvent,
+        message::ActionInvocation,
         handler::{
+            ErrorAction,
             RaceContext,
             RaceHandler,
-            WsStream,
         },
         model::*,
+        persistence::StateStore,
+        queue::{
+            RateLimit,
+            SendQueue,
+            TokenBucket,
+            Writer,
+        },
+        registry::RoomRegistry,
+        stream::{
+            BotEvent,
+            BotEvents,
+            StreamHandler,
+        },
+        transport::{
+            Connector,
+            MessageSink,
+            MessageStream,
+            TungsteniteConnector,
+        },
     },
 };
 
-const SCAN_RACES_EVERY: Duration = Duration::from_secs(30);
+const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_EXTRA_ROOM_CAPACITY: usize = 1_024;
+const USER_AGENT: &str = concat!("racetime-rs/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_ROOM_RATE_LIMIT: RateLimit = RateLimit::new(5, Duration::from_secs(1));
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_RECONNECT_RESET_AFTER: Duration = Duration::from_secs(60 * 60 * 24);
+/// How many chat message IDs are remembered per race room to avoid passing messages replayed after a reconnect to the handler again.
+const SEEN_MESSAGES_CAPACITY: usize = 1_000;
+const SYNC_ERROR: &str = "Possible sync error. Refresh to continue.";
 
-enum ErrorContext {
+/// Describes where an error in a race room occurred. Passed to [`RaceHandler::on_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorContext {
+    Announcement,
     ChatDelete,
     ChatHistory,
     ChatMessage,
     ChatPurge,
+    Close,
     Decode,
+    Disconnected,
     End,
+    MessageAction,
+    Moderation,
     New,
     Ping,
+    Persistence,
     Pong,
     RaceData,
+    RaceEvent,
     RaceRenders,
     RaceSplit,
     Reconnect,
+    Reconnected,
     Recv,
+    Restore,
+    SaveState,
     ServerError,
     ShouldStop,
+    Timer,
 }
 
 impl fmt::Display for ErrorContext {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Announcement => write!(f, "while sending announcement"),
             Self::ChatDelete => write!(f, "from chat_delete callback"),
             Self::ChatHistory => write!(f, "from chat_history callback"),
             Self::ChatMessage => write!(f, "from chat_message callback"),
             Self::ChatPurge => write!(f, "from chat_purge callback"),
+            Self::Close => write!(f, "while closing the connection"),
             Self::Decode => write!(f, "while decoding server message"),
+            Self::Disconnected => write!(f, "from disconnected callback"),
             Self::End => write!(f, "from end callback"),
+            Self::MessageAction => write!(f, "from message_action callback"),
+            Self::Moderation => write!(f, "while moderating chat"),
             Self::New => write!(f, "from RaceHandler constructor"),
             Self::Ping => write!(f, "while sending ping"),
+            Self::Persistence => write!(f, "while accessing the state store"),
             Self::Pong => write!(f, "from pong callback"),
             Self::RaceData => write!(f, "from race_data callback"),
+            Self::RaceEvent => write!(f, "from race_event callback"),
             Self::RaceRenders => write!(f, "from race_renders callback"),
             Self::RaceSplit => write!(f, "from race_split callback"),
             Self::Reconnect => write!(f, "while trying to reconnect"),
+            Self::Reconnected => write!(f, "from reconnected callback"),
             Self::Recv => write!(f, "while waiting for message from server"),
+            Self::Restore => write!(f, "from restore callback"),
+            Self::SaveState => write!(f, "from save_state callback"),
             Self::ServerError => write!(f, "from error callback"),
             Self::ShouldStop => write!(f, "from should_stop callback"),
+            Self::Timer => write!(f, "from timer callback"),
+        }
+    }
+}
+
+/// A kind of error which a [`ReconnectPolicy`] can treat as a transient network failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransientError {
+    /// An I/O error of the given kind, e.g. [`ConnectionReset`](io::ErrorKind::ConnectionReset).
+    Io(io::ErrorKind),
+    /// The server reset the connection without a closing handshake.
+    ResetWithoutClosingHandshake,
+    /// The server responded to the WebSocket handshake with a 5xx status code.
+    HandshakeServerError,
+    /// Requesting an access token failed because of a network error or a 5xx status code, e.g. when racetime.gg's `/o/token` endpoint is briefly unavailable while reconnecting.
+    TokenRequest,
+}
+
+impl TransientError {
+    fn matches(&self, error: &Error) -> bool {
+        match (self, error) {
+            (Self::Io(kind), Error::Io(e) | Error::Tungstenite(tungstenite::Error::Io(e))) => e.kind() == *kind,
+            (Self::ResetWithoutClosingHandshake, Error::Tungstenite(tungstenite::Error::Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake))) => true,
+            (Self::HandshakeServerError, Error::Tungstenite(tungstenite::Error::Http(response))) => response.status().is_server_error(),
+            (Self::TokenRequest, Error::Reqwest(e)) => e.status().is_none_or(|status| status.is_server_error()),
+            (_, _) => false,
+        }
+    }
+}
+
+/// Determines which failures of a race room connection are transient and how the bot reconnects after them. Set using [`Bot::set_reconnect_policy`].
+///
+/// Transient failures cause the bot to reconnect without calling [`RaceHandler::on_error`], but [`RaceHandler::disconnected`] and [`RaceHandler::reconnected`] are called. The bot also reconnects using this policy if no `pong` is received in time (see [`Bot::set_ping_interval`]) and when [`RaceHandler::on_error`] returns [`ErrorAction::Reconnect`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// How long to wait before the first reconnection attempt. Each further attempt waits twice as long as the previous one.
+    pub initial_delay: Duration,
+    /// The longest time to wait before a reconnection attempt.
+    pub max_delay: Duration,
+    /// The fraction by which each delay is randomly shortened or lengthened, between 0 and 1, so that rooms which lost their connection at the same time don't all reconnect at once.
+    pub jitter: f64,
+    /// How many times in a row the bot tries to reconnect before giving up and passing the error to [`RaceHandler::on_error`] with [`ErrorContext::Reconnect`]. Failed attempts are only retried if the error is transient. `None` means no limit.
+    pub max_attempts: Option<u32>,
+    /// Once a connection has stayed up for this long, the delay is reset to `initial_delay`.
+    pub reset_after: Duration,
+    /// The errors which are considered transient when they occur while waiting for a message from the server or while connecting.
+    pub transient_errors: Vec<TransientError>,
+    /// The WebSocket close codes which are considered transient when the server closes the connection.
+    pub transient_close_codes: Vec<u16>,
+    /// The WebSocket close reasons which are considered transient when the server closes the connection, regardless of the close code.
+    pub transient_close_reasons: Vec<String>,
+    /// Whether to reconnect when the server sends the error “Possible sync error. Refresh to continue.” instead of passing it to [`RaceHandler::error`].
+    ///
+    /// This error is not documented by racetime.gg. Despite what it sounds like, it is usually caused by the client (see <https://github.com/racetimeGG/racetime-app/pull/196>), so this is disabled by default.
+    pub reconnect_on_sync_error: bool,
+}
+
+impl ReconnectPolicy {
+    /// Returns whether the given error is considered transient by this policy.
+    pub fn is_transient(&self, error: &Error) -> bool {
+        self.transient_errors.iter().any(|transient| transient.matches(error))
+    }
+
+    fn is_transient_close(&self, frame: &CloseFrame<'_>) -> bool {
+        self.transient_close_codes.contains(&u16::from(frame.code)) || self.transient_close_reasons.iter().any(|reason| *reason == frame.reason)
+    }
+
+    /// The delay before a reconnection attempt, given the number of previous attempts since the delay was last reset.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter > 0.0 {
+            delay.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+        } else {
+            delay
+        }
+    }
+}
+
+/// Defaults to a delay starting at 1 second, growing to at most 5 minutes and reset after 24 hours without reconnecting, with 10% jitter and no limit on attempts. Sync errors don't cause a reconnect.
+///
+/// Connection resets and aborts, refused connections, timeouts, unexpected ends of file, broken pipes, resets without a closing handshake, server errors during the handshake, and failed access token requests (see [`TransientError::TokenRequest`]) are considered transient, as are the close codes 1001 (going away), 1011 (internal error), 1012 (service restart), and 1013 (try again later) and the close reason sent when racetime.gg's CloudFlare proxy restarts.
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: DEFAULT_RECONNECT_INITIAL_DELAY,
+            max_delay: DEFAULT_RECONNECT_MAX_DELAY,
+            jitter: 0.1,
+            max_attempts: None,
+            reset_after: DEFAULT_RECONNECT_RESET_AFTER,
+            transient_errors: vec![
+                TransientError::Io(io::ErrorKind::ConnectionReset),
+                TransientError::Io(io::ErrorKind::ConnectionAborted),
+                TransientError::Io(io::ErrorKind::ConnectionRefused),
+                TransientError::Io(io::ErrorKind::TimedOut),
+                TransientError::Io(io::ErrorKind::UnexpectedEof),
+                TransientError::Io(io::ErrorKind::BrokenPipe),
+                TransientError::ResetWithoutClosingHandshake,
+                TransientError::HandshakeServerError,
+                TransientError::TokenRequest,
+            ],
+            transient_close_codes: vec![1001, 1011, 1012, 1013],
+            transient_close_reasons: vec!["CloudFlare WebSocket proxy restarting".to_owned()],
+            reconnect_on_sync_error: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
+/// The websocket connection to a race room, along with the state needed to reestablish it.
+struct Connection {
+    stream: MessageStream,
+    writer: Writer,
+    connector: Arc<dyn Connector>,
+    client: reqwest::Client,
+    policy: Arc<ReconnectPolicy>,
+    /// Whether the connection is currently established, i.e. `false` if the bot was shut down while reconnecting.
+    connected: bool,
+    connected_at: Instant,
+    /// The number of reconnection attempts since the delay was last reset.
+    attempts: u32,
+    heartbeat: Option<Heartbeat>,
+    last_ping: Instant,
+    pong_deadline: Option<Instant>,
+    seen_messages: HashSet<String>,
+    seen_messages_order: VecDeque<String>,
+}
+
+impl Connection {
+    fn new(stream: MessageStream, writer: Writer, connector: Arc<dyn Connector>, client: reqwest::Client, policy: Arc<ReconnectPolicy>, heartbeat: Option<Heartbeat>) -> Self {
+        Self {
+            connected: true,
+            connected_at: Instant::now(),
+            attempts: 0,
+            last_ping: Instant::now(),
+            pong_deadline: None,
+            seen_messages: HashSet::default(),
+            seen_messages_order: VecDeque::default(),
+            stream, writer, connector, client, policy, heartbeat,
+        }
+    }
+
+    /// Remembers the ID of a chat message. Returns `false` if it had already been seen.
+    fn mark_seen(&mut self, message: &ChatMessage) -> bool {
+        if !self.seen_messages.insert(message.id.clone()) { return false }
+        self.seen_messages_order.push_back(message.id.clone());
+        if self.seen_messages_order.len() > SEEN_MESSAGES_CAPACITY {
+            if let Some(id) = self.seen_messages_order.pop_front() {
+                self.seen_messages.remove(&id);
+            }
+        }
+        true
+    }
+
+    /// Returns when the next ping should be sent, or when the connection should be considered dead if no pong has been received by then.
+    fn next_heartbeat(&self) -> Option<Instant> {
+        self.pong_deadline.or_else(|| self.heartbeat.map(|heartbeat| self.last_ping + heartbeat.ping_interval))
+    }
+
+    /// Reconnects to the race room according to the reconnect policy, notifying the handler if there is one.
+    async fn reconnect<S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(&mut self, handler: &mut Option<H>, ctx: &RaceContext<S>, data: &Mutex<BotData>, shutdown: &mut watch::Receiver<Option<Instant>>, reason: &str) -> Result<(), (Error, ErrorContext)> {
+        // actions sent in the meantime are kept in the send queue until the connection has been reestablished
+        self.writer.disconnect();
+        self.connected = false;
+        if let Some(handler) = handler {
+            handler.disconnected(ctx, reason).await.map_err(|e| (e, ErrorContext::Disconnected))?;
+        }
+        if self.connected_at.elapsed() >= self.policy.reset_after {
+            self.attempts = 0;
+        }
+        let mut reason = reason.to_owned();
+        let mut failed_attempts = 0;
+        loop {
+            let delay = self.policy.delay(self.attempts);
+            self.attempts = self.attempts.saturating_add(1);
+            log!(warn, "{reason}, reconnecting in {delay:?}…");
+            // if the bot is shut down while reconnecting, the connection stays closed and the caller handles the shutdown
+            if shutdown.borrow().is_some() { return Ok(()) }
+            tokio::select! {
+                () = sleep(delay) => {}
+                Ok(()) = shutdown.changed() => return Ok(()),
+            }
+            let data = data.lock().await;
+            let (category_slug, websocket_bot_url) = {
+                let race_data = ctx.data().await;
+                (race_data.category.slug.clone(), race_data.websocket_bot_url.clone())
+            };
+            let res = tokio::select! {
+                res = data.connect(&*self.connector, &category_slug, &websocket_bot_url) => res,
+                Ok(()) = shutdown.changed() => return Ok(()),
+            };
+            match res {
+                Ok((sink, stream)) => {
+                    drop(data);
+                    self.writer.connect(sink);
+                    self.stream = stream;
+                    break
+                }
+                Err(e) => {
+                    failed_attempts += 1;
+                    if !self.policy.is_transient(&e) || self.policy.max_attempts.is_some_and(|max_attempts| failed_attempts >= max_attempts) {
+                        return Err((e, ErrorContext::Reconnect))
+                    }
+                    reason = format!("failed to reconnect: {e}");
+                }
+            }
+        }
+        self.connected = true;
+        self.connected_at = Instant::now();
+        self.last_ping = Instant::now();
+        self.pong_deadline = None;
+        self.resync(handler, ctx, data).await?;
+        if let Some(handler) = handler {
+            handler.reconnected(ctx).await.map_err(|e| (e, ErrorContext::Reconnected))?;
+        }
+        Ok(())
+    }
+
+    /// Refetches the race data, which may have changed while the connection was down, and passes any changes to the handler.
+    async fn resync<S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(&mut self, handler: &mut Option<H>, ctx: &RaceContext<S>, data: &Mutex<BotData>) -> Result<(), (Error, ErrorContext)> {
+        let url = data.lock().await.host_info.http_uri(&ctx.data().await.data_url);
+        let race = match async { url }
+            .and_then(|url| async { Ok(self.client.get(url).send().await?.error_for_status()?.json::<RaceData>().await?) })
+            .await
+        {
+            Ok(race) => race,
+            Err(e) => {
+                log!(warn, "failed to refetch data for race {} after reconnecting: {e}", ctx.data().await.name);
+                return Ok(())
+            }
+        };
+        if race.version <= ctx.data().await.version { return Ok(()) }
+        match handler {
+            Some(handler) => update_race_data(handler, ctx, race).await,
+            None => {
+                *ctx.data.write().await = race;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Replaces the race data and passes the changes to the handler.
+async fn update_race_data<S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(handler: &mut H, ctx: &RaceContext<S>, race: RaceData) -> Result<(), (Error, ErrorContext)> {
+    let old_race_data = mem::replace(&mut *ctx.data.write().await, race);
+    let events = RaceEvent::diff(&old_race_data, &*ctx.data().await);
+    handler.race_data(ctx, old_race_data).await.map_err(|e| (e, ErrorContext::RaceData))?;
+    for event in events {
+        handler.race_event(ctx, event).await.map_err(|e| (e, ErrorContext::RaceEvent))?;
+    }
+    Ok(())
+}
+
+/// Discards the state kept for the race room by the race handler's [`CommandRouter`](crate::command::CommandRouter) and [`ModerationFilter`](crate::moderation::ModerationFilter), once the bot stops handling the room.
+///
+/// The returned future doesn't borrow the handler, which isn't necessarily [`Sync`].
+fn forget_room<'a, S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(handler: &H, ctx: &'a RaceContext<S>) -> impl Future<Output = ()> + Send + 'a {
+    let router = handler.command_router();
+    let filter = handler.moderation_filter();
+    async move {
+        let name = ctx.data().await.name.clone();
+        if let Some(router) = router {
+            router.forget_race(&name).await;
+        }
+        if let Some(filter) = filter {
+            filter.forget_race(&name).await;
+        }
+    }
+}
+
+/// Ends a race handler which is done handling its room and removes its saved state.
+async fn stop<S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(handler: H, ctx: &RaceContext<S>, store: Option<&dyn StateStore>) -> Result<(), (Error, ErrorContext)> {
+    ctx.timers.clear(true);
+    forget_room(&handler, ctx).await;
+    handler.end(ctx).await.map_err(|e| (e, ErrorContext::End))?;
+    if let Some(store) = store {
+        let name = ctx.data().await.name.clone();
+        store.remove(&name).await.map_err(|e| (e, ErrorContext::Persistence))?;
+    }
+    Ok(())
+}
+
+/// How the bot gets access tokens for a category.
+enum Auth {
+    Credentials {
+        client_id: String,
+        client_secret: String,
+    },
+    TokenProvider(Arc<dyn TokenProvider>),
+}
+
+/// The OAuth credentials of a racetime.gg application, along with its current access token.
+struct Application {
+    /// Set if the token provider was created by the bot, so it can be reused for other categories with the same credentials.
+    credentials: Option<Arc<ClientCredentials>>,
+    token_provider: Arc<dyn TokenProvider>,
+}
+
+impl Application {
+    /// Checks that the token provider works by getting an initial access token.
+    async fn new(host_info: &HostInfo, client: &reqwest::Client, auth: Auth) -> Result<Self, Error> {
+        let (credentials, token_provider) = match auth {
+            Auth::Credentials { client_id, client_secret } => {
+                let credentials = Arc::new(ClientCredentials::new_with_host(host_info.clone(), client_id, client_secret, client.clone()));
+                (Some(Arc::clone(&credentials)), credentials as Arc<dyn TokenProvider>)
+            }
+            Auth::TokenProvider(token_provider) => (None, token_provider),
+        };
+        token_provider.access_token().await?;
+        Ok(Self { credentials, token_provider })
+    }
+
+    /// Whether this application can be reused for a category with the given authentication.
+    fn matches(&self, auth: &Auth) -> bool {
+        match auth {
+            Auth::Credentials { client_id, client_secret } => self.credentials.as_ref().is_some_and(|credentials| credentials.matches(client_id, client_secret)),
+            // compares only the data pointers, since before Rust 1.72, Arc::ptr_eq also compared vtable pointers
+            Auth::TokenProvider(token_provider) => Arc::as_ptr(&self.token_provider).cast::<()>() == Arc::as_ptr(token_provider).cast::<()>(),
         }
     }
 }
 
 struct BotData {
     host_info: HostInfo,
-    category_slug: String,
     handled_races: HashSet<String>,
-    client_id: String,
-    client_secret: String,
-    access_token: String,
-    reauthorize_every: Duration,
+    applications: Vec<Application>,
+    /// The index into `applications` of the application used for each category.
+    categories: HashMap<String, usize>,
+}
+
+impl BotData {
+    /// Connects to a race room using the access token of the race's category. Races in categories which haven't been added to the bot use the first category's token.
+    async fn connect(&self, connector: &dyn Connector, category_slug: &str, websocket_bot_url: &str) -> Result<(MessageSink, MessageStream), Error> {
+        let application = &self.applications[self.categories.get(category_slug).copied().unwrap_or_default()];
+        let access_token = application.token_provider.access_token().await?;
+        let mut request = self.host_info.websocket_uri(websocket_bot_url)?.into_client_request()?;
+        request.headers_mut().append(
+            http::header::HeaderName::from_static("authorization"),
+            format!("Bearer {access_token}").parse::<http::header::HeaderValue>()?,
+        );
+        connector.connect(&self.host_info, request).await
+    }
+}
+
+/// Handles a race room with a specific [`RaceHandler`] type. Used for categories added using [`Bot::add_category_with_handler`].
+type HandleRace<S> = for<'a> fn(&'a Bot<S>, &'a str, &'a str) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+fn handle_race<'a, S: Send + Sync + ?Sized + 'static, H: RaceHandler<S>>(bot: &'a Bot<S>, name: &'a str, data_url: &'a str) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>> {
+    Box::pin(bot.maybe_handle_race::<H>(name, data_url))
+}
+
+struct Category<S: Send + Sync + ?Sized + 'static> {
+    slug: String,
+    /// `None` if the category's races are handled by the handler type passed to [`Bot::run_until`].
+    handle_race: Option<HandleRace<S>>,
+}
+
+/// Configures a [`Bot`] before authorizing it. Created using [`Bot::builder`] or [`Bot::builder_with_token_provider`].
+///
+/// Options which are not set use the same defaults as [`Bot::new`]. Most of them can also be changed later using the `set_*` methods of [`Bot`].
+pub struct BotBuilder<S: Send + Sync + ?Sized + 'static> {
+    host_info: HostInfo,
+    category_slug: String,
+    auth: Auth,
+    state: Arc<S>,
+    client: Option<reqwest::Client>,
+    user_agent_suffix: Option<String>,
+    scan_interval: Duration,
+    extra_room_capacity: usize,
+    max_concurrent_rooms: Option<usize>,
+    shutdown_timeout: Duration,
+    room_rate_limit: Option<RateLimit>,
+    global_rate_limit: Option<RateLimit>,
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    reconnect_policy: ReconnectPolicy,
+    state_store: Option<Arc<dyn StateStore>>,
+    connector: Arc<dyn Connector>,
+}
+
+impl<S: Send + Sync + ?Sized + 'static> BotBuilder<S> {
+    fn new(category_slug: &str, auth: Auth, state: Arc<S>) -> Self {
+        Self {
+            host_info: HostInfo::default(),
+            category_slug: category_slug.to_owned(),
+            client: None,
+            user_agent_suffix: None,
+            scan_interval: DEFAULT_SCAN_INTERVAL,
+            extra_room_capacity: DEFAULT_EXTRA_ROOM_CAPACITY,
+            max_concurrent_rooms: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            room_rate_limit: Some(DEFAULT_ROOM_RATE_LIMIT),
+            global_rate_limit: None,
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            pong_timeout: DEFAULT_PONG_TIMEOUT,
+            reconnect_policy: ReconnectPolicy::default(),
+            state_store: None,
+            connector: Arc::new(TungsteniteConnector),
+            auth, state,
+        }
+    }
+
+    /// Connects to the given host instead of racetime.gg.
+    pub fn host_info(mut self, host_info: HostInfo) -> Self {
+        self.host_info = host_info;
+        self
+    }
+
+    /// Uses the given HTTP client for authorization and for fetching category and race data. By default, a new client is created.
+    ///
+    /// The client's user agent is not changed, so this can't be combined with [`user_agent_suffix`](BotBuilder::user_agent_suffix).
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Appends the given text to the `racetime-rs/<version>` user agent of HTTP requests, e.g. to identify the bot to the racetime.gg admins.
+    pub fn user_agent_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.user_agent_suffix = Some(suffix.into());
+        self
+    }
+
+    /// Sets how often the bot checks for new races in its categories. Defaults to 30 seconds.
+    pub fn scan_interval(mut self, scan_interval: Duration) -> Self {
+        self.scan_interval = scan_interval;
+        self
+    }
+
+    /// Sets how many room slugs sent to the [`extra_room_sender`](Bot::extra_room_sender) can be buffered before sending waits. Defaults to 1024.
+    pub fn extra_room_capacity(mut self, capacity: usize) -> Self {
+        self.extra_room_capacity = capacity;
+        self
+    }
+
+    /// Limits how many race rooms the bot handles at the same time. Defaults to no limit.
+    ///
+    /// Listed rooms which aren't handled because of this limit are picked up by a later scan once other rooms have been removed. Extra rooms are ignored while the limit is reached.
+    pub fn max_concurrent_rooms(mut self, max_concurrent_rooms: Option<usize>) -> Self {
+        self.max_concurrent_rooms = max_concurrent_rooms;
+        self
+    }
+
+    /// See [`Bot::set_shutdown_timeout`].
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// See [`Bot::set_room_rate_limit`].
+    pub fn room_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.room_rate_limit = limit;
+        self
+    }
+
+    /// See [`Bot::set_global_rate_limit`].
+    pub fn global_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.global_rate_limit = limit;
+        self
+    }
+
+    /// See [`Bot::set_ping_interval`].
+    pub fn ping_interval(mut self, ping_interval: Option<Duration>) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// See [`Bot::set_pong_timeout`].
+    pub fn pong_timeout(mut self, pong_timeout: Duration) -> Self {
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    /// See [`Bot::set_reconnect_policy`].
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// See [`Bot::set_state_store`].
+    pub fn state_store(mut self, store: Option<Arc<dyn StateStore>>) -> Self {
+        self.state_store = store;
+        self
+    }
+
+    /// See [`Bot::set_connector`].
+    pub fn connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.connector = connector;
+        self
+    }
+
+    /// Checks the configuration, then authorizes the bot.
+    ///
+    /// Returns [`Error::InvalidConfig`] without contacting the server if any of the options are invalid.
+    pub async fn build(self) -> Result<Bot<S>, Error> {
+        if self.scan_interval.is_zero() { return Err(Error::InvalidConfig("scan interval must be nonzero")) }
+        if self.extra_room_capacity == 0 { return Err(Error::InvalidConfig("extra room capacity must be nonzero")) }
+        if self.max_concurrent_rooms == Some(0) { return Err(Error::InvalidConfig("maximum number of concurrent rooms must be nonzero")) }
+        if self.ping_interval.is_some_and(|ping_interval| ping_interval.is_zero()) { return Err(Error::InvalidConfig("ping interval must be nonzero")) }
+        if self.pong_timeout.is_zero() { return Err(Error::InvalidConfig("pong timeout must be nonzero")) }
+        if self.reconnect_policy.initial_delay > self.reconnect_policy.max_delay { return Err(Error::InvalidConfig("initial reconnect delay must not exceed the maximum delay")) }
+        let client = match (self.client, self.user_agent_suffix) {
+            (Some(_), Some(_)) => return Err(Error::InvalidConfig("a user agent suffix can't be combined with a custom HTTP client")),
+            (Some(client), None) => client,
+            (None, Some(suffix)) => {
+                let user_agent = http::header::HeaderValue::from_str(&format!("{USER_AGENT} {suffix}")).map_err(|_| Error::InvalidConfig("user agent suffix must be a valid header value"))?;
+                reqwest::Client::builder().user_agent(user_agent).build()?
+            }
+            (None, None) => reqwest::Client::builder().user_agent(USER_AGENT).build()?,
+        };
+        let application = Application::new(&self.host_info, &client, self.auth).await?;
+        let (extra_room_tx, extra_room_rx) = mpsc::channel(self.extra_room_capacity);
+        let (shutdown_tx, _) = watch::channel(None);
+        Ok(Bot {
+            data: Arc::new(Mutex::new(BotData {
+                handled_races: HashSet::default(),
+                applications: vec![application],
+                categories: HashMap::from([(self.category_slug.clone(), 0)]),
+                host_info: self.host_info,
+            })),
+            categories: vec![Category {
+                slug: self.category_slug,
+                handle_race: None,
+            }],
+            shutdown_timeout: self.shutdown_timeout,
+            room_rate_limit: self.room_rate_limit,
+            global_rate_limit: self.global_rate_limit.map(|limit| Arc::new(std::sync::Mutex::new(TokenBucket::new(limit)))),
+            ping_interval: self.ping_interval,
+            pong_timeout: self.pong_timeout,
+            reconnect_policy: Arc::new(self.reconnect_policy),
+            state_store: self.state_store,
+            connector: self.connector,
+            scan_interval: self.scan_interval,
+            max_concurrent_rooms: self.max_concurrent_rooms,
+            rooms: RoomRegistry::new(),
+            room_tasks: std::sync::Mutex::default(),
+            events_tx: None,
+            state: self.state,
+            client, extra_room_tx, extra_room_rx, shutdown_tx,
+        })
+    }
 }
 
 pub struct Bot<S: Send + Sync + ?Sized + 'static> {
     client: reqwest::Client,
+    categories: Vec<Category<S>>,
     data: Arc<Mutex<BotData>>,
     state: Arc<S>,
     extra_room_tx: mpsc::Sender<String>,
     extra_room_rx: mpsc::Receiver<String>,
+    /// Set to the deadline for race rooms to shut down once the bot is shut down.
+    shutdown_tx: watch::Sender<Option<Instant>>,
+    shutdown_timeout: Duration,
+    room_rate_limit: Option<RateLimit>,
+    global_rate_limit: Option<Arc<std::sync::Mutex<TokenBucket>>>,
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    reconnect_policy: Arc<ReconnectPolicy>,
+    state_store: Option<Arc<dyn StateStore>>,
+    connector: Arc<dyn Connector>,
+    scan_interval: Duration,
+    max_concurrent_rooms: Option<usize>,
+    rooms: RoomRegistry<S>,
+    /// The tasks handling race rooms, so they can be aborted if they don't shut down in time.
+    room_tasks: std::sync::Mutex<Vec<AbortHandle>>,
+    events_tx: Option<mpsc::Sender<BotEvent<S>>>,
 }
 
 impl<S: Send + Sync + ?Sized + 'static> Bot<S> {
@@ -117,152 +742,453 @@ impl<S: Send + Sync + ?Sized + 'static> Bot<S> {
     }
 
     pub async fn new_with_host(host_info: HostInfo, category_slug: &str, client_id: &str, client_secret: &str, state: Arc<S>) -> Result<Self, Error> {
-        let client = reqwest::Client::builder().user_agent(concat!("racetime-rs/", env!("CARGO_PKG_VERSION"))).build()?;
-        let (access_token, reauthorize_every) = authorize_with_host(&host_info, client_id, client_secret, &client).await?;
-        let (extra_room_tx, extra_room_rx) = mpsc::channel(1_024);
-        Ok(Bot {
-            data: Arc::new(Mutex::new(BotData {
-                access_token, reauthorize_every,
-                handled_races: HashSet::default(),
-                category_slug: category_slug.to_owned(),
-                client_id: client_id.to_owned(),
-                client_secret: client_secret.to_owned(),
-                host_info,
-            })),
-            client, state, extra_room_tx, extra_room_rx,
-        })
+        Self::builder(category_slug, client_id, client_secret, state).host_info(host_info).build().await
+    }
+
+    /// Starts configuring a bot with more options than [`Bot::new`]. See [`BotBuilder`].
+    pub fn builder(category_slug: &str, client_id: &str, client_secret: &str, state: Arc<S>) -> BotBuilder<S> {
+        BotBuilder::new(category_slug, Auth::Credentials { client_id: client_id.to_owned(), client_secret: client_secret.to_owned() }, state)
+    }
+
+    /// Like [`Bot::builder`], but the bot gets access tokens for the category from the given provider instead of authorizing with a client ID and secret. See the [`auth`](crate::auth) module for details.
+    ///
+    /// This can be used to share access tokens with other code using the racetime.gg API.
+    pub fn builder_with_token_provider(category_slug: &str, token_provider: Arc<dyn TokenProvider>, state: Arc<S>) -> BotBuilder<S> {
+        BotBuilder::new(category_slug, Auth::TokenProvider(token_provider), state)
+    }
+
+    /// Sets how long shutting down the bot via [`Bot::run_until`] may take, including race handlers' [`end`](RaceHandler::end) callbacks and sending queued messages.
+    ///
+    /// If a race handler's `end` callback does not complete within this time, it is cancelled and the connection to the race room is closed anyway. Race rooms which haven't shut down by then, e.g. because a race handler callback is stuck, are aborted. Defaults to 10 seconds.
+    pub fn set_shutdown_timeout(&mut self, shutdown_timeout: Duration) {
+        self.shutdown_timeout = shutdown_timeout;
+    }
+
+    /// Sets the rate limit for actions sent to each race room, or disables it if `None` is passed. Defaults to a burst of 5 actions, then 1 action per second.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_room_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.room_rate_limit = limit;
+    }
+
+    /// Sets a rate limit for actions sent across all race rooms handled by this bot, or disables it if `None` is passed. Defaults to no limit.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_global_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.global_rate_limit = limit.map(|limit| Arc::new(std::sync::Mutex::new(TokenBucket::new(limit))));
+    }
+
+    /// Sets how often the bot sends a `ping` action to each race room to check that the connection is still alive, or disables this if `None` is passed. Defaults to 30 seconds.
+    ///
+    /// If no `pong` is received within the [pong timeout](Bot::set_pong_timeout), the bot reconnects to the room. This only affects race rooms which start being handled after it is called.
+    pub fn set_ping_interval(&mut self, ping_interval: Option<Duration>) {
+        self.ping_interval = ping_interval;
+    }
+
+    /// Sets how long the bot waits for a `pong` after sending a `ping` before reconnecting. Defaults to 10 seconds.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_pong_timeout(&mut self, pong_timeout: Duration) {
+        self.pong_timeout = pong_timeout;
+    }
+
+    /// Sets which failures of race room connections are considered transient and how the bot reconnects after them. See [`ReconnectPolicy`] for the defaults.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = Arc::new(policy);
+    }
+
+    /// Sets where race handler state is saved so that it survives bot restarts, or disables saving state if `None` is passed. Defaults to `None`. See the [`persistence`](crate::persistence) module for details.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_state_store(&mut self, store: Option<Arc<dyn StateStore>>) {
+        self.state_store = store;
+    }
+
+    /// Sets how the bot opens WebSocket connections to race rooms. Defaults to [`TungsteniteConnector`]. See the [`transport`](crate::transport) module for details.
+    ///
+    /// This only affects race rooms which start being handled after it is called.
+    pub fn set_connector(&mut self, connector: Arc<dyn Connector>) {
+        self.connector = connector;
+    }
+
+    /// Has the bot also handle races in the given category, using the race handler type passed to [`Bot::run`] or [`Bot::run_until`]. The handler can tell the categories apart using [`RaceData::category`].
+    ///
+    /// If `client_id` and `client_secret` are the same as for a category that's already handled, that category's access token is reused. Otherwise, the bot authorizes separately for this category and keeps both access tokens refreshed.
+    ///
+    /// If the category is already handled, its credentials are replaced.
+    pub async fn add_category(&mut self, category_slug: &str, client_id: &str, client_secret: &str) -> Result<(), Error> {
+        self.add_category_inner(category_slug, Auth::Credentials { client_id: client_id.to_owned(), client_secret: client_secret.to_owned() }, None).await
+    }
+
+    /// Like [`Bot::add_category`], but races in the category are handled using the given race handler type instead of the one passed to [`Bot::run`] or [`Bot::run_until`].
+    pub async fn add_category_with_handler<H: RaceHandler<S>>(&mut self, category_slug: &str, client_id: &str, client_secret: &str) -> Result<(), Error> {
+        self.add_category_inner(category_slug, Auth::Credentials { client_id: client_id.to_owned(), client_secret: client_secret.to_owned() }, Some(handle_race::<S, H>)).await
+    }
+
+    /// Like [`Bot::add_category`], but access tokens for the category are taken from the given provider. If the same provider (i.e. the same [`Arc`]) is already used for another category, it is shared.
+    pub async fn add_category_with_token_provider(&mut self, category_slug: &str, token_provider: Arc<dyn TokenProvider>) -> Result<(), Error> {
+        self.add_category_inner(category_slug, Auth::TokenProvider(token_provider), None).await
+    }
+
+    /// Like [`Bot::add_category_with_token_provider`], but races in the category are handled using the given race handler type instead of the one passed to [`Bot::run`] or [`Bot::run_until`].
+    pub async fn add_category_with_handler_and_token_provider<H: RaceHandler<S>>(&mut self, category_slug: &str, token_provider: Arc<dyn TokenProvider>) -> Result<(), Error> {
+        self.add_category_inner(category_slug, Auth::TokenProvider(token_provider), Some(handle_race::<S, H>)).await
+    }
+
+    async fn add_category_inner(&mut self, category_slug: &str, auth: Auth, handle_race: Option<HandleRace<S>>) -> Result<(), Error> {
+        let mut data = self.data.lock().await;
+        let data = &mut *data;
+        let application = match data.applications.iter().position(|application| application.matches(&auth)) {
+            Some(idx) => idx,
+            None => {
+                data.applications.push(Application::new(&data.host_info, &self.client, auth).await?);
+                data.applications.len() - 1
+            }
+        };
+        data.categories.insert(category_slug.to_owned(), application);
+        if let Some(category) = self.categories.iter_mut().find(|category| category.slug == category_slug) {
+            category.handle_race = handle_race;
+        } else {
+            self.categories.push(Category { slug: category_slug.to_owned(), handle_race });
+        }
+        Ok(())
+    }
+
+    /// Returns the token provider used for the category passed to [`Bot::new`] or [`Bot::builder`], which can be shared with other code using the racetime.gg API. See the [`auth`](crate::auth) module for details.
+    pub async fn token_provider(&self) -> Arc<dyn TokenProvider> {
+        Arc::clone(&self.data.lock().await.applications[0].token_provider)
     }
 
     /// Returns a sender that takes extra room slugs (e.g. as returned from [`crate::StartRace::start`]) and has the bot handle those rooms.
     ///
     /// This can be used to have the bot handle unlisted rooms, which aren't detected automatically since they're not listed on the category detail API endpoint.
+    ///
+    /// Slugs without a category refer to rooms in the category passed to [`Bot::new`]. Rooms in other categories can be sent as `category/slug`.
     pub fn extra_room_sender(&self) -> mpsc::Sender<String> {
         self.extra_room_tx.clone()
     }
 
+    /// Returns a handle to the race rooms handled by this bot, which can be used to list them, send messages to them, or be notified when rooms are added or removed. See the [`registry`](crate::registry) module for details.
+    pub fn rooms(&self) -> RoomRegistry<S> {
+        self.rooms.clone()
+    }
+
     /// Low-level handler for the race room. Loops over the websocket,
     /// calling the appropriate method for each message that comes in.
-    async fn handle<H: RaceHandler<S>>(mut stream: WsStream, ctx: RaceContext<S>, data: &Mutex<BotData>) -> Result<(), (Error, ErrorContext)> {
-        async fn reconnect<S: Send + Sync + ?Sized>(last_network_error: &mut Instant, reconnect_wait_time: &mut Duration, stream: &mut WsStream, ctx: &RaceContext<S>, data: &Mutex<BotData>, reason: &str) -> Result<(), Error> {
-            if last_network_error.elapsed() >= Duration::from_secs(60 * 60 * 24) {
-                *reconnect_wait_time = Duration::from_secs(1); // reset wait time after no crash for a day
+    ///
+    /// If `handler_slot` is [`None`], a new handler is created first, restoring its state from the `store` if there is any. It is left in place if an error occurs so that handling can be resumed according to [`RaceHandler::on_error`].
+    ///
+    /// When the bot is shut down, the handler's [`end`](RaceHandler::end) callback is called with the given timeout and the connection is closed.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn handle<H: RaceHandler<S>>(conn: &mut Connection, handler_slot: &mut Option<H>, ctx: &RaceContext<S>, data: &Mutex<BotData>, store: Option<&dyn StateStore>, shutdown: &mut watch::Receiver<Option<Instant>>) -> Result<(), (Error, ErrorContext)> {
+        let mut saved_state = None::<Json>;
+        if handler_slot.is_none() && shutdown.borrow().is_none() {
+            let name = ctx.data().await.name.clone();
+            let state = match store {
+                Some(store) => store.load(&name).await.map_err(|e| (e, ErrorContext::Persistence))?,
+                None => None,
+            };
+            *handler_slot = Some(if let Some(state) = state {
+                saved_state = Some(state.clone());
+                H::restore(ctx, state).await.map_err(|e| (e, ErrorContext::Restore))?
             } else {
-                *reconnect_wait_time *= 2; // exponential backoff
-            }
-            eprintln!("{reason}, reconnecting in {reconnect_wait_time:?}…");
-            sleep(*reconnect_wait_time).await;
-            *last_network_error = Instant::now();
-            let data = data.lock().await;
-            let mut request = data.host_info.websocket_uri(&ctx.data().await.websocket_bot_url)?.into_client_request()?;
-            request.headers_mut().append(
-                http::header::HeaderName::from_static("authorization"),
-                format!("Bearer {}", data.access_token).parse::<http::header::HeaderValue>()?,
-            );
-            let (ws_conn, _) = tokio_tungstenite::client_async_tls(
-                request, TcpStream::connect(data.host_info.websocket_socketaddrs()).await?,
-            ).await?;
-            drop(data);
-            (*ctx.sender.lock().await, *stream) = ws_conn.split();
-            Ok(())
+                H::new(ctx).await.map_err(|e| (e, ErrorContext::New))?
+            });
         }
-
-        let mut last_network_error = Instant::now();
-        let mut reconnect_wait_time = Duration::from_secs(1);
-        let mut handler = H::new(&ctx).await.map_err(|e| (e, ErrorContext::New))?;
-        while let Some(msg_res) = stream.next().await {
+        // announcements which were due before connecting are skipped
+        let mut announced_until = Utc::now();
+        loop {
+            if let (Some(store), Some(handler)) = (store, handler_slot.as_ref()) {
+                if let Some(state) = handler.save_state().map_err(|e| (e, ErrorContext::SaveState))? {
+                    if saved_state.as_ref() != Some(&state) {
+                        let name = ctx.data().await.name.clone();
+                        store.save(&name, &state).await.map_err(|e| (e, ErrorContext::Persistence))?;
+                        saved_state = Some(state);
+                    }
+                }
+            }
+            let shutdown_deadline = *shutdown.borrow_and_update();
+            if let Some(deadline) = shutdown_deadline {
+                ctx.timers.clear(true);
+                if let Some(handler) = handler_slot.take() {
+                    forget_room(&handler, ctx).await;
+                    match timeout_at(deadline, handler.end(ctx)).await {
+                        Ok(res) => res.map_err(|e| (e, ErrorContext::End))?,
+                        Err(_) => log!(warn, "race handler for {} did not end within the shutdown timeout, disconnecting anyway", ctx.data().await.name),
+                    }
+                }
+                if !conn.connected {
+                    log!(warn, "race room {} was shut down while reconnecting, queued messages were not sent", ctx.data().await.name);
+                } else if timeout_at(deadline, ctx.queue.drain()).await.is_err() {
+                    log!(warn, "send queue for {} was not drained within the shutdown timeout, disconnecting anyway", ctx.data().await.name);
+                }
+                conn.writer.close().await.map_err(|e| (e, ErrorContext::Close))?;
+                return Ok(())
+            }
+            let next_heartbeat = conn.next_heartbeat();
+            let next_timer = ctx.timers.next_deadline();
+            let announcer = handler_slot.as_ref().and_then(|handler| handler.announcer());
+            let next_announcement = match announcer {
+                Some(ref announcer) => announcer.next(&*ctx.data().await, announced_until).map(|(at, _)| Instant::now() + (at - Utc::now()).to_std().unwrap_or_default()),
+                None => None,
+            };
+            let msg_res = tokio::select! {
+                msg_res = conn.stream.next() => match msg_res {
+                    Some(msg_res) => msg_res,
+                    None => break,
+                },
+                Ok(()) = shutdown.changed() => continue,
+                () = sleep_until(next_heartbeat.unwrap_or_else(Instant::now)), if next_heartbeat.is_some() => {
+                    if let Some(heartbeat) = conn.heartbeat {
+                        if conn.pong_deadline.is_some() {
+                            conn.reconnect(
+                                handler_slot, ctx, data, shutdown,
+                                &format!("no pong received within {:?}", heartbeat.pong_timeout),
+                            ).await?;
+                        } else {
+                            conn.writer.send_frame(tungstenite::Message::Text(json!({"action": "ping"}).to_string())).await.map_err(|e| (e, ErrorContext::Ping))?;
+                            conn.last_ping = Instant::now();
+                            conn.pong_deadline = Some(conn.last_ping + heartbeat.pong_timeout);
+                        }
+                    }
+                    continue
+                }
+                e = conn.writer.connection_lost() => {
+                    conn.reconnect(handler_slot, ctx, data, shutdown, &format!("{e} while sending action")).await?;
+                    continue
+                }
+                () = ctx.timers.changed() => continue,
+                () = sleep_until(next_announcement.unwrap_or_else(Instant::now)), if next_announcement.is_some() => {
+                    if let Some(announcer) = announcer {
+                        let now = Utc::now();
+                        let mut latest = None;
+                        {
+                            let data = ctx.data().await;
+                            while let Some((at, message)) = announcer.next(&data, announced_until).filter(|&(at, _)| at <= now) {
+                                announced_until = at;
+                                latest = Some(message);
+                            }
+                        }
+                        if let Some(message) = latest {
+                            ctx.send_message(&message).await.map_err(|e| (e, ErrorContext::Announcement))?;
+                        }
+                    }
+                    continue
+                }
+                () = sleep_until(next_timer.unwrap_or_else(Instant::now)), if next_timer.is_some() => {
+                    let handler = handler_slot.as_mut().expect("race handler should be initialized");
+                    for name in ctx.timers.take_due() {
+                        handler.timer(ctx, name).await.map_err(|e| (e, ErrorContext::Timer))?;
+                    }
+                    if handler.should_stop(ctx).await.map_err(|e| (e, ErrorContext::ShouldStop))? {
+                        return stop(handler_slot.take().expect("race handler should be initialized"), ctx, store).await
+                    }
+                    continue
+                }
+            };
+            let handler = handler_slot.as_mut().expect("race handler should be initialized");
             match msg_res {
                 Ok(tungstenite::Message::Text(buf)) => {
                     match serde_json::from_str(&buf).map_err(|e| (e.into(), ErrorContext::Decode))? {
-                        Message::ChatHistory { messages } => handler.chat_history(&ctx, messages).await.map_err(|e| (e, ErrorContext::ChatHistory))?,
-                        Message::ChatMessage { message } => handler.chat_message(&ctx, message).await.map_err(|e| (e, ErrorContext::ChatMessage))?,
-                        Message::ChatDelete { delete } => handler.chat_delete(&ctx, delete).await.map_err(|e| (e, ErrorContext::ChatDelete))?,
-                        Message::ChatPurge { purge } => handler.chat_purge(&ctx, purge).await.map_err(|e| (e, ErrorContext::ChatPurge))?,
-                        Message::Error { errors } => {
-                            /*if errors.iter().all(|error| error == "Possible sync error. Refresh to continue.") {
-                                // This error is not documented by racetime.gg but the wording suggests a server-side network issue.
-                                // Reestablish the WebSocket connection as a workaround.
-                                let data = data.lock().await;
-                                let mut request = format!("wss://{}{}", data.host, ctx.data().await.websocket_bot_url).into_client_request().map_err(|e| (e.into(), ErrorContext::Reconnect))?;
-                                request.headers_mut().append(
-                                    http::header::HeaderName::from_static("authorization"),
-                                    format!("Bearer {}", data.access_token).parse::<http::header::HeaderValue>().map_err(|e| (e.into(), ErrorContext::Reconnect))?,
-                                );
-                                let (ws_conn, _) = tokio_tungstenite::client_async_tls(request, TcpStream::connect((&*data.host, 443)).await.map_err(|e| (e.into(), ErrorContext::Reconnect))?).await.map_err(|e| (e.into(), ErrorContext::Reconnect))?;
-                                drop(data);
-                                (*ctx.sender.lock().await, stream) = ws_conn.split();
-                            } else*/ // Despite what it sounds like, this is a client-side error. See also https://github.com/racetimeGG/racetime-app/pull/196
-                            {
-                                handler.error(&ctx, errors).await.map_err(|e| (e, ErrorContext::ServerError))?;
+                        Message::ChatHistory { mut messages } => {
+                            // the history is sent again after reconnecting, but messages which were already handled shouldn't be handled twice
+                            messages.retain(|message| conn.mark_seen(message));
+                            handler.chat_history(ctx, messages).await.map_err(|e| (e, ErrorContext::ChatHistory))?;
+                        }
+                        Message::ChatMessage { message } => if conn.mark_seen(&message) {
+                            let deleted = if let Some(filter) = handler.moderation_filter() {
+                                filter.check(ctx, &message).await.map_err(|e| (e, ErrorContext::Moderation))?.is_some_and(|(_, deleted)| deleted)
+                            } else {
+                                false
+                            };
+                            let action = ActionInvocation::parse(&ctx.actions.lock().expect("message action registry lock poisoned"), &message);
+                            if deleted {
+                                // moderated messages aren't passed to the handler
+                            } else if let Some(action) = action {
+                                handler.message_action(ctx, action, &message).await.map_err(|e| (e, ErrorContext::MessageAction))?;
+                            } else {
+                                handler.chat_message(ctx, message).await.map_err(|e| (e, ErrorContext::ChatMessage))?;
                             }
                         }
-                        Message::Pong => handler.pong(&ctx).await.map_err(|e| (e, ErrorContext::Pong))?,
-                        Message::RaceData { race } => {
-                            let old_race_data = mem::replace(&mut *ctx.data.write().await, race);
-                            handler.race_data(&ctx, old_race_data).await.map_err(|e| (e, ErrorContext::RaceData))?;
+                        Message::ChatDelete { delete } => handler.chat_delete(ctx, delete).await.map_err(|e| (e, ErrorContext::ChatDelete))?,
+                        Message::ChatPurge { purge } => handler.chat_purge(ctx, purge).await.map_err(|e| (e, ErrorContext::ChatPurge))?,
+                        Message::Error { errors } => if conn.policy.reconnect_on_sync_error && errors.iter().all(|error| error == SYNC_ERROR) {
+                            conn.reconnect(handler_slot, ctx, data, shutdown, "possible sync error").await?;
+                            continue
+                        } else {
+                            handler.error(ctx, errors).await.map_err(|e| (e, ErrorContext::ServerError))?;
+                        },
+                        Message::Pong => {
+                            conn.pong_deadline = None;
+                            handler.pong(ctx).await.map_err(|e| (e, ErrorContext::Pong))?;
                         }
-                        Message::RaceRenders => handler.race_renders(&ctx).await.map_err(|e| (e, ErrorContext::RaceRenders))?,
-                        Message::RaceSplit => handler.race_split(&ctx).await.map_err(|e| (e, ErrorContext::RaceSplit))?,
+                        Message::RaceData { race } => update_race_data(handler, ctx, race).await?,
+                        Message::RaceRenders => handler.race_renders(ctx).await.map_err(|e| (e, ErrorContext::RaceRenders))?,
+                        Message::RaceSplit => handler.race_split(ctx).await.map_err(|e| (e, ErrorContext::RaceSplit))?,
                     }
-                    if handler.should_stop(&ctx).await.map_err(|e| (e, ErrorContext::ShouldStop))? {
-                        return handler.end(&ctx).await.map_err(|e| (e, ErrorContext::End))
+                    if handler.should_stop(ctx).await.map_err(|e| (e, ErrorContext::ShouldStop))? {
+                        return stop(handler_slot.take().expect("race handler should be initialized"), ctx, store).await
                     }
                 }
-                Ok(tungstenite::Message::Ping(payload)) => ctx.sender.lock().await.send(tungstenite::Message::Pong(payload)).await.map_err(|e| (e.into(), ErrorContext::Ping))?,
-                Ok(tungstenite::Message::Close(Some(tungstenite::protocol::CloseFrame { reason, .. }))) if reason == "CloudFlare WebSocket proxy restarting" => reconnect(
-                    &mut last_network_error, &mut reconnect_wait_time, &mut stream, &ctx, data,
-                    "WebSocket connection closed by server",
-                ).await.map_err(|e| (e, ErrorContext::Reconnect))?,
+                Ok(tungstenite::Message::Ping(payload)) => conn.writer.send_frame(tungstenite::Message::Pong(payload)).await.map_err(|e| (e, ErrorContext::Ping))?,
+                Ok(tungstenite::Message::Close(Some(frame))) if conn.policy.is_transient_close(&frame) => conn.reconnect(
+                    handler_slot, ctx, data, shutdown,
+                    &format!("WebSocket connection closed by server with code {}: {}", frame.code, frame.reason),
+                ).await?,
                 Ok(msg) => return Err((Error::UnexpectedMessageType(msg), ErrorContext::Recv)),
-                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof) => reconnect( //TODO other error kinds?
-                    &mut last_network_error, &mut reconnect_wait_time, &mut stream, &ctx, data,
-                    "unexpected end of file while waiting for message form server",
-                ).await.map_err(|e| (e, ErrorContext::Reconnect))?,
-                Err(tungstenite::Error::Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake)) => reconnect(
-                    &mut last_network_error, &mut reconnect_wait_time, &mut stream, &ctx, data,
-                    "connection reset without closing handshake while waiting for message form server",
-                ).await.map_err(|e| (e, ErrorContext::Reconnect))?,
-                Err(e) => return Err((e.into(), ErrorContext::Recv)),
+                Err(e) => match e {
+                    e if conn.policy.is_transient(&e) => conn.reconnect(
+                        handler_slot, ctx, data, shutdown,
+                        &format!("{e} while waiting for message from server"),
+                    ).await?,
+                    e => return Err((e, ErrorContext::Recv)),
+                },
             }
         }
         Err((Error::EndOfStream, ErrorContext::Recv))
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(race = name)))]
     async fn maybe_handle_race<H: RaceHandler<S>>(&self, name: &str, data_url: &str) -> Result<(), Error> {
         let mut data = self.data.lock().await;
         if !data.handled_races.contains(name) {
+            if let Some(max_concurrent_rooms) = self.max_concurrent_rooms {
+                if self.rooms.len() >= max_concurrent_rooms {
+                    log!(warn, "Not handling race {name} for now since the bot is already handling the maximum number of race rooms ({max_concurrent_rooms})");
+                    return Ok(())
+                }
+            }
             let race_data = match async { data.host_info.http_uri(data_url) }
                 .and_then(|url| async { Ok(self.client.get(url).send().await?.error_for_status()?.json().await?) })
                 .await
             {
                 Ok(race_data) => race_data,
                 Err(e) => {
-                    eprintln!("Fatal error when attempting to retrieve data for race {name} (retrying in {} seconds): {e:?}", SCAN_RACES_EVERY.as_secs_f64());
+                    log!(error, "Fatal error when attempting to retrieve data for race {name} (retrying in {} seconds): {e:?}", self.scan_interval.as_secs_f64());
                     return Ok(())
                 }
             };
             if H::should_handle(&race_data, Arc::clone(&self.state)).await? {
-                let mut request = data.host_info.websocket_uri(&race_data.websocket_bot_url)?.into_client_request()?;
-                request.headers_mut().append(http::header::HeaderName::from_static("authorization"), format!("Bearer {}", data.access_token).parse()?);
-                let (ws_conn, _) = tokio_tungstenite::client_async_tls(
-                    request, TcpStream::connect(data.host_info.websocket_socketaddrs()).await?,
-                ).await?;
+                let (sink, stream) = match data.connect(&*self.connector, &race_data.category.slug, &race_data.websocket_bot_url).await {
+                    Ok(conn) => conn,
+                    Err(e) if self.reconnect_policy.is_transient(&e) => {
+                        log!(error, "Error when attempting to connect to race {name} (retrying in {} seconds): {e:?}", self.scan_interval.as_secs_f64());
+                        return Ok(())
+                    }
+                    Err(e) => return Err(e),
+                };
                 data.handled_races.insert(name.to_owned());
                 drop(data);
-                let (sink, stream) = ws_conn.split();
                 let race_data = Arc::new(RwLock::new(race_data));
                 let ctx = RaceContext {
                     global_state: Arc::clone(&self.state),
                     data: Arc::clone(&race_data),
-                    sender: Arc::new(Mutex::new(sink)),
+                    queue: Arc::new(SendQueue::default()),
+                    actions: Arc::default(),
+                    timers: Arc::default(),
+                    events: self.events_tx.clone(),
+                    #[cfg(feature = "tracing")]
+                    span: tracing::Span::current(),
                 };
+                self.rooms.insert(name.to_owned(), ctx.clone());
+                ctx.emit(|| BotEvent::RoomAdded(ctx.clone())).await;
+                let (writer, writer_task) = Writer::new(Arc::clone(&ctx.queue), sink, self.room_rate_limit, self.global_rate_limit.clone());
+                #[cfg(feature = "tracing")] let writer_task = tracing::Instrument::instrument(writer_task, tracing::Span::current());
+                tokio::spawn(writer_task);
                 let name = name.to_owned();
                 let data_clone = Arc::clone(&self.data);
-                H::task(Arc::clone(&self.state), race_data, tokio::spawn(async move {
-                    if let Err((e, ctx)) = Self::handle::<H>(stream, ctx, &data_clone).await {
-                        panic!("error in race handler {ctx}: {e} ({e:?})")
+                let mut shutdown = self.shutdown_tx.subscribe();
+                let shutdown_timeout = self.shutdown_timeout;
+                let client = self.client.clone();
+                let reconnect_policy = Arc::clone(&self.reconnect_policy);
+                let connector = Arc::clone(&self.connector);
+                let state_store = self.state_store.clone();
+                let rooms = self.rooms.clone();
+                let heartbeat = self.ping_interval.map(|ping_interval| Heartbeat { ping_interval, pong_timeout: self.pong_timeout });
+                let task = async move {
+                    let mut conn = Connection::new(stream, writer, connector, client, reconnect_policy, heartbeat);
+                    let mut handler = None;
+                    let mut reconnect = false;
+                    // the number of times in a row the handler couldn't be created
+                    let mut failed_inits = 0;
+                    loop {
+                        let res = if mem::take(&mut reconnect) && shutdown.borrow().is_none() {
+                            conn.reconnect(&mut handler, &ctx, &data_clone, &mut shutdown, "reconnecting after error").await
+                        } else {
+                            Ok(())
+                        };
+                        let res = match res {
+                            Ok(()) => Self::handle::<H>(&mut conn, &mut handler, &ctx, &data_clone, state_store.as_deref(), &mut shutdown).await,
+                            Err(e) => Err(e),
+                        };
+                        let Err((e, error_ctx)) = res else { break };
+                        let connection_lost = matches!(e, Error::EndOfStream);
+                        let init_failed = handler.is_none() && shutdown.borrow().is_none();
+                        let action = H::on_error(&ctx, e, error_ctx);
+                        #[cfg(feature = "tracing")] let action = tracing::Instrument::instrument(action, tracing::error_span!("on_error", context = %error_ctx));
+                        match action.await {
+                            ErrorAction::Continue => reconnect = connection_lost,
+                            ErrorAction::Reconnect => reconnect = true,
+                            ErrorAction::Restart => {
+                                ctx.timers.clear(false);
+                                // the new handler should start from scratch rather than from the state which may have caused the error
+                                if let Some(ref store) = state_store {
+                                    if let Err(e) = store.remove(&name).await {
+                                        log!(error, "failed to remove saved state for race {name}: {e}");
+                                    }
+                                }
+                                handler = None;
+                                reconnect = connection_lost;
+                            }
+                            ErrorAction::Abandon => {
+                                if let Some(ref handler) = handler {
+                                    forget_room(handler, &ctx).await;
+                                }
+                                // the room stays in handled_races so it isn't picked up again
+                                rooms.remove(&name);
+                                ctx.emit(|| BotEvent::RoomRemoved(name.clone())).await;
+                                ctx.timers.clear(true);
+                                // contexts may outlive the room, e.g. when cloned from the room registry, so don't keep its action buttons around
+                                ctx.actions.lock().expect("message action registry lock poisoned").clear();
+                                ctx.queue.close();
+                                let _ = conn.writer.close().await;
+                                return
+                            }
+                        }
+                        if !init_failed {
+                            failed_inits = 0;
+                        } else if !reconnect {
+                            // reconnecting already waits, otherwise a constructor that keeps failing would be retried in a tight loop
+                            let delay = conn.policy.delay(failed_inits);
+                            failed_inits = failed_inits.saturating_add(1);
+                            log!(warn, "failed to create race handler for {name}, retrying in {delay:?}…");
+                            tokio::select! {
+                                () = sleep(delay) => {}
+                                Ok(()) = shutdown.changed() => {}
+                            }
+                        }
                     }
+                    rooms.remove(&name);
+                    ctx.emit(|| BotEvent::RoomRemoved(name.clone())).await;
+                    ctx.timers.clear(true);
+                    ctx.actions.lock().expect("message action registry lock poisoned").clear();
+                    if conn.connected {
+                        let deadline = shutdown.borrow().unwrap_or_else(|| Instant::now() + shutdown_timeout);
+                        let _ = timeout_at(deadline, ctx.queue.drain()).await;
+                    }
+                    ctx.queue.close();
                     data_clone.lock().await.handled_races.remove(&name);
-                })).await?;
+                };
+                #[cfg(feature = "tracing")] let task = tracing::Instrument::instrument(task, tracing::Span::current());
+                let join_handle = tokio::spawn(task);
+                {
+                    let mut room_tasks = self.room_tasks.lock().expect("room task list lock poisoned");
+                    room_tasks.retain(|task| !task.is_finished());
+                    room_tasks.push(join_handle.abort_handle());
+                }
+                H::task(Arc::clone(&self.state), race_data, join_handle).await?;
             }
         }
         Ok(())
@@ -273,59 +1199,74 @@ impl<S: Send + Sync + ?Sized + 'static> Bot<S> {
         self.run_until::<H, _, _>(future::pending()).await
     }
 
+    /// Runs the bot in the background and returns a stream of the events in all handled race rooms, as an alternative to implementing [`RaceHandler`]. Requires an active [`tokio`] runtime. See the [`stream`](crate::stream) module for details.
+    ///
+    /// Races in categories added using [`Bot::add_category_with_handler`] are still handled by their race handlers and don't appear in the stream.
+    pub fn into_stream(mut self) -> BotEvents<S> {
+        let (events_tx, rx) = mpsc::channel(1_024);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        self.events_tx = Some(events_tx);
+        let task = tokio::spawn(self.run_until::<StreamHandler, _, _>(async move { let _ = shutdown_rx.await; }));
+        BotEvents { rx, shutdown: Some(shutdown), task: Some(task) }
+    }
+
     /// Run the bot until the `shutdown` future resolves. Requires an active [`tokio`] runtime. `shutdown` must be cancel safe.
+    ///
+    /// Once `shutdown` resolves, the bot stops looking for new races and calls [`RaceHandler::end`] on every running race handler (see [`Bot::set_shutdown_timeout`]), then waits for all race room connections to be closed before returning. Race rooms which are waiting to reconnect stop doing so, and messages sent by their handlers are discarded. Race rooms which haven't shut down within the [shutdown timeout](Bot::set_shutdown_timeout) are aborted.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(categories = %itertools::Itertools::format(self.categories.iter().map(|category| &category.slug), ", "))))]
     pub async fn run_until<H: RaceHandler<S>, T, Fut: Future<Output = T>>(mut self, shutdown: Fut) -> Result<T, Error> {
         tokio::pin!(shutdown);
-        // Divide the reauthorization interval by 2 to avoid token expiration
-        let reauthorize_every = self.data.lock().await.reauthorize_every / 2;
-        let mut reauthorize = interval_at(Instant::now() + reauthorize_every, reauthorize_every);
-        let mut refresh_races = interval(SCAN_RACES_EVERY);
+        let mut refresh_races = interval(self.scan_interval);
         refresh_races.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
             tokio::select! {
-                output = &mut shutdown => return Ok(output), //TODO shut down running handlers
-                _ = reauthorize.tick() => {
-                    let mut data = self.data.lock().await;
-                    match authorize_with_host(&data.host_info, &data.client_id, &data.client_secret, &self.client).await {
-                        Ok((access_token, reauthorize_every)) => {
-                            data.access_token = access_token;
-                            data.reauthorize_every = reauthorize_every;
-                            reauthorize = interval_at(Instant::now() + reauthorize_every / 2, reauthorize_every / 2);
-                        }
-                        Err(Error::Reqwest(e)) if e.status().map_or(true, |status| status.is_server_error()) => {
-                            // racetime.gg's auth endpoint has been known to return server errors intermittently, and we should also resist intermittent network errors.
-                            // In those cases, we retry again after half the remaining lifetime of the current token, until that would exceed the rate limit.
-                            let reauthorize_every = reauthorize.period() / 2;
-                            if reauthorize_every < SCAN_RACES_EVERY { return Err(Error::Reqwest(e)) }
-                            reauthorize = interval_at(Instant::now() + reauthorize_every, reauthorize_every);
+                output = &mut shutdown => {
+                    let deadline = Instant::now() + self.shutdown_timeout;
+                    self.shutdown_tx.send_replace(Some(deadline));
+                    if timeout_at(deadline, self.shutdown_tx.closed()).await.is_err() {
+                        // e.g. a race handler callback is stuck and the room's task never sees the shutdown
+                        log!(warn, "race rooms did not shut down within {:?}, aborting them", self.shutdown_timeout);
+                        for task in self.room_tasks.lock().expect("room task list lock poisoned").drain(..) {
+                            task.abort();
                         }
-                        Err(e) => return Err(e),
+                        self.rooms.clear();
                     }
+                    return Ok(output)
                 }
                 _ = refresh_races.tick() => {
-                    let url = async {
-                        let data = self.data.lock().await;
-                        data.host_info.http_uri(&format!("/{}/data", &data.category_slug))
-                    };
-                    let data = match url
-                        .and_then(|url| async { Ok(self.client.get(url).send().await?.error_for_status()?.json::<CategoryData>().await?) })
-                        .await
-                    {
-                        Ok(data) => data,
-                        Err(e) => {
-                            eprintln!("Error when attempting to retrieve category data (retrying in {} seconds): {e:?}", SCAN_RACES_EVERY.as_secs_f64());
-                            continue
+                    for category in &self.categories {
+                        let url = async { self.data.lock().await.host_info.http_uri(&format!("/{}/data", category.slug)) };
+                        let data = match url
+                            .and_then(|url| async { Ok(self.client.get(url).send().await?.error_for_status()?.json::<CategoryData>().await?) })
+                            .await
+                        {
+                            Ok(data) => data,
+                            Err(e) => {
+                                log!(error, "Error when attempting to retrieve data for category {} (retrying in {} seconds): {e:?}", category.slug, self.scan_interval.as_secs_f64());
+                                continue
+                            }
+                        };
+                        for summary_data in data.current_races {
+                            self.handle_race::<H>(category, &summary_data.name, &summary_data.data_url).await?;
                         }
-                    };
-                    for summary_data in data.current_races {
-                        self.maybe_handle_race::<H>(&summary_data.name, &summary_data.data_url).await?;
                     }
                 }
                 Some(slug) = self.extra_room_rx.recv() => {
-                    let data = self.data.lock().await;
-                    self.maybe_handle_race::<H>(&format!("{}/{}", data.category_slug, slug), &format!("/{}/{}/data", data.category_slug, slug)).await?;
+                    let (category_slug, slug) = slug.split_once('/').unwrap_or((&self.categories[0].slug, &slug));
+                    if let Some(category) = self.categories.iter().find(|category| category.slug == category_slug) {
+                        self.handle_race::<H>(category, &format!("{category_slug}/{slug}"), &format!("/{category_slug}/{slug}/data")).await?;
+                    } else {
+                        log!(error, "Not handling extra room {category_slug}/{slug} since the bot doesn't handle that category");
+                    }
                 }
             }
         }
     }
+
+    async fn handle_race<H: RaceHandler<S>>(&self, category: &Category<S>, name: &str, data_url: &str) -> Result<(), Error> {
+        match category.handle_race {
+            Some(handle_race) => handle_race(self, name, data_url).await,
+            None => self.maybe_handle_race::<H>(name, data_url).await,
+        }
+    }
 }
@@ -0,0 +1,761 @@
+//! A declarative alternative to matching on command names in [`RaceHandler::command`](crate::RaceHandler::command).
+//!
+//! Build a [`CommandRouter`] once and return it from [`RaceHandler::command_router`](crate::RaceHandler::command_router):
+//!
+//! ```ignore
+//! let router = CommandRouter::new()
+//!     .command(Command::new("seed", |handler: &mut MyHandler, ctx, args, _msg| Box::pin(async move {
+//!         let preset = args.str("preset").unwrap_or("standard");
+//!         handler.roll_seed(ctx, preset).await
+//!     }))
+//!         .alias("roll")
+//!         .description("Roll a seed")
+//!         .arg(Arg::choice("preset", ["standard", "hard"]).optional())
+//!         .cooldown(Duration::from_secs(30)))
+//!     .command(Command::new("lock", |handler: &mut MyHandler, ctx, _args, _msg| Box::pin(async move {
+//!         handler.locked = true;
+//!         ctx.send_message("Settings locked.").await
+//!     }))
+//!         .permission(Permission::Monitor));
+//! ```
+//!
+//! Alternatively, with the `derive` feature enabled, commands can be declared as an enum deriving [`RaceCommand`] and parsed using [`parse_command`]:
+//!
+//! ```ignore
+//! #[derive(FromArg)]
+//! enum Preset { Standard, Hard }
+//!
+//! #[derive(RaceCommand)]
+//! enum Cmd {
+//!     /// Roll a seed
+//!     #[command(alias = "roll")]
+//!     Seed { preset: Option<Preset> },
+//!     #[command(monitor)]
+//!     Lock,
+//! }
+//!
+//! async fn command(&mut self, ctx: &RaceContext<S>, cmd_name: String, args: Vec<String>, is_moderator: bool, is_monitor: bool, msg: &ChatMessage) -> Result<(), Error> {
+//!     match parse_command(ctx, &cmd_name, args, is_moderator, is_monitor, msg).await? {
+//!         Some(Cmd::Seed { preset }) => self.roll_seed(ctx, preset.unwrap_or(Preset::Standard)).await?,
+//!         Some(Cmd::Lock) => self.locked = true,
+//!         None => {}
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use {
+    std::{
+        collections::HashMap,
+        fmt,
+        time::Duration,
+    },
+    futures::future::BoxFuture,
+    lazy_regex::regex_captures,
+    tokio::{
+        sync::Mutex,
+        time::Instant,
+    },
+    crate::{
+        Error,
+        handler::RaceContext,
+        model::*,
+    },
+};
+
+/// Who is allowed to use a command.
+///
+/// Each level includes all levels below it, e.g. race monitors can use commands that require [`Permission::Entrant`] even if they're not entered in the race.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Permission {
+    #[default]
+    Anyone,
+    /// Users who have joined the race. Pending invites and join requests don't count.
+    Entrant,
+    /// Race monitors, including the user who opened the race.
+    Monitor,
+    /// racetime.gg moderators for the category.
+    Moderator,
+}
+
+impl Permission {
+    /// Returns the highest permission level held by the sender of the given message.
+    ///
+    /// `is_moderator` and `is_monitor` should be the values passed to [`RaceHandler::command`](crate::RaceHandler::command).
+    pub fn of_sender(race_data: &RaceData, msg: &ChatMessage, is_moderator: bool, is_monitor: bool) -> Self {
+        if is_moderator {
+            Self::Moderator
+        } else if is_monitor {
+            Self::Monitor
+        } else if msg.user.as_ref().is_some_and(|sender| race_data.entrants.iter().any(|entrant|
+            entrant.user.id == sender.id && !matches!(entrant.status.value, EntrantStatusValue::Requested | EntrantStatusValue::Invited | EntrantStatusValue::Declined)
+        )) {
+            Self::Entrant
+        } else {
+            Self::Anyone
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Anyone => write!(f, "anyone"),
+            Self::Entrant => write!(f, "entrants"),
+            Self::Monitor => write!(f, "race monitors"),
+            Self::Moderator => write!(f, "moderators"),
+        }
+    }
+}
+
+/// The type of value a command argument accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A whole number.
+    Int,
+    /// A duration, either as a number of seconds, in the form `1h30m15s`, or in the form `1:30:15`. See [`parse_duration`].
+    Duration,
+    /// The name of a user who is entered in or monitoring the race, optionally prefixed with `@`.
+    User,
+    /// One of the given words, case-insensitive.
+    Choice(Vec<String>),
+    /// A single word. Use quotes for arguments containing spaces.
+    Word,
+    /// The remainder of the command. Must be the last argument.
+    Rest,
+}
+
+/// A command argument definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arg {
+    name: String,
+    kind: ArgKind,
+    optional: bool,
+}
+
+impl Arg {
+    pub fn new(name: impl Into<String>, kind: ArgKind) -> Self {
+        Self {
+            name: name.into(),
+            optional: false,
+            kind,
+        }
+    }
+
+    pub fn int(name: impl Into<String>) -> Self { Self::new(name, ArgKind::Int) }
+    pub fn duration(name: impl Into<String>) -> Self { Self::new(name, ArgKind::Duration) }
+    pub fn user(name: impl Into<String>) -> Self { Self::new(name, ArgKind::User) }
+    pub fn word(name: impl Into<String>) -> Self { Self::new(name, ArgKind::Word) }
+    pub fn rest(name: impl Into<String>) -> Self { Self::new(name, ArgKind::Rest) }
+
+    pub fn choice(name: impl Into<String>, choices: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::new(name, ArgKind::Choice(choices.into_iter().map(Into::into).collect()))
+    }
+
+    /// Allows this argument to be omitted. Since arguments are positional, all arguments after an optional one should be optional as well.
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    fn usage(&self) -> String {
+        let name = match self.kind {
+            ArgKind::Choice(ref choices) => choices.join("|"),
+            ArgKind::Rest => format!("{}…", self.name),
+            _ => self.name.clone(),
+        };
+        if self.optional { format!("[{name}]") } else { format!("<{name}>") }
+    }
+
+    fn parse(&self, race_data: &RaceData, raw: &str) -> Result<ArgValue, String> {
+        match self.kind {
+            ArgKind::Int => i64::from_arg(race_data, raw).map(ArgValue::Int),
+            ArgKind::Duration => Duration::from_arg(race_data, raw).map(ArgValue::Duration),
+            ArgKind::User => UserData::from_arg(race_data, raw).map(ArgValue::User),
+            ArgKind::Choice(ref choices) => choices.iter()
+                .find(|choice| choice.eq_ignore_ascii_case(raw))
+                .map(|choice| ArgValue::Text(choice.clone()))
+                .ok_or_else(|| format!("{raw:?} is not one of {}", choices.join(", "))),
+            ArgKind::Word | ArgKind::Rest => Ok(ArgValue::Text(raw.to_owned())),
+        }
+    }
+}
+
+/// A parsed command argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgValue {
+    Int(i64),
+    Duration(Duration),
+    User(UserData),
+    /// The value of a [`Choice`](ArgKind::Choice), [`Word`](ArgKind::Word), or [`Rest`](ArgKind::Rest) argument. For choices, this is the spelling from the argument definition.
+    Text(String),
+}
+
+/// The parsed arguments of a command invocation, by name. Optional arguments that were omitted are absent.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Args {
+    values: HashMap<String, ArgValue>,
+}
+
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&ArgValue> {
+        self.values.get(name)
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        if let Some(ArgValue::Int(value)) = self.get(name) { Some(*value) } else { None }
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        if let Some(ArgValue::Duration(value)) = self.get(name) { Some(*value) } else { None }
+    }
+
+    pub fn user(&self, name: &str) -> Option<&UserData> {
+        if let Some(ArgValue::User(value)) = self.get(name) { Some(value) } else { None }
+    }
+
+    pub fn str(&self, name: &str) -> Option<&str> {
+        if let Some(ArgValue::Text(value)) = self.get(name) { Some(value) } else { None }
+    }
+}
+
+type CommandFn<H, S> = Box<dyn for<'a> Fn(&'a mut H, &'a RaceContext<S>, Args, &'a ChatMessage) -> BoxFuture<'a, Result<(), Error>> + Send + Sync>;
+
+/// A command that can be registered with a [`CommandRouter`].
+pub struct Command<H, S: Send + Sync + ?Sized + 'static> {
+    name: String,
+    aliases: Vec<String>,
+    description: Option<String>,
+    args: Vec<Arg>,
+    permission: Permission,
+    cooldown: Option<Duration>,
+    run: CommandFn<H, S>,
+}
+
+impl<H, S: Send + Sync + ?Sized + 'static> Command<H, S> {
+    /// Creates a command with the given name, which should not include the `!` prefix.
+    ///
+    /// `run` is called with the parsed arguments if the command is used by someone with the required [`permission`](Command::permission).
+    pub fn new(name: impl Into<String>, run: impl for<'a> Fn(&'a mut H, &'a RaceContext<S>, Args, &'a ChatMessage) -> BoxFuture<'a, Result<(), Error>> + Send + Sync + 'static) -> Self {
+        Self {
+            name: name.into(),
+            aliases: Vec::default(),
+            description: None,
+            args: Vec::default(),
+            permission: Permission::default(),
+            cooldown: None,
+            run: Box::new(run),
+        }
+    }
+
+    /// Adds an alternative name for this command.
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Sets the description shown by `!help`.
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Adds a positional argument.
+    pub fn arg(mut self, arg: Arg) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    /// Restricts who can use this command. Defaults to [`Permission::Anyone`].
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    /// Sets how long this command can't be used again in the same race room after being used.
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    fn matches(&self, cmd_name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(cmd_name) || self.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(cmd_name))
+    }
+
+    fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.clone(),
+            aliases: self.aliases.clone(),
+            description: self.description.clone(),
+            usage: self.usage(),
+            permission: self.permission,
+        }
+    }
+
+    fn usage(&self) -> String {
+        let mut usage = format!("!{}", self.name);
+        for arg in &self.args {
+            usage.push(' ');
+            usage.push_str(&arg.usage());
+        }
+        usage
+    }
+
+    fn parse_args(&self, race_data: &RaceData, raw_args: Vec<String>) -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut raw_args = raw_args.into_iter();
+        for arg in &self.args {
+            let raw = if let ArgKind::Rest = arg.kind {
+                Some(raw_args.by_ref().collect::<Vec<_>>().join(" ")).filter(|rest| !rest.is_empty())
+            } else {
+                raw_args.next()
+            };
+            if let Some(raw) = raw {
+                args.values.insert(arg.name.clone(), arg.parse(race_data, &raw)?);
+            } else if !arg.optional {
+                return Err(format!("missing {}", arg.usage()))
+            }
+        }
+        if raw_args.next().is_some() {
+            return Err("too many arguments".to_owned())
+        }
+        Ok(args)
+    }
+}
+
+/// A set of [`Command`]s which can be dispatched by name.
+///
+/// Unless disabled using [`CommandRouter::without_help`], a `!help` command is generated which lists the commands available to the user or shows the usage of a specific command.
+pub struct CommandRouter<H, S: Send + Sync + ?Sized + 'static> {
+    commands: Vec<Command<H, S>>,
+    help: bool,
+    last_used: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl<H, S: Send + Sync + ?Sized + 'static> CommandRouter<H, S> {
+    pub fn new() -> Self {
+        Self {
+            commands: Vec::default(),
+            help: true,
+            last_used: Mutex::default(),
+        }
+    }
+
+    /// Registers a command. If multiple commands share a name or alias, the one registered first takes precedence.
+    pub fn command(mut self, command: Command<H, S>) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Disables the generated `!help` command.
+    pub fn without_help(mut self) -> Self {
+        self.help = false;
+        self
+    }
+
+    /// Runs the command with the given name, replying in chat if the user doesn't have permission, the command is on cooldown, or the arguments are invalid.
+    ///
+    /// The parameters are the same as those of [`RaceHandler::command`](crate::RaceHandler::command). Returns whether a command (including `!help`) with the given name exists.
+    #[allow(clippy::too_many_arguments)] // mirrors the parameters of RaceHandler::command
+    pub async fn dispatch(&self, handler: &mut H, ctx: &RaceContext<S>, cmd_name: &str, args: Vec<String>, is_moderator: bool, is_monitor: bool, msg: &ChatMessage) -> Result<bool, Error> {
+        let (race_name, permission) = {
+            let data = ctx.data().await;
+            (data.name.clone(), Permission::of_sender(&data, msg, is_moderator, is_monitor))
+        };
+        let Some(command) = self.commands.iter().find(|command| command.matches(cmd_name)) else {
+            if self.help && cmd_name.eq_ignore_ascii_case("help") {
+                send_help(ctx, &self.commands.iter().map(Command::info).collect::<Vec<_>>(), args, permission).await?;
+                return Ok(true)
+            }
+            return Ok(false)
+        };
+        if permission < command.permission {
+            send_permission_denied(ctx, command.permission, &command.name).await?;
+            return Ok(true)
+        }
+        let args = match command.parse_args(&*ctx.data().await, args) {
+            Ok(args) => args,
+            Err(e) => {
+                send_usage_error(ctx, &e, &command.usage()).await?;
+                return Ok(true)
+            }
+        };
+        if let Some(cooldown) = command.cooldown {
+            let mut last_used = self.last_used.lock().await;
+            let key = (race_name, command.name.clone());
+            if let Some(remaining) = last_used.get(&key).and_then(|last_used| cooldown.checked_sub(last_used.elapsed())).filter(|remaining| !remaining.is_zero()) {
+                drop(last_used);
+                ctx.send_message(&format!("!{} is on cooldown, try again in {}s.", command.name, remaining.as_secs() + 1)).await?;
+                return Ok(true)
+            }
+            last_used.insert(key, Instant::now());
+        }
+        (command.run)(handler, ctx, args, msg).await?;
+        Ok(true)
+    }
+
+    /// Forgets when commands were last used in the given race room.
+    ///
+    /// This is called automatically for the router returned by [`RaceHandler::command_router`](crate::RaceHandler::command_router) when the bot stops handling the room. If [`dispatch`](CommandRouter::dispatch) is called manually, this should be called once the room is no longer needed.
+    pub async fn forget_race(&self, race_name: &str) {
+        self.last_used.lock().await.retain(|(race, _), _| race != race_name);
+    }
+}
+
+impl<H, S: Send + Sync + ?Sized + 'static> Default for CommandRouter<H, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Information about a command, used for `!help` and error messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandInfo {
+    /// The command's name, without the `!` prefix.
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub description: Option<String>,
+    /// The command's name and arguments, e.g. `!seed [standard|hard]`.
+    pub usage: String,
+    pub permission: Permission,
+}
+
+impl CommandInfo {
+    fn matches(&self, cmd_name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(cmd_name) || self.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(cmd_name))
+    }
+}
+
+/// A set of commands represented as an enum with one variant per command.
+///
+/// This is usually implemented using `#[derive(RaceCommand)]`, which requires the `derive` feature. Each variant is a command named after the lowercased variant name, and each field of a variant is a positional argument whose type implements [`FromArg`]. Fields of type `Option<T>` are optional. Doc comments on variants are used as descriptions for `!help`. The following attributes are supported:
+///
+/// * `#[command(name = "…")]` on a variant overrides the command name.
+/// * `#[command(alias = "…")]` on a variant adds an alternative name. Can be repeated.
+/// * `#[command(entrant)]`, `#[command(monitor)]`, or `#[command(moderator)]` on a variant sets the [`Permission`] required to use the command.
+/// * `#[command(name = "…")]` on a field overrides the argument name shown in usage text.
+/// * `#[command(rest)]` on the last field, which must be a `String` or `Option<String>`, collects the remainder of the command.
+///
+/// Use [`parse_command`] to parse a command in [`RaceHandler::command`](crate::RaceHandler::command).
+pub trait RaceCommand: Sized {
+    /// Returns information about each command, in declaration order.
+    fn commands() -> Vec<CommandInfo>;
+
+    /// Parses the arguments of the command with the given name, which is the [`name`](CommandInfo::name) of one of the entries returned by [`commands`](RaceCommand::commands).
+    ///
+    /// The error is a message suitable for displaying in chat.
+    fn parse_args(race_data: &RaceData, name: &str, args: Vec<String>) -> Result<Self, String>;
+}
+
+#[cfg(feature = "derive")] pub use racetime_derive::{
+    FromArg,
+    RaceCommand,
+};
+
+/// A type that can be used as an argument of a command declared using `#[derive(RaceCommand)]`.
+///
+/// `#[derive(FromArg)]` can be used on enums without fields, which will then accept any of the lowercased variant names, case-insensitive. Like for [`RaceCommand`], `#[command(name = "…")]` and `#[command(alias = "…")]` can be used on variants.
+pub trait FromArg: Sized {
+    /// Parses a single argument. The error is a message suitable for displaying in chat.
+    fn from_arg(race_data: &RaceData, arg: &str) -> Result<Self, String>;
+
+    /// The placeholder to show in usage text instead of the argument name, e.g. the allowed values of a choice.
+    ///
+    /// The default implementation returns `None`.
+    fn usage() -> Option<String> { None }
+}
+
+impl FromArg for String {
+    fn from_arg(_: &RaceData, arg: &str) -> Result<Self, String> {
+        Ok(arg.to_owned())
+    }
+}
+
+macro_rules! from_arg_int {
+    ($($ty:ty),*) => {
+        $(
+            impl FromArg for $ty {
+                fn from_arg(_: &RaceData, arg: &str) -> Result<Self, String> {
+                    arg.parse().map_err(|_| format!("{arg:?} is not a valid number"))
+                }
+            }
+        )*
+    };
+}
+
+from_arg_int!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl FromArg for bool {
+    fn from_arg(_: &RaceData, arg: &str) -> Result<Self, String> {
+        match &*arg.to_ascii_lowercase() {
+            "yes" | "y" | "on" | "true" => Ok(true),
+            "no" | "n" | "off" | "false" => Ok(false),
+            _ => Err(format!("{arg:?} is not one of yes, no")),
+        }
+    }
+
+    fn usage() -> Option<String> { Some("yes|no".to_owned()) }
+}
+
+impl FromArg for Duration {
+    fn from_arg(_: &RaceData, arg: &str) -> Result<Self, String> {
+        parse_duration(arg).ok_or_else(|| format!("{arg:?} is not a valid duration"))
+    }
+}
+
+impl FromArg for UserData {
+    fn from_arg(race_data: &RaceData, arg: &str) -> Result<Self, String> {
+        find_user(race_data, arg).cloned().ok_or_else(|| format!("could not find user {arg:?} in this race"))
+    }
+}
+
+/// Used by code generated by `#[derive(RaceCommand)]`.
+#[doc(hidden)]
+pub struct ArgParser<'a> {
+    race_data: &'a RaceData,
+    args: std::vec::IntoIter<String>,
+}
+
+#[doc(hidden)]
+impl<'a> ArgParser<'a> {
+    pub fn new(race_data: &'a RaceData, args: Vec<String>) -> Self {
+        Self { race_data, args: args.into_iter() }
+    }
+
+    pub fn usage<T: FromArg>(name: &str, optional: bool) -> String {
+        let name = T::usage().unwrap_or_else(|| name.to_owned());
+        if optional { format!("[{name}]") } else { format!("<{name}>") }
+    }
+
+    pub fn rest_usage(name: &str, optional: bool) -> String {
+        if optional { format!("[{name}…]") } else { format!("<{name}…>") }
+    }
+
+    pub fn required<T: FromArg>(&mut self, name: &str) -> Result<T, String> {
+        let raw = self.args.next().ok_or_else(|| format!("missing {}", Self::usage::<T>(name, false)))?;
+        T::from_arg(self.race_data, &raw)
+    }
+
+    pub fn optional<T: FromArg>(&mut self, _: &str) -> Result<Option<T>, String> {
+        self.args.next().map(|raw| T::from_arg(self.race_data, &raw)).transpose()
+    }
+
+    pub fn rest(&mut self, name: &str) -> Result<String, String> {
+        self.optional_rest(name)?.ok_or_else(|| format!("missing {}", Self::rest_usage(name, false)))
+    }
+
+    pub fn optional_rest(&mut self, _: &str) -> Result<Option<String>, String> {
+        Ok(Some(self.args.by_ref().collect::<Vec<_>>().join(" ")).filter(|rest| !rest.is_empty()))
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        if self.args.next().is_some() {
+            Err("too many arguments".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Parses a command declared using a [`RaceCommand`] type, replying in chat if the user doesn't have permission or the arguments are invalid.
+///
+/// The parameters are the same as those of [`RaceHandler::command`](crate::RaceHandler::command). Returns `None` if the command should not be run, including if no command with the given name exists. A `!help` command is generated if `C` doesn't define one.
+pub async fn parse_command<C: RaceCommand, S: Send + Sync + ?Sized + 'static>(ctx: &RaceContext<S>, cmd_name: &str, args: Vec<String>, is_moderator: bool, is_monitor: bool, msg: &ChatMessage) -> Result<Option<C>, Error> {
+    let commands = C::commands();
+    let permission = Permission::of_sender(&*ctx.data().await, msg, is_moderator, is_monitor);
+    let Some(command) = commands.iter().find(|command| command.matches(cmd_name)) else {
+        if cmd_name.eq_ignore_ascii_case("help") {
+            send_help(ctx, &commands, args, permission).await?;
+        }
+        return Ok(None)
+    };
+    if permission < command.permission {
+        send_permission_denied(ctx, command.permission, &command.name).await?;
+        return Ok(None)
+    }
+    let result = C::parse_args(&*ctx.data().await, &command.name, args);
+    match result {
+        Ok(command) => Ok(Some(command)),
+        Err(e) => {
+            send_usage_error(ctx, &e, &command.usage).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn send_permission_denied<S: Send + Sync + ?Sized + 'static>(ctx: &RaceContext<S>, required: Permission, cmd_name: &str) -> Result<(), Error> {
+    ctx.send_message(&format!("Sorry, only {required} can use !{cmd_name}.")).await
+}
+
+async fn send_usage_error<S: Send + Sync + ?Sized + 'static>(ctx: &RaceContext<S>, error: &str, usage: &str) -> Result<(), Error> {
+    ctx.send_message(&format!("Error: {error}. Usage: {usage}")).await
+}
+
+async fn send_help<S: Send + Sync + ?Sized + 'static>(ctx: &RaceContext<S>, commands: &[CommandInfo], args: Vec<String>, permission: Permission) -> Result<(), Error> {
+    if let Some(cmd_name) = args.first() {
+        let cmd_name = cmd_name.strip_prefix('!').unwrap_or(cmd_name);
+        if let Some(command) = commands.iter().find(|command| command.matches(cmd_name)) {
+            let mut reply = command.usage.clone();
+            if let Some(ref description) = command.description {
+                reply.push_str(" – ");
+                reply.push_str(description);
+            }
+            if !command.aliases.is_empty() {
+                reply.push_str(&format!(" (aliases: {})", command.aliases.iter().map(|alias| format!("!{alias}")).collect::<Vec<_>>().join(", ")));
+            }
+            if command.permission > Permission::Anyone {
+                reply.push_str(&format!(" [{} only]", command.permission));
+            }
+            ctx.send_message(&reply).await
+        } else {
+            ctx.send_message(&format!("Unknown command: !{cmd_name}")).await
+        }
+    } else {
+        let available = commands.iter()
+            .filter(|command| permission >= command.permission)
+            .map(|command| format!("!{}", command.name))
+            .collect::<Vec<_>>();
+        if available.is_empty() {
+            ctx.send_message("No commands available.").await
+        } else {
+            ctx.send_message(&format!("Available commands: {}. Use !help <command> for details.", available.join(", "))).await
+        }
+    }
+}
+
+/// Parses a duration given as a number of seconds (`90`), with unit suffixes (`1h30m`, `90s`), or as colon-separated hours, minutes, and seconds (`1:30:00`, `1:30`).
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    /// Converts a possibly empty number of the given unit to seconds, returning [`None`] on overflow.
+    fn secs(value: &str, unit: u64) -> Option<u64> {
+        if value.is_empty() { Some(0) } else { value.parse::<u64>().ok()?.checked_mul(unit) }
+    }
+
+    if let Ok(secs) = s.parse() {
+        Some(Duration::from_secs(secs))
+    } else if let Some((_, hours, minutes, seconds)) = regex_captures!("^(?:([0-9]+):)?([0-9]+):([0-9]{2})$", s) {
+        Some(Duration::from_secs(
+            secs(hours, 60 * 60)?
+                .checked_add(secs(minutes, 60)?)?
+                .checked_add(secs(seconds, 1)?)?
+        ))
+    } else if let Some((_, hours, minutes, seconds)) = regex_captures!("^(?:([0-9]+)h)?(?:([0-9]+)m)?(?:([0-9]+)s)?$", &s.to_ascii_lowercase()) {
+        if hours.is_empty() && minutes.is_empty() && seconds.is_empty() { return None }
+        Some(Duration::from_secs(
+            secs(hours, 60 * 60)?
+                .checked_add(secs(minutes, 60)?)?
+                .checked_add(secs(seconds, 1)?)?
+        ))
+    } else {
+        None
+    }
+}
+
+/// Finds a user who is entered in, monitoring, or opened the race by name or full name (with discriminator), case-insensitive. A leading `@` is ignored.
+pub fn find_user<'a>(race_data: &'a RaceData, name: &str) -> Option<&'a UserData> {
+    let name = name.strip_prefix('@').unwrap_or(name);
+    race_data.entrants.iter().map(|entrant| &entrant.user)
+        .chain(&race_data.monitors)
+        .chain(&race_data.opened_by)
+        .find(|user| user.name.eq_ignore_ascii_case(name) || user.full_name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::test_util,
+        super::*,
+    };
+
+    fn command(args: impl IntoIterator<Item = Arg>) -> Command<(), ()> {
+        args.into_iter().fold(
+            Command::new("test", |(), _ctx, _args, _msg| Box::pin(async { Ok(()) })),
+            Command::arg,
+        )
+    }
+
+    fn raw_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    #[tokio::test]
+    async fn forget_race_clears_cooldowns() -> Result<(), Error> {
+        let ctx = test_util::context(test_util::race(RaceStatusValue::Open));
+        let router = CommandRouter::new()
+            .command(command([]).cooldown(Duration::from_secs(60)));
+        let msg = ChatMessage {
+            id: "message-1".to_owned(),
+            user: Some(test_util::user("Alice")),
+            bot: None,
+            posted_at: chrono::Utc::now(),
+            message: "!test".to_owned(),
+            message_plain: "!test".to_owned(),
+            highlight: false,
+            is_bot: false,
+            is_system: Some(false),
+        };
+        assert!(router.dispatch(&mut (), &ctx, "test", Vec::default(), false, false, &msg).await?);
+        assert_eq!(router.last_used.lock().await.len(), 1);
+        router.forget_race("other/race-1234").await;
+        assert_eq!(router.last_used.lock().await.len(), 1);
+        router.forget_race(&ctx.data().await.name).await;
+        assert!(router.last_used.lock().await.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_duration_formats() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1H5S"), Some(Duration::from_secs(60 * 60 + 5)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1:30:00"), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1:30"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("1:3"), None);
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert_eq!(parse_duration("5124095576030432h"), None);
+        assert_eq!(parse_duration("307445734561825861m"), None);
+        assert_eq!(parse_duration("5124095576030431h59m60s"), None);
+        assert_eq!(parse_duration("5124095576030432:00:00"), None);
+        assert_eq!(parse_duration("307445734561825861:00"), None);
+        assert_eq!(parse_duration("18446744073709551615"), Some(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn parse_args_required_and_optional() {
+        let race = test_util::race(RaceStatusValue::Open);
+        let command = command([Arg::int("count"), Arg::duration("delay").optional()]);
+        let args = command.parse_args(&race, raw_args(&["3", "1m"])).unwrap();
+        assert_eq!(args.int("count"), Some(3));
+        assert_eq!(args.duration("delay"), Some(Duration::from_secs(60)));
+        let args = command.parse_args(&race, raw_args(&["3"])).unwrap();
+        assert_eq!(args.int("count"), Some(3));
+        assert_eq!(args.get("delay"), None);
+        assert_eq!(command.parse_args(&race, Vec::default()), Err("missing <count>".to_owned()));
+        assert_eq!(command.parse_args(&race, raw_args(&["3", "1m", "extra"])), Err("too many arguments".to_owned()));
+        assert!(command.parse_args(&race, raw_args(&["three"])).is_err());
+        assert!(command.parse_args(&race, raw_args(&["3", "5124095576030432h"])).is_err());
+    }
+
+    #[test]
+    fn parse_args_choice_user_and_rest() {
+        let mut race = test_util::race(RaceStatusValue::Open);
+        race.entrants.push(test_util::entrant("Alice", EntrantStatusValue::Ready));
+        let command = command([Arg::choice("preset", ["Standard", "Hard"]), Arg::user("opponent"), Arg::rest("comment").optional()]);
+        let args = command.parse_args(&race, raw_args(&["hard", "@alice", "good", "luck"])).unwrap();
+        assert_eq!(args.str("preset"), Some("Hard"));
+        assert_eq!(args.user("opponent").map(|user| &*user.name), Some("Alice"));
+        assert_eq!(args.str("comment"), Some("good luck"));
+        let args = command.parse_args(&race, raw_args(&["standard", "Owner#0000"])).unwrap();
+        assert_eq!(args.user("opponent").map(|user| &*user.name), Some("Owner"));
+        assert_eq!(args.get("comment"), None);
+        assert!(command.parse_args(&race, raw_args(&["easy", "alice"])).is_err());
+        assert!(command.parse_args(&race, raw_args(&["hard", "bob"])).is_err());
+        let command = self::command([Arg::rest("comment")]);
+        assert_eq!(command.parse_args(&race, Vec::default()), Err("missing <comment…>".to_owned()));
+    }
+}
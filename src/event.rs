@@ -0,0 +1,241 @@
+//! High-level race room events, derived by comparing consecutive versions of a race's [`RaceData`].
+
+use {
+    chrono::Duration,
+    crate::model::*,
+};
+
+/// Something that happened in a race room between two `race.data` messages.
+///
+/// The bot computes these automatically and passes them to [`RaceHandler::race_event`](crate::RaceHandler::race_event). They can also be computed manually using [`RaceEvent::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RaceEvent {
+    /// A user joined the race, either directly or by accepting an invite or having their join request accepted.
+    EntrantJoined(Entrant),
+    /// An entrant left the race or was removed from it. This is also emitted when a pending invite or join request disappears.
+    EntrantLeft(Entrant),
+    EntrantReady(Entrant),
+    EntrantUnready(Entrant),
+    /// A user was invited to the race.
+    EntrantInvited(Entrant),
+    /// A user requested to join an invitational race.
+    EntrantRequested(Entrant),
+    /// A user declined an invite to the race.
+    EntrantDeclined(Entrant),
+    EntrantFinished {
+        entrant: Entrant,
+        place: Option<u32>,
+        finish_time: Option<Duration>,
+    },
+    EntrantForfeited(Entrant),
+    EntrantDisqualified(Entrant),
+    /// An entrant's finish, forfeit, or disqualification was undone.
+    EntrantUndone(Entrant),
+    /// An entrant added or changed their comment.
+    CommentAdded {
+        entrant: Entrant,
+        comment: String,
+    },
+    /// The race's status changed, e.g. from [`Open`](RaceStatusValue::Open) to [`Pending`](RaceStatusValue::Pending).
+    StatusChanged {
+        old: RaceStatusValue,
+        new: RaceStatusValue,
+    },
+    MonitorAdded(UserData),
+    MonitorRemoved(UserData),
+    /// The race's combined info text (see [`RaceData::info`]) changed.
+    InfoChanged {
+        old: String,
+        new: String,
+    },
+}
+
+impl RaceEvent {
+    /// Returns the events that happened between the `old` and `new` versions of a race's data.
+    ///
+    /// Entrants and monitors are matched by user ID.
+    pub fn diff(old: &RaceData, new: &RaceData) -> Vec<Self> {
+        let mut events = Vec::default();
+        if old.status.value != new.status.value {
+            events.push(Self::StatusChanged { old: old.status.value, new: new.status.value });
+        }
+        if old.info != new.info {
+            events.push(Self::InfoChanged { old: old.info.clone(), new: new.info.clone() });
+        }
+        for monitor in &new.monitors {
+            if !old.monitors.iter().any(|old_monitor| old_monitor.id == monitor.id) {
+                events.push(Self::MonitorAdded(monitor.clone()));
+            }
+        }
+        for monitor in &old.monitors {
+            if !new.monitors.iter().any(|new_monitor| new_monitor.id == monitor.id) {
+                events.push(Self::MonitorRemoved(monitor.clone()));
+            }
+        }
+        for entrant in &new.entrants {
+            if let Some(old_entrant) = old.entrants.iter().find(|old_entrant| old_entrant.user.id == entrant.user.id) {
+                if let Some(event) = Self::entrant_status_change(old_entrant.status.value, entrant) {
+                    events.push(event);
+                }
+                if let Some(ref comment) = entrant.comment {
+                    if old_entrant.comment.as_ref() != Some(comment) {
+                        events.push(Self::CommentAdded { entrant: entrant.clone(), comment: comment.clone() });
+                    }
+                }
+            } else {
+                events.push(match entrant.status.value {
+                    EntrantStatusValue::Requested => Self::EntrantRequested(entrant.clone()),
+                    EntrantStatusValue::Invited => Self::EntrantInvited(entrant.clone()),
+                    EntrantStatusValue::Declined => Self::EntrantDeclined(entrant.clone()),
+                    _ => Self::EntrantJoined(entrant.clone()),
+                });
+            }
+        }
+        for entrant in &old.entrants {
+            if !new.entrants.iter().any(|new_entrant| new_entrant.user.id == entrant.user.id) {
+                events.push(Self::EntrantLeft(entrant.clone()));
+            }
+        }
+        events
+    }
+
+    fn entrant_status_change(old_status: EntrantStatusValue, entrant: &Entrant) -> Option<Self> {
+        use EntrantStatusValue::*;
+
+        let entrant = entrant.clone();
+        Some(match (old_status, entrant.status.value) {
+            (old, new) if old == new => return None,
+            (_, Requested) => Self::EntrantRequested(entrant),
+            (_, Invited) => Self::EntrantInvited(entrant),
+            (_, Declined) => Self::EntrantDeclined(entrant),
+            (Requested | Invited | Declined, NotReady | Ready) => Self::EntrantJoined(entrant),
+            (_, Ready) => Self::EntrantReady(entrant),
+            (Ready, NotReady) => Self::EntrantUnready(entrant),
+            (_, Done) => Self::EntrantFinished { place: entrant.place, finish_time: entrant.finish_time, entrant },
+            (_, Dnf) => Self::EntrantForfeited(entrant),
+            (_, Dq) => Self::EntrantDisqualified(entrant),
+            (Done | Dnf | Dq, _) => Self::EntrantUndone(entrant),
+            (_, NotReady | InProgress) => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::test_util,
+        super::*,
+    };
+
+    fn with_status(mut entrant: Entrant, status: EntrantStatusValue) -> Entrant {
+        entrant.status.value = status;
+        entrant
+    }
+
+    #[test]
+    fn no_change() {
+        let mut race = test_util::race(RaceStatusValue::Open);
+        race.entrants.push(test_util::entrant("Alice", EntrantStatusValue::Ready));
+        race.monitors.push(test_util::user("Bob"));
+        let mut new = race.clone();
+        new.version += 1;
+        new.entrants_count = 1;
+        assert_eq!(RaceEvent::diff(&race, &new), Vec::default());
+    }
+
+    #[test]
+    fn status_transitions() {
+        let open = test_util::race(RaceStatusValue::Open);
+        for (old, new) in [
+            (RaceStatusValue::Open, RaceStatusValue::Invitational),
+            (RaceStatusValue::Open, RaceStatusValue::Pending),
+            (RaceStatusValue::Pending, RaceStatusValue::InProgress),
+            (RaceStatusValue::InProgress, RaceStatusValue::Finished),
+            (RaceStatusValue::Open, RaceStatusValue::Cancelled),
+        ] {
+            let old_race = RaceData { status: test_util::race_status(old), ..open.clone() };
+            let new_race = RaceData { status: test_util::race_status(new), ..open.clone() };
+            assert_eq!(RaceEvent::diff(&old_race, &new_race), vec![RaceEvent::StatusChanged { old, new }]);
+        }
+    }
+
+    #[test]
+    fn entrant_joined_and_left() {
+        let old = test_util::race(RaceStatusValue::Open);
+        let mut new = old.clone();
+        let alice = test_util::entrant("Alice", EntrantStatusValue::NotReady);
+        let bob = test_util::entrant("Bob", EntrantStatusValue::Invited);
+        let carol = test_util::entrant("Carol", EntrantStatusValue::Requested);
+        new.entrants = vec![alice.clone(), bob.clone(), carol.clone()];
+        assert_eq!(RaceEvent::diff(&old, &new), vec![
+            RaceEvent::EntrantJoined(alice.clone()),
+            RaceEvent::EntrantInvited(bob.clone()),
+            RaceEvent::EntrantRequested(carol.clone()),
+        ]);
+        assert_eq!(RaceEvent::diff(&new, &old), vec![
+            RaceEvent::EntrantLeft(alice),
+            RaceEvent::EntrantLeft(bob),
+            RaceEvent::EntrantLeft(carol),
+        ]);
+    }
+
+    #[test]
+    fn entrant_status_changes() {
+        use EntrantStatusValue::*;
+
+        let alice = test_util::entrant("Alice", NotReady);
+        let mut finished = with_status(alice.clone(), Done);
+        finished.place = Some(1);
+        finished.finish_time = Some(Duration::minutes(90));
+        let cases = [
+            (Invited, with_status(alice.clone(), NotReady), Some(RaceEvent::EntrantJoined(with_status(alice.clone(), NotReady)))),
+            (Requested, with_status(alice.clone(), Ready), Some(RaceEvent::EntrantJoined(with_status(alice.clone(), Ready)))),
+            (Invited, with_status(alice.clone(), Declined), Some(RaceEvent::EntrantDeclined(with_status(alice.clone(), Declined)))),
+            (NotReady, with_status(alice.clone(), Ready), Some(RaceEvent::EntrantReady(with_status(alice.clone(), Ready)))),
+            (Ready, with_status(alice.clone(), NotReady), Some(RaceEvent::EntrantUnready(with_status(alice.clone(), NotReady)))),
+            (Ready, with_status(alice.clone(), InProgress), None),
+            (InProgress, finished.clone(), Some(RaceEvent::EntrantFinished { entrant: finished.clone(), place: Some(1), finish_time: Some(Duration::minutes(90)) })),
+            (InProgress, with_status(alice.clone(), Dnf), Some(RaceEvent::EntrantForfeited(with_status(alice.clone(), Dnf)))),
+            (InProgress, with_status(alice.clone(), Dq), Some(RaceEvent::EntrantDisqualified(with_status(alice.clone(), Dq)))),
+            (Done, with_status(alice.clone(), InProgress), Some(RaceEvent::EntrantUndone(with_status(alice.clone(), InProgress)))),
+            (Dnf, with_status(alice.clone(), InProgress), Some(RaceEvent::EntrantUndone(with_status(alice.clone(), InProgress)))),
+            (InProgress, with_status(alice.clone(), InProgress), None),
+        ];
+        for (old_status, entrant, expected) in cases {
+            assert_eq!(RaceEvent::entrant_status_change(old_status, &entrant), expected, "{old_status:?} → {:?}", entrant.status.value);
+            let mut old = test_util::race(RaceStatusValue::InProgress);
+            old.entrants.push(with_status(entrant.clone(), old_status));
+            let mut new = old.clone();
+            new.entrants = vec![entrant];
+            assert_eq!(RaceEvent::diff(&old, &new), expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let mut old = test_util::race(RaceStatusValue::Finished);
+        old.entrants.push(test_util::entrant("Alice", EntrantStatusValue::Done));
+        let mut new = old.clone();
+        new.entrants[0].comment = Some("gg".to_owned());
+        assert_eq!(RaceEvent::diff(&old, &new), vec![RaceEvent::CommentAdded { entrant: new.entrants[0].clone(), comment: "gg".to_owned() }]);
+        let mut edited = new.clone();
+        edited.entrants[0].comment = Some("gg wp".to_owned());
+        assert_eq!(RaceEvent::diff(&new, &edited), vec![RaceEvent::CommentAdded { entrant: edited.entrants[0].clone(), comment: "gg wp".to_owned() }]);
+        // removing a comment isn't an event
+        assert_eq!(RaceEvent::diff(&new, &old), Vec::default());
+    }
+
+    #[test]
+    fn monitors_and_info() {
+        let mut old = test_util::race(RaceStatusValue::Open);
+        old.monitors.push(test_util::user("Bob"));
+        let mut new = old.clone();
+        new.monitors = vec![test_util::user("Carol")];
+        new.info = "Seed: 1234".to_owned();
+        assert_eq!(RaceEvent::diff(&old, &new), vec![
+            RaceEvent::InfoChanged { old: String::default(), new: "Seed: 1234".to_owned() },
+            RaceEvent::MonitorAdded(test_util::user("Carol")),
+            RaceEvent::MonitorRemoved(test_util::user("Bob")),
+        ]);
+    }
+}
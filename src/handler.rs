@@ -1,45 +1,58 @@
 use {
-    std::sync::Arc,
-    async_trait::async_trait,
-    futures::{
-        SinkExt as _,
-        stream::{
-            SplitSink,
-            SplitStream,
-        },
+    std::{
+        collections::HashMap,
+        sync::Arc,
     },
+    async_trait::async_trait,
+    chrono::Duration,
     serde_json::{
         Value as Json,
         json,
     },
-    tokio::{
-        net::TcpStream,
-        sync::{
-            Mutex,
-            RwLock,
-            RwLockReadGuard,
-        },
-    },
-    tokio_tungstenite::{
-        MaybeTlsStream,
-        WebSocketStream,
-        tungstenite,
+    tokio::sync::{
+        RwLock,
+        RwLockReadGuard,
+        mpsc,
     },
     uuid::Uuid,
     crate::{
         Error,
+        announcer::Announcer,
+        bot::ErrorContext,
+        command::CommandRouter,
+        event::RaceEvent,
+        message::{
+            ActionInvocation,
+            MessageBuilder,
+        },
         model::*,
+        moderation::ModerationFilter,
+        queue::{
+            Delivery,
+            Priority,
+            SendQueue,
+        },
+        stream::BotEvent,
+        timer::Timers,
     },
 };
 
-pub type WsStream = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
-pub type WsSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>;
+/// The maximum length of a chat message accepted by racetime.gg, in characters.
+pub const MAX_MESSAGE_LENGTH: usize = 1000;
 
 /// A type passed to [`RaceHandler`] callback methods which can be used to check the current status of the race or send messages.
 pub struct RaceContext<S: Send + Sync + ?Sized + 'static> {
     pub global_state: Arc<S>,
     pub(crate) data: Arc<RwLock<RaceData>>,
-    pub sender: Arc<Mutex<WsSink>>,
+    pub(crate) queue: Arc<SendQueue>,
+    /// The names of the commands of the action buttons sent in this room, mapped to the names of their fields.
+    pub(crate) actions: Arc<std::sync::Mutex<HashMap<String, Vec<String>>>>,
+    pub(crate) timers: Arc<Timers>,
+    /// The sender of the stream returned by [`Bot::into_stream`](crate::Bot::into_stream), if the bot was started that way.
+    pub(crate) events: Option<mpsc::Sender<BotEvent<S>>>,
+    /// The span of the race room, used as the parent of the spans of actions so they can be attributed to the room even when called from other tasks.
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
 }
 
 impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
@@ -48,29 +61,97 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
         self.data.read().await
     }
 
-    /// Sends a raw JSON message to the server.
+    /// Sends a raw JSON message to the server and waits until it has been sent.
+    ///
+    /// The message is queued with the priority returned by [`Priority::of`]. See the [`queue`](crate::queue) module for details.
     ///
-    /// The methods [`set_bot_raceinfo`](RaceContext::set_bot_raceinfo) through [`remove_monitor`](RaceContext::remove_monitor) should be preferred.
+    /// The methods [`set_bot_raceinfo`](RaceContext::set_bot_raceinfo) through [`override_stream`](RaceContext::override_stream) should be preferred.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self)))]
     pub async fn send_raw(&self, message: &Json) -> Result<(), Error> {
-        self.sender.lock().await.send(tungstenite::Message::Text(serde_json::to_string(&message)?)).await?;
-        Ok(())
+        self.queue_raw(message.clone(), Priority::of(message)).await
+    }
+
+    /// Adds a raw JSON message to the race room's send queue without waiting for it to be sent.
+    ///
+    /// The returned [`Delivery`] can be awaited to wait until the message has been sent.
+    pub fn queue_raw(&self, message: Json, priority: Priority) -> Delivery {
+        self.queue.push(message, priority)
     }
 
     /// Send a chat message to the race room.
     ///
     /// `message` should be the message string you want to send.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn send_message(&self, message: &str) -> Result<(), Error> {
-        self.send_raw(&json!({
+        self.queue_message(message).await
+    }
+
+    /// Adds a chat message to the race room's send queue without waiting for it to be sent.
+    ///
+    /// The returned [`Delivery`] can be awaited to wait until the message has been sent.
+    pub fn queue_message(&self, message: &str) -> Delivery {
+        self.queue_raw(json!({
             "action": "message",
             "data": {
                 "message": message,
                 "guid": Uuid::new_v4(),
             },
-        })).await?;
+        }), Priority::Normal)
+    }
+
+    /// Schedules a call to [`RaceHandler::timer`] with the given name after `delay`, replacing any timer with the same name.
+    ///
+    /// Timers are cancelled when the race handler stops or is restarted.
+    pub fn set_timer(&self, name: impl Into<String>, delay: std::time::Duration) {
+        self.timers.set(name.into(), delay, None);
+    }
+
+    /// Schedules calls to [`RaceHandler::timer`] with the given name every `period`, starting after one period, until cancelled. Replaces any timer with the same name.
+    ///
+    /// Timers are cancelled when the race handler stops or is restarted. If the handler falls behind, missed calls are skipped.
+    ///
+    /// # Panics
+    ///
+    /// If `period` is zero.
+    pub fn set_interval(&self, name: impl Into<String>, period: std::time::Duration) {
+        assert!(!period.is_zero(), "timer interval must be nonzero");
+        self.timers.set(name.into(), period, Some(period));
+    }
+
+    /// Cancels the timer with the given name. Returns whether there was such a timer.
+    pub fn cancel_timer(&self, name: &str) -> bool {
+        self.timers.cancel(name)
+    }
+
+    /// Sends an event to the stream returned by [`Bot::into_stream`](crate::Bot::into_stream), if any. Events are dropped if the stream has been dropped.
+    pub(crate) async fn emit(&self, event: impl FnOnce() -> BotEvent<S>) {
+        if let Some(ref events) = self.events {
+            let _ = events.send(event()).await;
+        }
+    }
+
+    /// Starts building a chat message with additional options, such as pinning it or adding action buttons.
+    pub fn message(&self, message: impl Into<String>) -> MessageBuilder<'_, S> {
+        MessageBuilder::new(self, message.into())
+    }
+
+    /// Send a chat message to the race room, splitting it into multiple messages if it's longer than [`MAX_MESSAGE_LENGTH`].
+    ///
+    /// See [`split_message`] for how the message is split. `continuation`, if given, is prepended to every message after the first, e.g. `"(cont.) "`. The messages are sent in order.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
+    pub async fn send_long_message(&self, message: &str, continuation: Option<&str>) -> Result<(), Error> {
+        // queue all parts before waiting so they can't be interleaved with other messages from this context
+        let deliveries = split_message(message, MAX_MESSAGE_LENGTH, continuation.unwrap_or_default()).iter()
+            .map(|part| self.queue_message(part))
+            .collect::<Vec<_>>();
+        for delivery in deliveries {
+            delivery.await?;
+        }
         Ok(())
     }
 
     /// Set the `info_bot` field on the race room's data.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn set_bot_raceinfo(&self, info: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "setinfo",
@@ -82,6 +163,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Set the `info_user` field on the race room's data.
     ///
     /// `info` should be the information you wish to set, and `pos` the behavior in case there is existing info.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn set_user_raceinfo(&self, info: &str, pos: RaceInfoPos) -> Result<(), Error> {
         let info = match (&*self.data().await.info, pos) {
             ("", _) | (_, RaceInfoPos::Overwrite) => info.to_owned(),
@@ -96,6 +178,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     }
 
     /// Set the room in an open state.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn set_open(&self) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "make_open",
@@ -104,6 +187,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     }
 
     /// Set the room in an invite-only state.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn set_invitational(&self) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "make_invitational",
@@ -112,6 +196,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     }
 
     /// Forces a start of the race.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn force_start(&self) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "begin",
@@ -120,6 +205,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     }
 
     /// Forcibly cancels a race.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn cancel_race(&self) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "cancel",
@@ -130,6 +216,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Invites a user to the race.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn invite_user(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "invite",
@@ -143,6 +230,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Accepts a request to join the race room.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn accept_request(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "accept_request",
@@ -156,6 +244,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Forcibly unreadies an entrant.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn force_unready(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "force_unready",
@@ -169,6 +258,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Forcibly removes an entrant from the race.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn remove_entrant(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "remove_entrant",
@@ -182,6 +272,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Adds a user as a race monitor.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn add_monitor(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "add_monitor",
@@ -195,6 +286,7 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
     /// Removes a user as a race monitor.
     ///
     /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
     pub async fn remove_monitor(&self, user: &str) -> Result<(), Error> {
         self.send_raw(&json!({
             "action": "remove_monitor",
@@ -204,6 +296,88 @@ impl<S: Send + Sync + ?Sized + 'static> RaceContext<S> {
         })).await?;
         Ok(())
     }
+
+    /// Deletes a chat message.
+    ///
+    /// `message_id` should be the [`id`](ChatMessage::id) of the message.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
+    pub async fn delete_message(&self, message_id: &str) -> Result<(), Error> {
+        self.queue_delete_message(message_id).await
+    }
+
+    /// Adds a [`delete_message`](RaceContext::delete_message) action to the race room's send queue without waiting for it to be sent.
+    pub fn queue_delete_message(&self, message_id: &str) -> Delivery {
+        self.queue_raw(json!({
+            "action": "delete_message",
+            "data": {
+                "message": message_id,
+            },
+        }), Priority::High)
+    }
+
+    /// Deletes all chat messages sent by a user in this race room.
+    ///
+    /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
+    pub async fn purge_user(&self, user: &str) -> Result<(), Error> {
+        self.queue_purge_user(user).await
+    }
+
+    /// Adds a [`purge_user`](RaceContext::purge_user) action to the race room's send queue without waiting for it to be sent.
+    pub fn queue_purge_user(&self, user: &str) -> Delivery {
+        self.queue_raw(json!({
+            "action": "purge_user",
+            "data": {
+                "user": user,
+            },
+        }), Priority::High)
+    }
+
+    /// Exempts an entrant from the race's streaming requirement. See [`Entrant::stream_override`].
+    ///
+    /// `user` should be the hashid of the user.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", parent = &self.span, skip(self), err))]
+    pub async fn override_stream(&self, user: &str) -> Result<(), Error> {
+        self.send_raw(&json!({
+            "action": "override_stream",
+            "data": {
+                "user": user,
+            },
+        })).await?;
+        Ok(())
+    }
+}
+
+/// Splits a chat message into parts of at most `max_len` characters each, including the `continuation` prefix on every part after the first.
+///
+/// Parts are split at the last line break that fits, or at the last whitespace if there is no line break, and only in the middle of a word if a single word doesn't fit. Whitespace at the split points is removed.
+pub fn split_message(message: &str, max_len: usize, continuation: &str) -> Vec<String> {
+    let mut parts = Vec::default();
+    let mut rest = message;
+    loop {
+        let prefix = if parts.is_empty() { "" } else { continuation };
+        let budget = max_len.saturating_sub(prefix.chars().count()).max(1);
+        let Some((limit, next_char)) = rest.char_indices().nth(budget) else {
+            parts.push(format!("{prefix}{rest}"));
+            break
+        };
+        // the character right after the limit is also a valid split point
+        let window = &rest[..limit + next_char.len_utf8()];
+        let (part, tail) = if let Some(idx) = window.rfind('\n').filter(|&idx| idx > 0) {
+            (&rest[..idx], &rest[idx + 1..])
+        } else if let Some((idx, whitespace)) = window.char_indices().rev().find(|&(idx, c)| idx > 0 && c.is_whitespace()) {
+            (&rest[..idx], &rest[idx + whitespace.len_utf8()..])
+        } else {
+            (&rest[..limit], &rest[limit..])
+        };
+        let part = part.trim_end();
+        if !part.is_empty() {
+            parts.push(format!("{prefix}{part}"));
+        }
+        rest = tail.trim_start_matches(['\r', '\n']);
+        if rest.is_empty() { break }
+    }
+    parts
 }
 
 impl<S: Send + Sync + ?Sized + 'static> Clone for RaceContext<S> {
@@ -211,7 +385,12 @@ impl<S: Send + Sync + ?Sized + 'static> Clone for RaceContext<S> {
         Self {
             global_state: Arc::clone(&self.global_state),
             data: Arc::clone(&self.data),
-            sender: Arc::clone(&self.sender),
+            queue: Arc::clone(&self.queue),
+            actions: Arc::clone(&self.actions),
+            timers: Arc::clone(&self.timers),
+            events: self.events.clone(),
+            #[cfg(feature = "tracing")]
+            span: self.span.clone(),
         }
     }
 }
@@ -243,6 +422,45 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// The `RaceHandler` this returns will receive events for that race.
     async fn new(ctx: &RaceContext<S>) -> Result<Self, Error>;
 
+    /// Called instead of [`new`](RaceHandler::new) if a [`StateStore`](crate::persistence::StateStore) is set and has saved state for the race room, e.g. because the bot was restarted while handling it.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn restore(ctx: &RaceContext<S>, _state: Json) -> Result<Self, Error>;
+    /// ```
+    ///
+    /// `state` is a value previously returned by [`save_state`](RaceHandler::save_state).
+    ///
+    /// The default implementation ignores the state and calls [`new`](RaceHandler::new).
+    async fn restore(ctx: &RaceContext<S>, _state: Json) -> Result<Self, Error> {
+        Self::new(ctx).await
+    }
+
+    /// Returns the state of this handler which should be saved to the bot's [`StateStore`](crate::persistence::StateStore), if any. This is checked after every callback and the state is saved if it has changed.
+    ///
+    /// The default implementation returns [`None`], so nothing is saved.
+    fn save_state(&self) -> Result<Option<Json>, Error> { Ok(None) }
+
+    /// Returns the [`CommandRouter`] used by the default implementation of [`command`](RaceHandler::command).
+    ///
+    /// The router is shared between all race rooms handled by this type (cooldowns are tracked per room), so it should usually be created once and cloned from a static or from the global state.
+    ///
+    /// The default implementation returns [`None`].
+    fn command_router(&self) -> Option<Arc<CommandRouter<Self, S>>> { None }
+
+    /// Returns the [`ModerationFilter`] applied to chat messages before they are passed to [`chat_message`](RaceHandler::chat_message).
+    ///
+    /// Like the command router, the filter is shared between all race rooms handled by this type (violations are tracked per room).
+    ///
+    /// The default implementation returns [`None`].
+    fn moderation_filter(&self) -> Option<Arc<ModerationFilter>> { None }
+
+    /// Returns the [`Announcer`] which announces the countdown, elapsed time, and time limit of the race in chat.
+    ///
+    /// The default implementation returns [`None`].
+    fn announcer(&self) -> Option<Arc<Announcer>> { None }
+
     /// Called for each chat message that starts with `!` and was not sent by the system or a bot.
     ///
     /// Equivalent to:
@@ -250,11 +468,16 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// ```ignore
     /// async fn command(&mut self: _ctx: &RaceContext<S>, _cmd_name: String, _args: Vec<String>, _is_moderator: bool, _is_monitor: bool, _msg: &ChatMessage) -> Result<(), Error>;
     /// ```
-    async fn command(&mut self, _ctx: &RaceContext<S>, _cmd_name: String, _args: Vec<String>, _is_moderator: bool, _is_monitor: bool, _msg: &ChatMessage) -> Result<(), Error> {
+    ///
+    /// The default implementation dispatches the command using the [`command_router`](RaceHandler::command_router), if any.
+    async fn command(&mut self, ctx: &RaceContext<S>, cmd_name: String, args: Vec<String>, is_moderator: bool, is_monitor: bool, msg: &ChatMessage) -> Result<(), Error> {
+        if let Some(router) = self.command_router() {
+            router.dispatch(self, ctx, &cmd_name, args, is_moderator, is_monitor, msg).await?;
+        }
         Ok(())
     }
 
-    /// Determine if the handler should be terminated. This is checked after every receieved message.
+    /// Determine if the handler should be terminated. This is checked after every receieved message and every [`timer`](RaceHandler::timer) call.
     ///
     /// Equivalent to:
     ///
@@ -286,6 +509,8 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// async fn chat_history(&mut self, _ctx: &RaceContext<S>: _msgs: Vec<ChatMessage>) -> Result<(), Error>;
     /// ```
     ///
+    /// The server sends the chat history again after the bot reconnects. Messages which have already been received in this race room are removed from it, so the list may be empty.
+    ///
     /// The default implementation does nothing.
     async fn chat_history(&mut self, _ctx: &RaceContext<S>, _msgs: Vec<ChatMessage>) -> Result<(), Error> { Ok(()) }
 
@@ -297,12 +522,14 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// async fn chat_message(&mut self, ctx: &RaceContext<S>, message: ChatMessage) -> Result<(), Error>;
     /// ```
     ///
+    /// This is not called again for messages which have already been received in this race room, e.g. if the server replays them after a reconnect.
+    ///
     /// The default implementation calls [`command`](RaceHandler::command) if appropriate.
     async fn chat_message(&mut self, ctx: &RaceContext<S>, message: ChatMessage) -> Result<(), Error> {
         if !message.is_bot && !message.is_system.unwrap_or(false /* Python duck typing strikes again */) && message.message.starts_with('!') {
             let data = ctx.data().await;
-            let can_monitor = message.user.as_ref().map_or(false, |sender|
-                data.opened_by.as_ref().map_or(false, |creator| creator.id == sender.id) || data.monitors.iter().any(|monitor| monitor.id == sender.id)
+            let can_monitor = message.user.as_ref().is_some_and(|sender|
+                data.opened_by.as_ref().is_some_and(|creator| creator.id == sender.id) || data.monitors.iter().any(|monitor| monitor.id == sender.id)
             );
             if let Some(mut split) = shlex::split(&message.message[1..]) {
                 if !split.is_empty() {
@@ -310,7 +537,7 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
                         ctx,
                         split.remove(0),
                         split,
-                        message.user.as_ref().map_or(false, |user| user.can_moderate),
+                        message.user.as_ref().is_some_and(|user| user.can_moderate),
                         can_monitor,
                         &message,
                     ).await?;
@@ -320,6 +547,23 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
         Ok(())
     }
 
+    /// Called when a user uses an action button created using [`MessageAction::command`](crate::message::MessageAction::command).
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn message_action(&mut self, _ctx: &RaceContext<S>, _action: ActionInvocation, _msg: &ChatMessage) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The chat message sent by the button is passed here instead of to [`chat_message`](RaceHandler::chat_message).
+    ///
+    /// Buttons send an ordinary chat message of the form `!name arg…`, so a user who types such a message by hand with the right number of arguments is indistinguishable from one who clicked the button.
+    /// Such messages are also routed here, which means a registered action shadows a [`command`](RaceHandler::command) or [`command_router`](RaceHandler::command_router) entry of the same name when called with the same number of arguments.
+    /// No permission check is done before this is called, so implementations should verify that `msg.user` is allowed to perform the action.
+    ///
+    /// The default implementation does nothing.
+    async fn message_action(&mut self, _ctx: &RaceContext<S>, _action: ActionInvocation, _msg: &ChatMessage) -> Result<(), Error> { Ok(()) }
+
     /// Called when a `chat.delete` message is received.
     ///
     /// Equivalent to:
@@ -366,6 +610,43 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// The default implementation does nothing.
     async fn pong(&mut self, _ctx: &RaceContext<S>) -> Result<(), Error> { Ok(()) }
 
+    /// Called when the connection to the race room has been lost and the bot is about to reconnect according to its [`ReconnectPolicy`](crate::bot::ReconnectPolicy).
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn disconnected(&mut self, _ctx: &RaceContext<S>, _reason: &str) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The connection is already lost when this is called. Actions sent from this callback are queued and only sent after reconnecting, so the callback must not wait for them to be delivered, e.g. by using [`RaceContext::queue_message`] instead of [`RaceContext::send_message`].
+    ///
+    /// The default implementation does nothing.
+    async fn disconnected(&mut self, _ctx: &RaceContext<S>, _reason: &str) -> Result<(), Error> { Ok(()) }
+
+    /// Called when the connection to the race room has been reestablished after being lost.
+    ///
+    /// Before this is called, the race data is refetched and any changes made while the connection was down are passed to [`race_data`](RaceHandler::race_data) and [`race_event`](RaceHandler::race_event).
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn reconnected(&mut self, _ctx: &RaceContext<S>) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn reconnected(&mut self, _ctx: &RaceContext<S>) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a timer set using [`RaceContext::set_timer`] or [`RaceContext::set_interval`] is due.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn timer(&mut self, _ctx: &RaceContext<S>, _name: String) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn timer(&mut self, _ctx: &RaceContext<S>, _name: String) -> Result<(), Error> { Ok(()) }
+
     /// Called when a `race.data` message is received.
     ///
     /// Equivalent to:
@@ -379,6 +660,212 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// The default implementation does nothing.
     async fn race_data(&mut self, _ctx: &RaceContext<S>, _old_race_data: RaceData) -> Result<(), Error> { Ok(()) }
 
+    /// Called for each [`RaceEvent`] derived from a `race.data` message, after [`race_data`](RaceHandler::race_data).
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn race_event(&mut self, ctx: &RaceContext<S>, event: RaceEvent) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation calls the callback corresponding to the event, e.g. [`entrant_joined`](RaceHandler::entrant_joined) for [`RaceEvent::EntrantJoined`].
+    async fn race_event(&mut self, ctx: &RaceContext<S>, event: RaceEvent) -> Result<(), Error> {
+        match event {
+            RaceEvent::EntrantJoined(entrant) => self.entrant_joined(ctx, entrant).await,
+            RaceEvent::EntrantLeft(entrant) => self.entrant_left(ctx, entrant).await,
+            RaceEvent::EntrantReady(entrant) => self.entrant_ready(ctx, entrant).await,
+            RaceEvent::EntrantUnready(entrant) => self.entrant_unready(ctx, entrant).await,
+            RaceEvent::EntrantInvited(entrant) => self.entrant_invited(ctx, entrant).await,
+            RaceEvent::EntrantRequested(entrant) => self.entrant_requested(ctx, entrant).await,
+            RaceEvent::EntrantDeclined(entrant) => self.entrant_declined(ctx, entrant).await,
+            RaceEvent::EntrantFinished { entrant, place, finish_time } => self.entrant_finished(ctx, entrant, place, finish_time).await,
+            RaceEvent::EntrantForfeited(entrant) => self.entrant_forfeited(ctx, entrant).await,
+            RaceEvent::EntrantDisqualified(entrant) => self.entrant_disqualified(ctx, entrant).await,
+            RaceEvent::EntrantUndone(entrant) => self.entrant_undone(ctx, entrant).await,
+            RaceEvent::CommentAdded { entrant, comment } => self.comment_added(ctx, entrant, comment).await,
+            RaceEvent::StatusChanged { old, new } => self.status_changed(ctx, old, new).await,
+            RaceEvent::MonitorAdded(monitor) => self.monitor_added(ctx, monitor).await,
+            RaceEvent::MonitorRemoved(monitor) => self.monitor_removed(ctx, monitor).await,
+            RaceEvent::InfoChanged { old, new } => self.info_changed(ctx, old, new).await,
+        }
+    }
+
+    /// Called when a user joins the race, either directly or by accepting an invite or having their join request accepted.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_joined(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_joined(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant leaves the race or is removed from it, or when a pending invite or join request disappears.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_left(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_left(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant readies up.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_ready(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_ready(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant unreadies.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_unready(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_unready(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a user is invited to the race.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_invited(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_invited(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a user requests to join the race.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_requested(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_requested(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a user declines an invite to the race.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_declined(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_declined(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant finishes the race.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_finished(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant, _place: Option<u32>, _finish_time: Option<Duration>) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_finished(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant, _place: Option<u32>, _finish_time: Option<Duration>) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant forfeits the race.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_forfeited(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_forfeited(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant is disqualified.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_disqualified(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_disqualified(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant's finish, forfeit, or disqualification is undone.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn entrant_undone(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn entrant_undone(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant) -> Result<(), Error> { Ok(()) }
+
+    /// Called when an entrant adds or changes their comment.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn comment_added(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant, _comment: String) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn comment_added(&mut self, _ctx: &RaceContext<S>, _entrant: Entrant, _comment: String) -> Result<(), Error> { Ok(()) }
+
+    /// Called when the race's status changes.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn status_changed(&mut self, _ctx: &RaceContext<S>, _old: RaceStatusValue, _new: RaceStatusValue) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn status_changed(&mut self, _ctx: &RaceContext<S>, _old: RaceStatusValue, _new: RaceStatusValue) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a user is added as a race monitor.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn monitor_added(&mut self, _ctx: &RaceContext<S>, _monitor: UserData) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn monitor_added(&mut self, _ctx: &RaceContext<S>, _monitor: UserData) -> Result<(), Error> { Ok(()) }
+
+    /// Called when a user is removed as a race monitor.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn monitor_removed(&mut self, _ctx: &RaceContext<S>, _monitor: UserData) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn monitor_removed(&mut self, _ctx: &RaceContext<S>, _monitor: UserData) -> Result<(), Error> { Ok(()) }
+
+    /// Called when the race's info text changes.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn info_changed(&mut self, _ctx: &RaceContext<S>, _old: String, _new: String) -> Result<(), Error>;
+    /// ```
+    ///
+    /// The default implementation does nothing.
+    async fn info_changed(&mut self, _ctx: &RaceContext<S>, _old: String, _new: String) -> Result<(), Error> { Ok(()) }
+
     /// Called when a `race.renders` message is received.
     ///
     /// Equivalent to:
@@ -401,6 +888,22 @@ pub trait RaceHandler<S: Send + Sync + ?Sized + 'static>: Send + Sized + 'static
     /// The default implementation does nothing.
     async fn race_split(&mut self, _ctx: &RaceContext<S>) -> Result<(), Error> { Ok(()) }
 
+    /// Called when an error occurs while handling a race room, either in one of this handler's callbacks or in the bot itself.
+    ///
+    /// Equivalent to:
+    ///
+    /// ```ignore
+    /// async fn on_error(ctx: &RaceContext<S>, error: Error, context: ErrorContext) -> ErrorAction;
+    /// ```
+    ///
+    /// The returned [`ErrorAction`] determines how the bot proceeds with this race room.
+    ///
+    /// The default implementation logs the error (using [`tracing`](https://docs.rs/tracing) if the `tracing` feature is enabled, or to stderr otherwise) and returns [`ErrorAction::Abandon`].
+    async fn on_error(ctx: &RaceContext<S>, error: Error, context: ErrorContext) -> ErrorAction {
+        log!(error, "error in race handler for {} {context}: {error} ({error:?})", ctx.data().await.name);
+        ErrorAction::Abandon
+    }
+
     /// Called when a room handler task is created.
     ///
     /// Equivalent to:
@@ -422,3 +925,78 @@ pub enum RaceInfoPos {
     /// Add the new race info after the existing one, if any, like so: `old | new`
     Suffix,
 }
+
+/// Returned from [`RaceHandler::on_error`] to decide how to proceed after an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Ignore the error and keep handling messages from the race room.
+    ///
+    /// If the connection was closed, this reconnects first. If the error came from [`RaceHandler::new`] or [`RaceHandler::restore`], the constructor is called again after waiting according to the [`ReconnectPolicy`](crate::bot::ReconnectPolicy), with the delay growing each time it fails in a row.
+    Continue,
+    /// Reestablish the connection to the race room, then keep handling messages using the same handler.
+    Reconnect,
+    /// Discard the current handler and create a new one using [`RaceHandler::new`]. [`RaceHandler::end`] is not called on the old handler.
+    ///
+    /// If the error came from [`RaceHandler::new`] or [`RaceHandler::restore`], the new handler is created after a delay, as with [`ErrorAction::Continue`].
+    Restart,
+    /// Disconnect from the race room without calling [`RaceHandler::end`].
+    ///
+    /// The room will not be handled again for the remainder of this bot's lifetime.
+    Abandon,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_short() {
+        assert_eq!(split_message("hello", 10, "… "), ["hello"]);
+        assert_eq!(split_message("0123456789", 10, "… "), ["0123456789"]);
+    }
+
+    #[test]
+    fn split_at_newlines_and_spaces() {
+        assert_eq!(split_message("line one\nline two", 12, ""), ["line one", "line two"]);
+        // a line break is preferred over a later space
+        assert_eq!(split_message("ab cd\nef gh ij", 10, ""), ["ab cd", "ef gh ij"]);
+        assert_eq!(split_message("the quick brown fox", 10, ""), ["the quick", "brown fox"]);
+        // the character right after the limit can be the split point
+        assert_eq!(split_message("0123456789 abc", 10, ""), ["0123456789", "abc"]);
+        // whitespace at split points is removed
+        assert_eq!(split_message("aaaa\n\n\nbbbb", 5, ""), ["aaaa", "bbbb"]);
+        assert_eq!(split_message("aaaa    bbbb", 5, ""), ["aaaa", "bbbb"]);
+    }
+
+    #[test]
+    fn split_long_word() {
+        assert_eq!(split_message("aaaaaaaaaa", 4, ""), ["aaaa", "aaaa", "aa"]);
+        assert_eq!(split_message("hi aaaaaaaaaa", 4, ""), ["hi", "aaaa", "aaaa", "aa"]);
+    }
+
+    #[test]
+    fn split_multibyte() {
+        assert_eq!(split_message("ééééé", 3, ""), ["ééé", "éé"]);
+        assert_eq!(split_message("日本語 テキスト", 4, ""), ["日本語", "テキスト"]);
+        assert_eq!(split_message("🏁🏁🏁🏁🏁", 2, "…"), ["🏁🏁", "…🏁", "…🏁", "…🏁"]);
+    }
+
+    #[test]
+    fn split_with_continuation() {
+        let parts = split_message("aaaa bbbb cccc dddd", 9, "+ ");
+        assert_eq!(parts, ["aaaa bbbb", "+ cccc", "+ dddd"]);
+        assert!(parts.iter().all(|part| part.chars().count() <= 9));
+        let message = "word ".repeat(1_000);
+        let parts = split_message(&message, MAX_MESSAGE_LENGTH, "(cont.) ");
+        assert!(parts.iter().all(|part| part.chars().count() <= MAX_MESSAGE_LENGTH));
+        assert!(parts.iter().skip(1).all(|part| part.starts_with("(cont.) word")));
+        assert_eq!(parts.iter().map(|part| part.split_whitespace().filter(|word| *word == "word").count()).sum::<usize>(), 1_000);
+    }
+
+    #[test]
+    fn split_with_long_continuation() {
+        // each part still makes progress even though the continuation alone exceeds the limit
+        assert_eq!(split_message("abcd", 2, "..."), ["ab", "...c", "...d"]);
+        assert_eq!(split_message("abc", 0, "..."), ["a", "...b", "...c"]);
+    }
+}
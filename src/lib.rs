@@ -1,17 +1,19 @@
 //! Utilities for creating chat bots for [racetime.gg](https://racetime.gg/).
 //!
-//! The main entry point is [`Bot::run`]. You can also create new race rooms using [`StartRace::start`].
+//! The main entry point is [`Bot::run`], or [`Bot::into_stream`] to consume race room events as a stream instead of implementing [`RaceHandler`]. You can also create new race rooms using [`StartRace::start`].
 //!
 //! For documentation, see also <https://github.com/racetimeGG/racetime-app/wiki/Category-bots>.
 
 #![deny(rust_2018_idioms, unused, unused_crate_dependencies, unused_import_braces, unused_qualifications, warnings)]
 #![forbid(unsafe_code)]
+#![allow(clippy::large_enum_variant, clippy::result_large_err)]
 
 use {
     std::{
         borrow::Cow,
         collections::BTreeMap,
         num::NonZeroU16,
+        sync::Arc,
         time::Duration,
     },
     collect_mac::collect,
@@ -21,14 +23,37 @@ use {
     tokio::net::ToSocketAddrs,
     url::Url,
 };
+#[cfg(test)] use trybuild as _; // only used by the derive integration tests
 pub use crate::{
     bot::Bot,
     handler::RaceHandler,
 };
 
+/// Logs an event using [`tracing`] if the `tracing` feature is enabled, or prints it to stderr otherwise.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "tracing")] tracing::$level!($($arg)*);
+        #[cfg(not(feature = "tracing"))] eprintln!($($arg)*);
+    }};
+}
+
+pub mod announcer;
+pub mod auth;
 pub mod bot;
+pub mod command;
+pub mod event;
 pub mod handler;
+pub mod message;
+#[cfg(feature = "mock")] pub mod mock;
 pub mod model;
+pub mod moderation;
+pub mod persistence;
+pub mod queue;
+pub mod registry;
+pub mod stream;
+#[cfg(test)] mod test_util;
+mod timer;
+pub mod transport;
 
 const RACETIME_HOST: &str = "racetime.gg";
 
@@ -41,22 +66,32 @@ pub enum Error {
     #[error(transparent)] Json(#[from] serde_json::Error),
     #[error(transparent)] Task(#[from] tokio::task::JoinError),
     #[error(transparent)] UrlParse(#[from] url::ParseError),
+    #[error("failed to send queued action: {0}")]
+    Delivery(Arc<Error>),
+    #[error("direct messages can't have actions")]
+    DirectMessageActions,
     #[error("websocket connection closed by the server")]
     EndOfStream,
+    #[error("invalid bot configuration: {0}")]
+    InvalidConfig(&'static str),
     #[error("the startrace location did not match the input category")]
     LocationCategory,
     #[error("the startrace location header did not have the expected format")]
     LocationFormat,
     #[error("the startrace response did not include a location header")]
     MissingLocationHeader,
+    #[error("the race room's send queue was closed before the action could be sent")]
+    QueueClosed,
     #[error("HTTP error{}: {0}", if let Some(url) = .0.url() { format!(" at {url}") } else { String::default() })]
     Reqwest(#[from] reqwest::Error),
-    #[error("server errors:{}", .0.into_iter().map(|msg| format!("\n• {msg}")).format(""))]
+    #[error("server errors:{}", .0.iter().map(|msg| format!("\n• {msg}")).format(""))]
     Server(Vec<String>),
     #[error("WebSocket error: {0}")]
     Tungstenite(#[from] tokio_tungstenite::tungstenite::Error),
     #[error("expected text message from websocket, but received {0:?}")]
     UnexpectedMessageType(tokio_tungstenite::tungstenite::Message),
+    #[error("the bot is not handling a race room named {0}")]
+    UnknownRoom(String),
 }
 
 /// A convenience trait for converting results to use this crate's [`Error`] type.
@@ -75,6 +110,7 @@ impl<T, E: std::error::Error + Send + Sync + 'static> ResultExt for Result<T, E>
     }
 }
 
+#[derive(Clone)]
 pub struct HostInfo {
     hostname: Cow<'static, str>,
     port: NonZeroU16,
@@ -89,6 +125,19 @@ impl HostInfo {
         }
     }
 
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn port(&self) -> NonZeroU16 {
+        self.port
+    }
+
+    /// Whether connections to this host use TLS, i.e. `https` and `wss`.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
     fn http_protocol(&self) -> &'static str {
         match self.secure {
             true => "https",
@@ -132,10 +181,13 @@ fn uri(proto: &str, host: &str, port: NonZeroU16, url: &str) -> Result<Url, Erro
 }
 
 /// Get an OAuth2 token from the authentication server.
+///
+/// Consider using [`auth::ClientCredentials`] instead, which caches the token and refreshes it as needed.
 pub async fn authorize(client_id: &str, client_secret: &str, client: &reqwest::Client) -> Result<(String, Duration), Error> {
     authorize_with_host(&HostInfo::default(), client_id, client_secret, client).await
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(host = %host_info.hostname), err))]
 pub async fn authorize_with_host(host_info: &HostInfo, client_id: &str, client_secret: &str, client: &reqwest::Client) -> Result<(String, Duration), Error> {
     #[derive(Deserialize)]
     struct AuthResponse {
@@ -226,7 +278,7 @@ impl StartRace {
 
     /// Creates a race room with the specified configuration and returns its slug.
     ///
-    /// An access token can be obtained using [`authorize`].
+    /// An access token can be obtained using [`authorize`]. To get it from a [`TokenProvider`](auth::TokenProvider) instead, use [`StartRace::start_with_token_provider`].
     pub async fn start(&self, access_token: &str, client: &reqwest::Client, category: &str) -> Result<String, Error> {
         self.start_with_host(&HostInfo::default(), access_token, client, category).await
     }
@@ -246,11 +298,20 @@ impl StartRace {
         Ok(slug.to_owned())
     }
 
+    /// Creates a race room with the specified configuration and returns its slug, using an access token from the given [`TokenProvider`](auth::TokenProvider).
+    pub async fn start_with_token_provider(&self, token_provider: &dyn auth::TokenProvider, client: &reqwest::Client, category: &str) -> Result<String, Error> {
+        self.start_with_token_provider_and_host(&HostInfo::default(), token_provider, client, category).await
+    }
+
+    pub async fn start_with_token_provider_and_host(&self, host_info: &HostInfo, token_provider: &dyn auth::TokenProvider, client: &reqwest::Client, category: &str) -> Result<String, Error> {
+        self.start_with_host(host_info, &token_provider.access_token().await?, client, category).await
+    }
+
     /// Edits the given race room.
     ///
     /// Due to a limitation of the racetime.gg API, all fields including ones that should remain the same must be specified.
     ///
-    /// An access token can be obtained using [`authorize`].
+    /// An access token can be obtained using [`authorize`]. To get it from a [`TokenProvider`](auth::TokenProvider) instead, use [`StartRace::edit_with_token_provider`].
     pub async fn edit(&self, access_token: &str, client: &reqwest::Client, category: &str, race_slug: &str) -> Result<(), Error> {
         self.edit_with_host(&HostInfo::default(), access_token, client, category, race_slug).await
     }
@@ -263,4 +324,13 @@ impl StartRace {
             .error_for_status()?;
         Ok(())
     }
+
+    /// Edits the given race room, using an access token from the given [`TokenProvider`](auth::TokenProvider). See [`StartRace::edit`].
+    pub async fn edit_with_token_provider(&self, token_provider: &dyn auth::TokenProvider, client: &reqwest::Client, category: &str, race_slug: &str) -> Result<(), Error> {
+        self.edit_with_token_provider_and_host(&HostInfo::default(), token_provider, client, category, race_slug).await
+    }
+
+    pub async fn edit_with_token_provider_and_host(&self, host_info: &HostInfo, token_provider: &dyn auth::TokenProvider, client: &reqwest::Client, category: &str, race_slug: &str) -> Result<(), Error> {
+        self.edit_with_host(host_info, &token_provider.access_token().await?, client, category, race_slug).await
+    }
 }
@@ -0,0 +1,352 @@
+//! Chat messages with options beyond plain text, sent using [`RaceContext::message`].
+
+use {
+    std::collections::HashMap,
+    serde::Serialize,
+    serde_json::{
+        Map,
+        Value as Json,
+        json,
+    },
+    url::Url,
+    uuid::Uuid,
+    crate::{
+        Error,
+        handler::RaceContext,
+        model::ChatMessage,
+        queue::{
+            Delivery,
+            Priority,
+        },
+    },
+};
+
+/// A chat message being built. Created using [`RaceContext::message`].
+#[must_use = "a message is only sent when calling send or queue"]
+pub struct MessageBuilder<'a, S: Send + Sync + ?Sized + 'static> {
+    ctx: &'a RaceContext<S>,
+    message: String,
+    pinned: bool,
+    direct_to: Option<String>,
+    actions: Vec<MessageAction>,
+}
+
+impl<'a, S: Send + Sync + ?Sized + 'static> MessageBuilder<'a, S> {
+    pub(crate) fn new(ctx: &'a RaceContext<S>, message: String) -> Self {
+        Self {
+            pinned: false,
+            direct_to: None,
+            actions: Vec::default(),
+            ctx, message,
+        }
+    }
+
+    /// Pins the message to the top of the chat.
+    pub fn pinned(mut self) -> Self {
+        self.pinned = true;
+        self
+    }
+
+    /// Sends the message only to the user with the given [hashid](crate::model::UserData::id). Direct messages can't have actions.
+    pub fn direct_to(mut self, user_id: impl Into<String>) -> Self {
+        self.direct_to = Some(user_id.into());
+        self
+    }
+
+    /// Adds an action button to the message.
+    pub fn action(mut self, action: MessageAction) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    /// Sends the message and waits until it has been sent.
+    pub async fn send(self) -> Result<(), Error> {
+        self.queue()?.await
+    }
+
+    /// Adds the message to the race room's send queue without waiting for it to be sent.
+    ///
+    /// Returns an error without sending anything if the message is a direct message with actions.
+    pub fn queue(self) -> Result<Delivery, Error> {
+        if self.direct_to.is_some() && !self.actions.is_empty() {
+            return Err(Error::DirectMessageActions)
+        }
+        let mut actions = Map::default();
+        for action in self.actions {
+            if let MessageActionKind::Command { ref name, ref survey, .. } = action.kind {
+                self.ctx.actions.lock().expect("message action registry lock poisoned").insert(name.to_ascii_lowercase(), survey.iter().map(|field| field.name.clone()).collect());
+            }
+            actions.insert(action.label.clone(), action.data());
+        }
+        Ok(self.ctx.queue_raw(json!({
+            "action": "message",
+            "data": MessageData {
+                message: self.message,
+                pinned: self.pinned,
+                actions: (!actions.is_empty()).then_some(actions),
+                direct_to: self.direct_to,
+                guid: Uuid::new_v4(),
+            },
+        }), Priority::Normal))
+    }
+}
+
+#[derive(Serialize)]
+struct MessageData {
+    message: String,
+    pinned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<Map<String, Json>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direct_to: Option<String>,
+    guid: Uuid,
+}
+
+enum MessageActionKind {
+    Command {
+        name: String,
+        survey: Vec<SurveyField>,
+        submit: Option<String>,
+    },
+    Url(Url),
+}
+
+/// An action button attached to a chat message.
+pub struct MessageAction {
+    label: String,
+    help: Option<String>,
+    kind: MessageActionKind,
+}
+
+impl MessageAction {
+    /// Creates a button which, when clicked, makes the user send the command `!name` followed by the values of the button's [`field`](MessageAction::field)s.
+    ///
+    /// Invocations of the command are passed to [`RaceHandler::message_action`](crate::RaceHandler::message_action) instead of being handled as regular chat messages. If a value contains a double quote, the invocation can't be parsed and is handled as a regular chat message instead.
+    ///
+    /// Avoid reusing the name of a chat command: hand-typed invocations with a matching number of arguments are also passed to `message_action`, without a permission check.
+    pub fn command(label: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            help: None,
+            kind: MessageActionKind::Command {
+                name: name.into(),
+                survey: Vec::default(),
+                submit: None,
+            },
+        }
+    }
+
+    /// Creates a button which opens the given URL.
+    pub fn url(label: impl Into<String>, url: Url) -> Self {
+        Self {
+            label: label.into(),
+            help: None,
+            kind: MessageActionKind::Url(url),
+        }
+    }
+
+    /// Sets the tooltip of the button.
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Adds a field the user fills in after clicking the button. Has no effect on URL buttons.
+    pub fn field(mut self, field: SurveyField) -> Self {
+        if let MessageActionKind::Command { ref mut survey, .. } = self.kind {
+            survey.push(field);
+        }
+        self
+    }
+
+    /// Sets the label of the button that submits the fields. Has no effect on URL buttons.
+    pub fn submit(mut self, label: impl Into<String>) -> Self {
+        if let MessageActionKind::Command { ref mut submit, .. } = self.kind {
+            *submit = Some(label.into());
+        }
+        self
+    }
+
+    fn data(&self) -> Json {
+        let mut data = Map::default();
+        match self.kind {
+            MessageActionKind::Command { ref name, ref survey, ref submit } => {
+                let mut message = format!("!{name}");
+                for field in survey {
+                    message.push_str(&format!(" \"${{{}}}\"", field.name));
+                }
+                data.insert("message".to_owned(), Json::String(message));
+                if !survey.is_empty() {
+                    data.insert("survey".to_owned(), json!(survey));
+                }
+                if let Some(ref submit) = submit {
+                    data.insert("submit".to_owned(), Json::String(submit.clone()));
+                }
+            }
+            MessageActionKind::Url(ref url) => {
+                data.insert("url".to_owned(), Json::String(url.to_string()));
+            }
+        }
+        if let Some(ref help) = self.help {
+            data.insert("help".to_owned(), Json::String(help.clone()));
+        }
+        Json::Object(data)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SurveyFieldKind {
+    Input,
+    Bool,
+    Radio,
+    Select,
+}
+
+/// A field of a [`MessageAction`] which the user fills in after clicking the button.
+#[derive(Serialize)]
+pub struct SurveyField {
+    name: String,
+    label: String,
+    #[serde(rename = "type")]
+    kind: SurveyFieldKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    placeholder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    help: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Map<String, Json>>,
+}
+
+impl SurveyField {
+    fn new(name: impl Into<String>, label: impl Into<String>, kind: SurveyFieldKind, options: Option<Map<String, Json>>) -> Self {
+        Self {
+            name: name.into(),
+            label: label.into(),
+            default: None,
+            placeholder: None,
+            help: None,
+            kind, options,
+        }
+    }
+
+    /// A text input.
+    pub fn input(name: impl Into<String>, label: impl Into<String>) -> Self {
+        Self::new(name, label, SurveyFieldKind::Input, None)
+    }
+
+    /// A checkbox.
+    pub fn bool(name: impl Into<String>, label: impl Into<String>) -> Self {
+        Self::new(name, label, SurveyFieldKind::Bool, None)
+    }
+
+    /// Radio buttons with the given values and their labels.
+    pub fn radio(name: impl Into<String>, label: impl Into<String>, options: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>) -> Self {
+        Self::new(name, label, SurveyFieldKind::Radio, Some(options.into_iter().map(|(value, label)| (value.into(), Json::String(label.into()))).collect()))
+    }
+
+    /// A dropdown with the given values and their labels.
+    pub fn select(name: impl Into<String>, label: impl Into<String>, options: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>) -> Self {
+        Self::new(name, label, SurveyFieldKind::Select, Some(options.into_iter().map(|(value, label)| (value.into(), Json::String(label.into()))).collect()))
+    }
+
+    /// Sets the initial value of the field.
+    pub fn default(mut self, default: impl Into<String>) -> Self {
+        self.default = Some(default.into());
+        self
+    }
+
+    /// Sets the placeholder text of an input field.
+    pub fn placeholder(mut self, placeholder: impl Into<String>) -> Self {
+        self.placeholder = Some(placeholder.into());
+        self
+    }
+
+    /// Sets the help text shown next to the field.
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+/// A use of an action button created using [`MessageAction::command`]. Passed to [`RaceHandler::message_action`](crate::RaceHandler::message_action).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionInvocation {
+    /// The `name` passed to [`MessageAction::command`], lowercased.
+    pub name: String,
+    /// The values of the action's fields, by field name.
+    pub values: HashMap<String, String>,
+}
+
+impl ActionInvocation {
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.values.get(field).map(String::as_str)
+    }
+
+    /// Parses a chat message as an invocation of one of the registered actions.
+    pub(crate) fn parse(actions: &HashMap<String, Vec<String>>, message: &ChatMessage) -> Option<Self> {
+        if message.is_bot || message.is_system.unwrap_or(false) { return None }
+        let mut split = shlex::split(message.message.strip_prefix('!')?)?.into_iter();
+        let name = split.next()?.to_ascii_lowercase();
+        let fields = actions.get(&name)?;
+        let values = split.collect::<Vec<_>>();
+        if values.len() != fields.len() { return None }
+        Some(Self {
+            values: fields.iter().cloned().zip(values).collect(),
+            name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            model::RaceStatusValue,
+            test_util,
+        },
+        super::*,
+    };
+
+    #[test]
+    fn omits_unset_options() -> Result<(), Error> {
+        let ctx = test_util::context(test_util::race(RaceStatusValue::Open));
+        drop(ctx.message("hello").queue()?);
+        drop(ctx.message("psst").direct_to("user-alice").queue()?);
+        let pending = ctx.queue.pending();
+        let data = pending[0]["data"].as_object().expect("message data is an object");
+        assert_eq!(data["message"], "hello");
+        assert!(!data.contains_key("actions"));
+        assert!(!data.contains_key("direct_to"));
+        let data = pending[1]["data"].as_object().expect("message data is an object");
+        assert_eq!(data["direct_to"], "user-alice");
+        assert!(!data.contains_key("actions"));
+        Ok(())
+    }
+
+    #[test]
+    fn actions() -> Result<(), Error> {
+        let ctx = test_util::context(test_util::race(RaceStatusValue::Open));
+        drop(ctx.message("pick a seed")
+            .action(MessageAction::command("Roll", "Seed").field(SurveyField::input("preset", "Preset")).submit("Roll!"))
+            .action(MessageAction::url("Help", "https://example.com/help".parse().expect("valid URL")).help("Opens the help page"))
+            .queue()?);
+        assert_eq!(ctx.queue.pending()[0]["data"]["actions"], json!({
+            "Roll": {
+                "message": "!Seed \"${preset}\"",
+                "survey": [{"name": "preset", "label": "Preset", "type": "input"}],
+                "submit": "Roll!",
+            },
+            "Help": {
+                "url": "https://example.com/help",
+                "help": "Opens the help page",
+            },
+        }));
+        assert_eq!(*ctx.actions.lock().expect("message action registry lock poisoned"), HashMap::from([("seed".to_owned(), vec!["preset".to_owned()])]));
+        assert!(matches!(ctx.message("psst").direct_to("user-alice").action(MessageAction::command("Roll", "seed")).queue(), Err(Error::DirectMessageActions)));
+        assert_eq!(ctx.queue.pending().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,748 @@
+//! An in-process stand-in for racetime.gg that can be used to test bots without connecting to the live site.
+//!
+//! Requires the `mock` feature. Start a [`MockServer`], open some race rooms on it, and point a [`Bot`](crate::Bot) at it using [`MockServer::host_info`]:
+//!
+//! ```ignore
+//! let server = MockServer::start("ootr").await?;
+//! server.open_race("wonderful-link-1234").await;
+//! let bot = Bot::new_with_host(server.host_info(), "ootr", "client-id", "client-secret", state).await?;
+//! tokio::spawn(bot.run::<Handler>());
+//! server.chat("wonderful-link-1234", &mock_user("Alice"), "!seed").await;
+//! let action = server.next_action().await;
+//! assert_eq!(action.action, "message");
+//! ```
+
+use {
+    std::{
+        collections::{
+            BTreeMap,
+            HashMap,
+            HashSet,
+            VecDeque,
+        },
+        net::SocketAddr,
+        num::NonZeroU16,
+        sync::Arc,
+    },
+    chrono::{
+        Duration,
+        prelude::*,
+    },
+    futures::{
+        SinkExt as _,
+        stream::StreamExt as _,
+    },
+    serde_json::{
+        Value as Json,
+        json,
+    },
+    tokio::{
+        io::{
+            AsyncReadExt as _,
+            AsyncWriteExt as _,
+        },
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+        sync::{
+            Mutex,
+            mpsc,
+        },
+        task::JoinHandle,
+    },
+    tokio_tungstenite::{
+        WebSocketStream,
+        tungstenite::{
+            self,
+            handshake::derive_accept_key,
+            protocol::{
+                CloseFrame,
+                Role,
+            },
+        },
+    },
+    crate::{
+        Error,
+        HostInfo,
+        ResultExt as _,
+        model::*,
+    },
+};
+
+const BOT_NAME: &str = "Mock bot";
+const GOAL: &str = "Beat the game";
+
+/// An action sent by a bot over a race room's websocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotAction {
+    /// The slug of the race room, without the category.
+    pub race: String,
+    /// The `action` field of the message, e.g. `"message"` or `"setinfo"`.
+    pub action: String,
+    /// The `data` field of the message, or [`Json::Null`] if there was none.
+    pub data: Json,
+}
+
+enum Outgoing {
+    Text(String),
+    Close(u16, String),
+    Drop,
+}
+
+struct Room {
+    data: RaceData,
+    chat: Vec<ChatMessage>,
+    connections: Vec<mpsc::UnboundedSender<Outgoing>>,
+}
+
+impl Room {
+    fn send(&mut self, outgoing: impl Fn() -> Outgoing) {
+        self.connections.retain(|tx| tx.send(outgoing()).is_ok());
+    }
+
+    fn broadcast(&mut self, message: &Message) {
+        let text = serde_json::to_string(message).expect("failed to serialize message");
+        self.send(|| Outgoing::Text(text.clone()));
+    }
+
+    fn push(&mut self, message: &Message) {
+        match message {
+            Message::ChatMessage { message } => self.chat.push(message.clone()),
+            Message::RaceData { race } => self.data = race.clone(),
+            _ => {}
+        }
+        self.broadcast(message);
+    }
+
+    fn race_data_changed(&mut self) {
+        self.data.version += 1;
+        update_info(&mut self.data);
+        let message = Message::RaceData { race: self.data.clone() };
+        self.broadcast(&message);
+    }
+}
+
+struct State {
+    category: String,
+    races: BTreeMap<String, Room>,
+    tokens: HashSet<String>,
+    token_lifetime: std::time::Duration,
+    next_id: u64,
+    failures: VecDeque<u16>,
+    ignore_pings: bool,
+    actions: Vec<BotAction>,
+    actions_tx: mpsc::UnboundedSender<BotAction>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn room(&mut self, slug: &str) -> &mut Room {
+        self.races.get_mut(slug).unwrap_or_else(|| panic!("no race room with slug {slug:?} on mock server"))
+    }
+
+    fn category_data(&self) -> CategoryData {
+        CategoryData {
+            name: self.category.clone(),
+            short_name: self.category.clone(),
+            slug: self.category.clone(),
+            url: format!("/{}", self.category),
+            data_url: format!("/{}/data", self.category),
+            image: None,
+            info: None,
+            streaming_required: false,
+            owners: Vec::default(),
+            moderators: Vec::default(),
+            goals: vec![GOAL.to_owned()],
+            current_races: self.races.values()
+                .map(|room| &room.data)
+                .filter(|race| !race.unlisted && !matches!(race.status.value, RaceStatusValue::Finished | RaceStatusValue::Cancelled))
+                .map(|race| RaceSummary {
+                    name: race.name.clone(),
+                    category: Some(race.category.clone()),
+                    status: race.status.clone(),
+                    url: race.url.clone(),
+                    data_url: race.data_url.clone(),
+                    goal: race.goal.clone(),
+                    info: race.info.clone(),
+                    entrants_count: race.entrants_count,
+                    entrants_count_finished: race.entrants_count_finished,
+                    entrants_count_inactive: race.entrants_count_inactive,
+                    opened_at: race.opened_at,
+                    started_at: race.started_at,
+                    time_limit: race.time_limit,
+                })
+                .collect(),
+            emotes: BTreeMap::default(),
+        }
+    }
+
+    fn new_race(&self, slug: &str) -> RaceData {
+        let category = &self.category;
+        RaceData {
+            version: 1,
+            name: format!("{category}/{slug}"),
+            slug: slug.to_owned(),
+            category: CategorySummary {
+                name: category.clone(),
+                short_name: category.clone(),
+                slug: category.clone(),
+                url: format!("/{category}"),
+                data_url: format!("/{category}/data"),
+            },
+            status: mock_race_status(RaceStatusValue::Open),
+            url: format!("/{category}/{slug}"),
+            data_url: format!("/{category}/{slug}/data"),
+            websocket_url: format!("/ws/race/{slug}"),
+            websocket_bot_url: format!("/ws/o/bot/{slug}"),
+            websocket_oauth_url: format!("/ws/o/race/{slug}"),
+            goal: Goal {
+                name: GOAL.to_owned(),
+                custom: false,
+            },
+            info: String::default(),
+            info_bot: None,
+            info_user: None,
+            entrants_count: 0,
+            entrants_count_finished: 0,
+            entrants_count_inactive: 0,
+            entrants: Vec::default(),
+            opened_at: Utc::now(),
+            start_delay: Duration::seconds(15),
+            started_at: None,
+            ended_at: None,
+            cancelled_at: None,
+            unlisted: false,
+            time_limit: Duration::hours(24),
+            streaming_required: false,
+            auto_start: true,
+            opened_by: None,
+            monitors: Vec::default(),
+            recordable: true,
+            recorded: false,
+            recorded_by: None,
+            allow_comments: true,
+            hide_comments: false,
+            allow_midrace_chat: true,
+            allow_non_entrant_chat: true,
+            chat_message_delay: Duration::zero(),
+        }
+    }
+}
+
+/// A local HTTP and WebSocket server that emulates the parts of racetime.gg used by this crate.
+///
+/// The following endpoints are supported:
+///
+/// * `POST /o/token` issues a new access token for any client credentials.
+/// * `GET /{category}/data` lists all rooms that are neither unlisted nor finished/cancelled.
+/// * `GET /{category}/{race}/data`
+/// * `POST /o/{category}/startrace` opens a new room with the given settings.
+/// * `POST /o/{category}/{race}/edit`
+/// * The bot websocket at each room's `websocket_bot_url`. The `ping`, `message`, `setinfo`, `delete_message`, `purge_user`, and `override_stream` actions are emulated, all other actions are only recorded.
+///
+/// The server shuts down when this value is dropped. Connections that are already open are not closed.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    actions_rx: Mutex<mpsc::UnboundedReceiver<BotAction>>,
+    listener: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a mock server for the given category on a random local port.
+    pub async fn start(category: &str) -> Result<Self, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let (actions_tx, actions_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(Mutex::new(State {
+            category: category.to_owned(),
+            races: BTreeMap::default(),
+            tokens: HashSet::default(),
+            token_lifetime: std::time::Duration::from_secs(36000),
+            next_id: 0,
+            failures: VecDeque::default(),
+            ignore_pings: false,
+            actions: Vec::default(),
+            actions_tx,
+        }));
+        let state_clone = Arc::clone(&state);
+        let listener = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(Arc::clone(&state_clone), stream));
+            }
+        });
+        Ok(Self {
+            actions_rx: Mutex::new(actions_rx),
+            addr, state, listener,
+        })
+    }
+
+    /// Returns the host info to pass to [`Bot::new_with_host`](crate::Bot::new_with_host) and the other `_with_host` functions.
+    pub fn host_info(&self) -> HostInfo {
+        HostInfo::new(self.addr.ip().to_string(), NonZeroU16::new(self.addr.port()).expect("bound to port 0"), false)
+    }
+
+    /// Sets the lifetime reported for access tokens issued from now on. Defaults to 10 hours.
+    pub async fn set_token_lifetime(&self, lifetime: std::time::Duration) {
+        self.state.lock().await.token_lifetime = lifetime;
+    }
+
+    /// Makes the bot websocket stop (or resume) answering `ping` actions with a `pong`, simulating a connection that has silently died. The actions are still recorded.
+    pub async fn ignore_pings(&self, ignore: bool) {
+        self.state.lock().await.ignore_pings = ignore;
+    }
+
+    /// Makes the next `count` HTTP requests, including websocket handshakes, fail with the given status code.
+    pub async fn fail_requests(&self, status: u16, count: usize) {
+        self.state.lock().await.failures.extend(std::iter::repeat_n(status, count));
+    }
+
+    /// Opens a race room with default settings and returns its data. The room can be modified using [`MockServer::update_race`].
+    pub async fn open_race(&self, slug: &str) -> RaceData {
+        let mut state = self.state.lock().await;
+        let data = state.new_race(slug);
+        state.races.insert(slug.to_owned(), Room {
+            data: data.clone(),
+            chat: Vec::default(),
+            connections: Vec::default(),
+        });
+        data
+    }
+
+    /// Returns the current data of the given race room, if it exists.
+    pub async fn race(&self, slug: &str) -> Option<RaceData> {
+        self.state.lock().await.races.get(slug).map(|room| room.data.clone())
+    }
+
+    /// Modifies the given race room's data, increments its version, and sends a `race.data` message to all connected bots.
+    ///
+    /// # Panics
+    ///
+    /// If there is no race room with the given slug.
+    pub async fn update_race(&self, slug: &str, f: impl FnOnce(&mut RaceData)) {
+        let mut state = self.state.lock().await;
+        let room = state.room(slug);
+        f(&mut room.data);
+        room.race_data_changed();
+    }
+
+    /// Sends a message to all bots connected to the given race room.
+    ///
+    /// `chat.message` and `race.data` messages are also applied to the room's state.
+    ///
+    /// # Panics
+    ///
+    /// If there is no race room with the given slug.
+    pub async fn push(&self, slug: &str, message: &Message) {
+        self.state.lock().await.room(slug).push(message);
+    }
+
+    /// Posts a chat message from the given user to the given race room and returns it.
+    ///
+    /// # Panics
+    ///
+    /// If there is no race room with the given slug.
+    pub async fn chat(&self, slug: &str, user: &UserData, message: &str) -> ChatMessage {
+        let mut state = self.state.lock().await;
+        let message = ChatMessage {
+            id: format!("message-{}", state.next_id()),
+            user: Some(user.clone()),
+            bot: None,
+            posted_at: Utc::now(),
+            message: message.to_owned(),
+            message_plain: message.to_owned(),
+            highlight: false,
+            is_bot: false,
+            is_system: Some(false),
+        };
+        state.room(slug).push(&Message::ChatMessage { message: message.clone() });
+        message
+    }
+
+    /// Closes all bot connections to the given race room without a closing handshake, as if the network connection was lost.
+    pub async fn disconnect(&self, slug: &str) {
+        if let Some(room) = self.state.lock().await.races.get_mut(slug) {
+            room.send(|| Outgoing::Drop);
+        }
+    }
+
+    /// Sends a close frame with the given code and reason to all bot connections to the given race room.
+    pub async fn close(&self, slug: &str, code: u16, reason: &str) {
+        if let Some(room) = self.state.lock().await.races.get_mut(slug) {
+            room.send(|| Outgoing::Close(code, reason.to_owned()));
+        }
+    }
+
+    /// Returns the number of open bot connections to the given race room.
+    pub async fn connection_count(&self, slug: &str) -> usize {
+        self.state.lock().await.races.get_mut(slug).map_or(0, |room| {
+            room.connections.retain(|tx| !tx.is_closed());
+            room.connections.len()
+        })
+    }
+
+    /// Returns all actions received so far, in the order they were received.
+    pub async fn actions(&self) -> Vec<BotAction> {
+        self.state.lock().await.actions.clone()
+    }
+
+    /// Waits for the next action from any bot connection.
+    ///
+    /// Each action is returned by this method exactly once, independently of [`MockServer::actions`].
+    pub async fn next_action(&self) -> BotAction {
+        self.actions_rx.lock().await.recv().await.expect("mock server state holds an action sender")
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// Returns user data for a user with the given name and an ID derived from it.
+pub fn mock_user(name: &str) -> UserData {
+    UserData {
+        id: format!("user-{}", name.to_lowercase()),
+        full_name: format!("{name}#0000"),
+        name: name.to_owned(),
+        discriminator: Some("0000".to_owned()),
+        url: format!("/user/user-{}", name.to_lowercase()),
+        avatar: None,
+        pronouns: None,
+        flair: String::default(),
+        twitch_name: None,
+        twitch_display_name: None,
+        twitch_channel: None,
+        can_moderate: false,
+    }
+}
+
+/// Returns entrant data for the given user with the given status.
+pub fn mock_entrant(user: UserData, status: EntrantStatusValue) -> Entrant {
+    Entrant {
+        status: EntrantStatus {
+            value: status,
+            verbose_value: format!("{status:?}"),
+            help_text: String::default(),
+        },
+        finish_time: None,
+        finished_at: None,
+        place: None,
+        place_ordinal: None,
+        score: None,
+        score_change: None,
+        comment: None,
+        has_comment: false,
+        stream_live: false,
+        stream_override: false,
+        team: None,
+        user,
+    }
+}
+
+/// Returns race status data for the given status value.
+pub fn mock_race_status(value: RaceStatusValue) -> RaceStatus {
+    RaceStatus {
+        verbose_value: match value {
+            RaceStatusValue::Open => "Open",
+            RaceStatusValue::Invitational => "Invitational",
+            RaceStatusValue::Pending => "Starting",
+            RaceStatusValue::InProgress => "In progress",
+            RaceStatusValue::Finished => "Finished",
+            RaceStatusValue::Cancelled => "Cancelled",
+        }.to_owned(),
+        help_text: String::default(),
+        value,
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn form(&self) -> HashMap<String, String> {
+        url::form_urlencoded::parse(&self.body).into_owned().collect()
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Request>, Error> {
+    let mut buf = Vec::default();
+    let (mut request, body_start) = loop {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 { return Ok(None) }
+        buf.extend_from_slice(&chunk[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        if let httparse::Status::Complete(len) = request.parse(&buf).to_racetime()? {
+            break (Request {
+                method: request.method.unwrap_or_default().to_owned(),
+                path: request.path.unwrap_or_default().split('?').next().unwrap_or_default().to_owned(),
+                headers: request.headers.iter().map(|header| (header.name.to_ascii_lowercase(), String::from_utf8_lossy(header.value).into_owned())).collect(),
+                body: Vec::default(),
+            }, len)
+        }
+    };
+    request.body = buf.split_off(body_start);
+    let content_length = request.headers.get("content-length").and_then(|len| len.parse().ok()).unwrap_or(0);
+    while request.body.len() < content_length {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 { break }
+        request.body.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(request))
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, extra_headers: &[(&str, &str)], body: &Json) -> Result<(), Error> {
+    let body = serde_json::to_string(body)?;
+    let reason = http::StatusCode::from_u16(status).ok().and_then(|status| status.canonical_reason()).unwrap_or("Unknown");
+    let mut response = format!("HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n", body.len());
+    for (name, value) in extra_headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn update_info(race: &mut RaceData) {
+    race.info = race.info_user.iter().chain(&race.info_bot).map(String::as_str).collect::<Vec<_>>().join("\n");
+}
+
+fn errors(errors: &[&str]) -> Json {
+    json!({"errors": errors})
+}
+
+fn apply_form(race: &mut RaceData, form: HashMap<String, String>) -> Result<(), String> {
+    fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("invalid value for {key}: {value:?}"))
+    }
+
+    for (key, value) in form {
+        match &*key {
+            "goal" => race.goal = Goal { name: value, custom: false },
+            "custom_goal" => race.goal = Goal { name: value, custom: true },
+            "invitational" => if matches!(race.status.value, RaceStatusValue::Open | RaceStatusValue::Invitational) {
+                race.status = mock_race_status(if parse(&key, &value)? { RaceStatusValue::Invitational } else { RaceStatusValue::Open });
+            },
+            "unlisted" => race.unlisted = parse(&key, &value)?,
+            "info_user" => race.info_user = Some(value).filter(|info| !info.is_empty()),
+            "info_bot" => race.info_bot = Some(value).filter(|info| !info.is_empty()),
+            "start_delay" => race.start_delay = Duration::seconds(parse(&key, &value)?),
+            "time_limit" => race.time_limit = Duration::hours(parse(&key, &value)?),
+            "streaming_required" => race.streaming_required = parse(&key, &value)?,
+            "auto_start" => race.auto_start = parse(&key, &value)?,
+            "allow_comments" => race.allow_comments = parse(&key, &value)?,
+            "hide_comments" => race.hide_comments = parse(&key, &value)?,
+            "allow_midrace_chat" => race.allow_midrace_chat = parse(&key, &value)?,
+            "allow_non_entrant_chat" => race.allow_non_entrant_chat = parse(&key, &value)?,
+            "chat_message_delay" => race.chat_message_delay = Duration::seconds(parse(&key, &value)?),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn serve(state: Arc<Mutex<State>>, mut stream: TcpStream) -> Result<(), Error> {
+    let Some(request) = read_request(&mut stream).await? else { return Ok(()) };
+    let mut lock = state.lock().await;
+    if let Some(status) = lock.failures.pop_front() {
+        drop(lock);
+        return respond(&mut stream, status, &[], &errors(&["simulated server error"])).await
+    }
+    let authorized = request.headers.get("authorization").and_then(|auth| auth.strip_prefix("Bearer ")).is_some_and(|token| lock.tokens.contains(token));
+    let segments = request.path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (&*request.method, &*segments) {
+        ("POST", ["o", "token"]) => {
+            let access_token = format!("mock-token-{}", lock.next_id());
+            lock.tokens.insert(access_token.clone());
+            let expires_in = lock.token_lifetime.as_secs();
+            drop(lock);
+            respond(&mut stream, 200, &[], &json!({
+                "access_token": access_token,
+                "expires_in": expires_in,
+                "token_type": "Bearer",
+                "scope": "read write",
+            })).await
+        }
+        ("GET", [category, "data"]) if *category == lock.category => {
+            let data = serde_json::to_value(lock.category_data())?;
+            drop(lock);
+            respond(&mut stream, 200, &[], &data).await
+        }
+        ("GET", [category, race, "data"]) if *category == lock.category && lock.races.contains_key(*race) => {
+            let data = serde_json::to_value(&lock.races[*race].data)?;
+            drop(lock);
+            respond(&mut stream, 200, &[], &data).await
+        }
+        ("POST", ["o", _, "startrace"] | ["o", _, _, "edit"]) if !authorized => {
+            drop(lock);
+            respond(&mut stream, 401, &[], &errors(&["invalid access token"])).await
+        }
+        ("POST", ["o", category, "startrace"]) if *category == lock.category => {
+            let slug = format!("mock-race-{}", lock.next_id());
+            let mut data = lock.new_race(&slug);
+            if let Err(e) = apply_form(&mut data, request.form()) {
+                drop(lock);
+                return respond(&mut stream, 400, &[], &errors(&[&e])).await
+            }
+            update_info(&mut data);
+            let location = data.url.clone();
+            lock.races.insert(slug, Room {
+                chat: Vec::default(),
+                connections: Vec::default(),
+                data,
+            });
+            drop(lock);
+            respond(&mut stream, 201, &[("Location", &location)], &json!({})).await
+        }
+        ("POST", ["o", category, race, "edit"]) if *category == lock.category && lock.races.contains_key(*race) => {
+            let room = lock.room(race);
+            if let Err(e) = apply_form(&mut room.data, request.form()) {
+                drop(lock);
+                return respond(&mut stream, 400, &[], &errors(&[&e])).await
+            }
+            room.race_data_changed();
+            drop(lock);
+            respond(&mut stream, 200, &[], &json!({})).await
+        }
+        ("GET", ["ws", "o", "bot", race]) if lock.races.contains_key(*race) => {
+            if !authorized {
+                drop(lock);
+                return respond(&mut stream, 401, &[], &errors(&["invalid access token"])).await
+            }
+            let Some(key) = request.headers.get("sec-websocket-key") else {
+                drop(lock);
+                return respond(&mut stream, 400, &[], &errors(&["expected a websocket handshake"])).await
+            };
+            let race = race.to_string();
+            let (tx, rx) = mpsc::unbounded_channel();
+            let room = lock.room(&race);
+            let history = serde_json::to_string(&Message::ChatHistory { messages: room.chat.clone() })?;
+            let _ = tx.send(Outgoing::Text(history));
+            room.connections.push(tx);
+            drop(lock);
+            stream.write_all(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", derive_accept_key(key.as_bytes())).as_bytes()).await?;
+            let ws = WebSocketStream::from_partially_read(stream, request.body, Role::Server, None).await;
+            serve_websocket(state, race, ws, rx).await
+        }
+        _ => {
+            drop(lock);
+            respond(&mut stream, 404, &[], &errors(&["not found"])).await
+        }
+    }
+}
+
+async fn serve_websocket(state: Arc<Mutex<State>>, race: String, mut ws: WebSocketStream<TcpStream>, mut rx: mpsc::UnboundedReceiver<Outgoing>) -> Result<(), Error> {
+    loop {
+        tokio::select! {
+            msg = ws.next() => match msg {
+                Some(Ok(tungstenite::Message::Text(text))) => if let Some(reply) = handle_action(&state, &race, &text).await {
+                    ws.send(tungstenite::Message::Text(serde_json::to_string(&reply)?)).await?;
+                },
+                Some(Ok(tungstenite::Message::Ping(payload))) => ws.send(tungstenite::Message::Pong(payload)).await?,
+                Some(Ok(tungstenite::Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            outgoing = rx.recv() => match outgoing {
+                Some(Outgoing::Text(text)) => ws.send(tungstenite::Message::Text(text)).await?,
+                Some(Outgoing::Close(code, reason)) => {
+                    ws.close(Some(CloseFrame { code: code.into(), reason: reason.into() })).await?;
+                    break
+                }
+                Some(Outgoing::Drop) | None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Records and emulates an action sent by a bot. Returns a message that should be sent only to the bot that sent the action.
+async fn handle_action(state: &Mutex<State>, race: &str, text: &str) -> Option<Json> {
+    let Ok(value) = serde_json::from_str::<Json>(text) else {
+        return Some(json!({"type": "error", "errors": ["Unable to process that message."]}))
+    };
+    let action = BotAction {
+        race: race.to_owned(),
+        action: value["action"].as_str().unwrap_or_default().to_owned(),
+        data: value.get("data").cloned().unwrap_or(Json::Null),
+    };
+    let mut state = state.lock().await;
+    state.actions.push(action.clone());
+    let _ = state.actions_tx.send(action.clone());
+    match &*action.action {
+        "ping" => return (!state.ignore_pings).then(|| json!({"type": "pong"})),
+        "message" => {
+            let text = action.data["message"].as_str().unwrap_or_default().to_owned();
+            let message = ChatMessage {
+                id: format!("message-{}", state.next_id()),
+                user: None,
+                bot: Some(BOT_NAME.to_owned()),
+                posted_at: Utc::now(),
+                message_plain: text.clone(),
+                message: text,
+                highlight: false,
+                is_bot: true,
+                is_system: Some(false),
+            };
+            state.room(race).push(&Message::ChatMessage { message });
+        }
+        "setinfo" => {
+            let room = state.room(race);
+            if let Some(info_bot) = action.data["info_bot"].as_str() {
+                room.data.info_bot = Some(info_bot.to_owned()).filter(|info| !info.is_empty());
+            }
+            if let Some(info_user) = action.data["info_user"].as_str() {
+                room.data.info_user = Some(info_user.to_owned()).filter(|info| !info.is_empty());
+            }
+            room.race_data_changed();
+        }
+        "delete_message" => {
+            let room = state.room(race);
+            if let Some(pos) = room.chat.iter().position(|message| action.data["message"] == *message.id) {
+                let message = room.chat.remove(pos);
+                room.push(&Message::ChatDelete { delete: ChatDelete {
+                    id: message.id,
+                    user: message.user,
+                    bot: message.bot,
+                    is_bot: message.is_bot,
+                    deleted_by: mock_user(BOT_NAME),
+                } });
+            }
+        }
+        "purge_user" => {
+            let room = state.room(race);
+            let user = room.chat.iter().filter_map(|message| message.user.as_ref())
+                .chain(room.data.entrants.iter().map(|entrant| &entrant.user))
+                .find(|user| action.data["user"] == *user.id)
+                .cloned();
+            if let Some(user) = user {
+                room.chat.retain(|message| message.user.as_ref().is_none_or(|sender| sender.id != user.id));
+                room.push(&Message::ChatPurge { purge: ChatPurge { user, purged_by: mock_user(BOT_NAME) } });
+            }
+        }
+        "override_stream" => {
+            let room = state.room(race);
+            if let Some(entrant) = room.data.entrants.iter_mut().find(|entrant| action.data["user"] == *entrant.user.id) {
+                entrant.stream_override = true;
+                room.race_data_changed();
+            }
+        }
+        _ => {}
+    }
+    None
+}
@@ -7,6 +7,8 @@ use {
     lazy_regex::regex_captures,
     serde::{
         Deserialize,
+        Serialize,
+        Serializer,
         de::{
             Deserializer,
             Error as _,
@@ -16,7 +18,7 @@ use {
     url::Url,
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Message {
     #[serde(rename = "chat.history")]
@@ -51,7 +53,7 @@ pub enum Message {
     RaceSplit,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CategoryData {
     pub name: String,
     pub short_name: String,
@@ -68,7 +70,7 @@ pub struct CategoryData {
     pub emotes: BTreeMap<String, Url>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CategorySummary {
     pub name: String,
     pub short_name: String,
@@ -77,7 +79,7 @@ pub struct CategorySummary {
     pub data_url: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ChatDelete {
     pub id: String,
     pub user: Option<UserData>,
@@ -86,7 +88,7 @@ pub struct ChatDelete {
     pub deleted_by: UserData,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ChatMessage {
     pub id: String,
     pub user: Option<UserData>,
@@ -99,23 +101,23 @@ pub struct ChatMessage {
     pub is_system: Option<bool>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ChatPurge {
     pub user: UserData,
     pub purged_by: UserData,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Goal {
     pub name: String,
     pub custom: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Entrant {
     pub user: UserData,
     pub status: EntrantStatus,
-    #[serde(deserialize_with = "deserialize_opt_django_duration")]
+    #[serde(deserialize_with = "deserialize_opt_django_duration", serialize_with = "serialize_opt_django_duration")]
     pub finish_time: Option<Duration>,
     pub finished_at: Option<DateTime<Utc>>,
     pub place: Option<u32>,
@@ -129,14 +131,14 @@ pub struct Entrant {
     pub team: Option<EntrantTeam>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct EntrantStatus {
     pub value: EntrantStatusValue,
     pub verbose_value: String,
     pub help_text: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum EntrantStatusValue {
     Requested,
@@ -150,7 +152,7 @@ pub enum EntrantStatusValue {
     Dq,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct EntrantTeam {
     pub name: String,
     pub slug: String,
@@ -160,7 +162,7 @@ pub struct EntrantTeam {
     pub avatar: Option<Url>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RaceData {
     pub version: u32,
     pub name: String,
@@ -181,13 +183,13 @@ pub struct RaceData {
     pub entrants_count_inactive: u32,
     pub entrants: Vec<Entrant>,
     pub opened_at: DateTime<Utc>,
-    #[serde(deserialize_with = "deserialize_django_duration")]
+    #[serde(deserialize_with = "deserialize_django_duration", serialize_with = "serialize_django_duration")]
     pub start_delay: Duration,
     pub started_at: Option<DateTime<Utc>>,
     pub ended_at: Option<DateTime<Utc>>,
     pub cancelled_at: Option<DateTime<Utc>>,
     pub unlisted: bool,
-    #[serde(deserialize_with = "deserialize_django_duration")]
+    #[serde(deserialize_with = "deserialize_django_duration", serialize_with = "serialize_django_duration")]
     pub time_limit: Duration,
     pub streaming_required: bool,
     pub auto_start: bool,
@@ -200,18 +202,18 @@ pub struct RaceData {
     pub hide_comments: bool,
     pub allow_midrace_chat: bool,
     pub allow_non_entrant_chat: bool,
-    #[serde(deserialize_with = "deserialize_django_duration")]
+    #[serde(deserialize_with = "deserialize_django_duration", serialize_with = "serialize_django_duration")]
     pub chat_message_delay: Duration,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RaceStatus {
     pub value: RaceStatusValue,
     pub verbose_value: String,
     pub help_text: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum RaceStatusValue {
     Open,
@@ -222,7 +224,7 @@ pub enum RaceStatusValue {
     Cancelled,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RaceSummary {
     pub name: String,
     pub category: Option<CategorySummary>,
@@ -236,11 +238,11 @@ pub struct RaceSummary {
     pub entrants_count_inactive: u32,
     pub opened_at: DateTime<Utc>,
     pub started_at: Option<DateTime<Utc>>,
-    #[serde(deserialize_with = "deserialize_django_duration")]
+    #[serde(deserialize_with = "deserialize_django_duration", serialize_with = "serialize_django_duration")]
     pub time_limit: Duration,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct UserData {
     pub id: String,
     pub full_name: String,
@@ -286,3 +288,23 @@ fn deserialize_opt_django_duration<'de, D: Deserializer<'de>>(deserializer: D) -
         Ok(None)
     }
 }
+
+fn format_django_duration(duration: Duration) -> String {
+    let (sign, duration) = if duration < Duration::zero() { ("-", -duration) } else { ("", duration) };
+    let seconds = duration.num_seconds();
+    let micros = (duration - Duration::seconds(seconds)).num_microseconds().unwrap_or_default();
+    let fraction = if micros == 0 { String::default() } else { format!(".{micros:06}") };
+    format!("{sign}P{}DT{:02}H{:02}M{:02}{fraction}S", seconds / (60 * 60 * 24), seconds / (60 * 60) % 24, seconds / 60 % 60, seconds % 60)
+}
+
+fn serialize_django_duration<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_django_duration(*duration))
+}
+
+fn serialize_opt_django_duration<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    if let Some(duration) = duration {
+        serializer.serialize_some(&format_django_duration(*duration))
+    } else {
+        serializer.serialize_none()
+    }
+}
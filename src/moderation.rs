@@ -0,0 +1,348 @@
+//! Automated chat moderation.
+//!
+//! Build a [`ModerationFilter`] once and return it from [`RaceHandler::moderation_filter`](crate::RaceHandler::moderation_filter):
+//!
+//! ```ignore
+//! let filter = ModerationFilter::new()
+//!     .banned_word("badword")
+//!     .block_links()
+//!     .allow_domain("racetime.gg")
+//!     .rate_limit(5, Duration::from_secs(10));
+//! ```
+//!
+//! Every chat message from a user who is neither a moderator nor a race monitor is checked against the configured rules. Each violation counts as a strike against the user in that race room, and the [`Response`]s for the number of strikes are carried out. Messages that are deleted or purged as a result are not passed to [`RaceHandler::chat_message`](crate::RaceHandler::chat_message).
+
+use {
+    std::{
+        collections::{
+            HashMap,
+            VecDeque,
+        },
+        fmt,
+        time::Duration,
+    },
+    lazy_regex::{
+        Regex,
+        regex,
+    },
+    tokio::{
+        sync::Mutex,
+        time::Instant,
+    },
+    url::Url,
+    crate::{
+        Error,
+        handler::RaceContext,
+        model::*,
+    },
+};
+
+/// A rule broken by a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The message contained one of the [banned words](ModerationFilter::banned_word).
+    BannedWord(String),
+    /// The message matched one of the [banned patterns](ModerationFilter::banned_pattern).
+    BannedPattern(String),
+    /// The message contained a link to a domain that's not [allowed](ModerationFilter::allow_domain).
+    Link(String),
+    /// The message had too many capital letters.
+    Caps,
+    /// The user sent the same message too many times.
+    Repetition,
+    /// The user sent too many messages in a short time.
+    Flood,
+}
+
+impl Violation {
+    fn warning(&self) -> &'static str {
+        match self {
+            Self::BannedWord(_) | Self::BannedPattern(_) => "Please watch your language.",
+            Self::Link(_) => "Links are not allowed here.",
+            Self::Caps => "Please don't use excessive caps.",
+            Self::Repetition => "Please don't repeat the same message.",
+            Self::Flood => "Please slow down.",
+        }
+    }
+}
+
+/// Describes what the user did, e.g. `used excessive caps`.
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BannedWord(word) => write!(f, "used the banned word {word:?}"),
+            Self::BannedPattern(pattern) => write!(f, "matched the banned pattern {pattern:?}"),
+            Self::Link(link) => write!(f, "posted the link {link}"),
+            Self::Caps => write!(f, "used excessive caps"),
+            Self::Repetition => write!(f, "repeated a message"),
+            Self::Flood => write!(f, "sent too many messages"),
+        }
+    }
+}
+
+/// Something that happens in response to a [`Violation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Response {
+    /// Send the user a direct message explaining the violation.
+    Warn,
+    /// Delete the offending message.
+    Delete,
+    /// Delete all of the user's messages in the race room.
+    Purge,
+    /// Send each race monitor a direct message about the violation.
+    NotifyMonitors,
+}
+
+#[derive(Default)]
+struct UserHistory {
+    strikes: usize,
+    recent: VecDeque<(Instant, String)>,
+}
+
+/// A set of chat moderation rules. See the [module-level documentation](self) for details.
+pub struct ModerationFilter {
+    banned_words: Vec<String>,
+    banned_patterns: Vec<Regex>,
+    block_links: bool,
+    allowed_domains: Vec<String>,
+    caps: Option<(f64, usize)>,
+    repetition: Option<(usize, Duration)>,
+    rate_limit: Option<(usize, Duration)>,
+    escalation: Vec<Vec<Response>>,
+    history: Mutex<HashMap<(String, String), UserHistory>>,
+}
+
+impl ModerationFilter {
+    /// Creates a filter with no rules.
+    ///
+    /// Unless changed using [`escalate`](ModerationFilter::escalate), the first two violations by a user are deleted and warned about, and further violations cause the user to be purged and the race monitors to be notified.
+    pub fn new() -> Self {
+        Self {
+            banned_words: Vec::default(),
+            banned_patterns: Vec::default(),
+            block_links: false,
+            allowed_domains: Vec::default(),
+            caps: None,
+            repetition: None,
+            rate_limit: None,
+            escalation: Vec::default(),
+            history: Mutex::default(),
+        }
+    }
+
+    /// Bans a word, matched case-insensitively against whole words. If `word` contains spaces, it is matched case-insensitively anywhere in the message.
+    pub fn banned_word(mut self, word: impl Into<String>) -> Self {
+        self.banned_words.push(word.into().to_lowercase());
+        self
+    }
+
+    /// Bans messages matching a regular expression.
+    pub fn banned_pattern(mut self, pattern: Regex) -> Self {
+        self.banned_patterns.push(pattern);
+        self
+    }
+
+    /// Bans messages containing links, except to domains allowed using [`allow_domain`](ModerationFilter::allow_domain).
+    pub fn block_links(mut self) -> Self {
+        self.block_links = true;
+        self
+    }
+
+    /// Allows links to the given domain and its subdomains even if links are [blocked](ModerationFilter::block_links).
+    pub fn allow_domain(mut self, domain: impl Into<String>) -> Self {
+        self.allowed_domains.push(domain.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Bans messages with at least `min_letters` letters of which more than `max_ratio` (between 0 and 1) are uppercase.
+    pub fn max_caps(mut self, max_ratio: f64, min_letters: usize) -> Self {
+        self.caps = Some((max_ratio, min_letters));
+        self
+    }
+
+    /// Bans sending the same message (case-insensitive) more than `max` times within `window`.
+    pub fn max_repeats(mut self, max: usize, window: Duration) -> Self {
+        self.repetition = Some((max, window));
+        self
+    }
+
+    /// Bans sending more than `max` messages within `window`.
+    pub fn rate_limit(mut self, max: usize, window: Duration) -> Self {
+        self.rate_limit = Some((max, window));
+        self
+    }
+
+    /// Adds an escalation step: the responses to a user's next strike. The first call defines the responses to the first strike, and so on. The last step is repeated for all further strikes.
+    pub fn escalate(mut self, responses: impl IntoIterator<Item = Response>) -> Self {
+        self.escalation.push(responses.into_iter().collect());
+        self
+    }
+
+    /// Checks a chat message against this filter's rules and carries out the appropriate responses.
+    ///
+    /// Messages from bots, the system, moderators, and race monitors are never checked. Returns the violation, if any, and whether the message was deleted. The responses are added to the race room's send queue without waiting for them to be sent.
+    pub async fn check<S: Send + Sync + ?Sized + 'static>(&self, ctx: &RaceContext<S>, msg: &ChatMessage) -> Result<Option<(Violation, bool)>, Error> {
+        if msg.is_bot || msg.is_system.unwrap_or(false) { return Ok(None) }
+        let Some(ref user) = msg.user else { return Ok(None) };
+        let (race_name, monitors) = {
+            let data = ctx.data().await;
+            if user.can_moderate || data.opened_by.as_ref().is_some_and(|creator| creator.id == user.id) || data.monitors.iter().any(|monitor| monitor.id == user.id) {
+                return Ok(None)
+            }
+            (data.name.clone(), data.monitors.clone())
+        };
+        let (violation, responses) = {
+            let mut history = self.history.lock().await;
+            let history = history.entry((race_name, user.id.clone())).or_default();
+            let now = Instant::now();
+            let text = msg.message.trim().to_lowercase();
+            let Some(violation) = self.violation(history, now, &text, &msg.message) else {
+                history.recent.push_back((now, text));
+                return Ok(None)
+            };
+            history.recent.push_back((now, text));
+            history.strikes += 1;
+            (violation, self.responses(history.strikes))
+        };
+        // the responses are only queued, since waiting for them to be sent would stop the room's messages from being handled while the send queue is rate limited
+        let mut deleted = false;
+        for response in responses {
+            match response {
+                Response::Warn => drop(ctx.message(violation.warning()).direct_to(&user.id).queue()?),
+                Response::Delete => if !deleted {
+                    drop(ctx.queue_delete_message(&msg.id));
+                    deleted = true;
+                },
+                Response::Purge => {
+                    drop(ctx.queue_purge_user(&user.id));
+                    deleted = true;
+                }
+                Response::NotifyMonitors => for monitor in &monitors {
+                    drop(ctx.message(format!("{} {violation}: {}", user.name, msg.message_plain)).direct_to(&monitor.id).queue()?);
+                },
+            }
+        }
+        Ok(Some((violation, deleted)))
+    }
+
+    /// Forgets the strikes and recent messages of all users in the given race room.
+    ///
+    /// This is called automatically for the filter returned by [`RaceHandler::moderation_filter`](crate::RaceHandler::moderation_filter) when the bot stops handling the room. If [`check`](ModerationFilter::check) is called manually, this should be called once the room is no longer needed.
+    pub async fn forget_race(&self, race_name: &str) {
+        self.history.lock().await.retain(|(race, _), _| race != race_name);
+    }
+
+    fn violation(&self, history: &mut UserHistory, now: Instant, text: &str, original: &str) -> Option<Violation> {
+        let max_window = self.repetition.iter().chain(&self.rate_limit).map(|&(_, window)| window).max().unwrap_or_default();
+        while history.recent.front().is_some_and(|&(sent_at, _)| now.duration_since(sent_at) > max_window) {
+            history.recent.pop_front();
+        }
+        for word in &self.banned_words {
+            let found = if word.contains(' ') {
+                text.contains(&**word)
+            } else {
+                text.split(|c: char| !c.is_alphanumeric()).any(|text_word| text_word == word)
+            };
+            if found { return Some(Violation::BannedWord(word.clone())) }
+        }
+        for pattern in &self.banned_patterns {
+            if pattern.is_match(original) { return Some(Violation::BannedPattern(pattern.as_str().to_owned())) }
+        }
+        if self.block_links {
+            for link in regex!(r"(?i)\b(?:https?://|www\.)[^\s]+").find_iter(original) {
+                let link = link.as_str();
+                let url = if link.to_ascii_lowercase().starts_with("www.") { format!("http://{link}") } else { link.to_owned() };
+                let allowed = Url::parse(&url).ok().and_then(|url| url.host_str().map(str::to_ascii_lowercase)).is_some_and(|host|
+                    self.allowed_domains.iter().any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+                );
+                if !allowed { return Some(Violation::Link(link.to_owned())) }
+            }
+        }
+        if let Some((max_ratio, min_letters)) = self.caps {
+            let letters = original.chars().filter(|c| c.is_alphabetic()).count();
+            let uppercase = original.chars().filter(|c| c.is_uppercase()).count();
+            if letters >= min_letters && uppercase as f64 > letters as f64 * max_ratio { return Some(Violation::Caps) }
+        }
+        if let Some((max, window)) = self.repetition {
+            if history.recent.iter().filter(|(sent_at, recent)| now.duration_since(*sent_at) <= window && recent == text).count() >= max {
+                return Some(Violation::Repetition)
+            }
+        }
+        if let Some((max, window)) = self.rate_limit {
+            if history.recent.iter().filter(|(sent_at, _)| now.duration_since(*sent_at) <= window).count() >= max {
+                return Some(Violation::Flood)
+            }
+        }
+        None
+    }
+
+    fn responses(&self, strikes: usize) -> Vec<Response> {
+        if self.escalation.is_empty() {
+            if strikes <= 2 {
+                vec![Response::Delete, Response::Warn]
+            } else {
+                vec![Response::Purge, Response::NotifyMonitors]
+            }
+        } else {
+            self.escalation[(strikes - 1).min(self.escalation.len() - 1)].clone()
+        }
+    }
+}
+
+impl Default for ModerationFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::test_util,
+        super::*,
+    };
+
+    fn message(user: &UserData, id: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            id: id.to_owned(),
+            user: Some(user.clone()),
+            bot: None,
+            posted_at: chrono::Utc::now(),
+            message: text.to_owned(),
+            message_plain: text.to_owned(),
+            highlight: false,
+            is_bot: false,
+            is_system: Some(false),
+        }
+    }
+
+    #[tokio::test]
+    async fn forget_race_resets_strikes() -> Result<(), Error> {
+        let ctx = test_util::context(test_util::race(RaceStatusValue::Open));
+        let alice = test_util::user("Alice");
+        let filter = ModerationFilter::new()
+            .banned_word("badword")
+            .escalate([Response::Warn])
+            .escalate([Response::Delete]);
+        // the context isn't connected, so this would hang if the responses were awaited
+        assert_eq!(filter.check(&ctx, &message(&alice, "1", "badword")).await?, Some((Violation::BannedWord("badword".to_owned()), false)));
+        assert_eq!(filter.check(&ctx, &message(&alice, "2", "fine")).await?, None);
+        assert_eq!(filter.check(&ctx, &message(&alice, "3", "BADWORD!")).await?, Some((Violation::BannedWord("badword".to_owned()), true)));
+        filter.forget_race("other/race-1234").await;
+        assert_eq!(filter.check(&ctx, &message(&alice, "4", "badword")).await?, Some((Violation::BannedWord("badword".to_owned()), true)));
+        filter.forget_race(&ctx.data().await.name).await;
+        assert!(filter.history.lock().await.is_empty());
+        assert_eq!(filter.check(&ctx, &message(&alice, "5", "badword")).await?, Some((Violation::BannedWord("badword".to_owned()), false)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn monitors_are_exempt() -> Result<(), Error> {
+        let owner = test_util::user("Owner");
+        let ctx = test_util::context(test_util::race(RaceStatusValue::Open));
+        let filter = ModerationFilter::new().banned_word("badword");
+        assert_eq!(filter.check(&ctx, &message(&owner, "1", "badword")).await?, None);
+        assert!(filter.history.lock().await.is_empty());
+        Ok(())
+    }
+}
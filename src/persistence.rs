@@ -0,0 +1,131 @@
+//! Persistence of race handler state across bot restarts.
+//!
+//! If a [`StateStore`] is set using [`Bot::set_state_store`](crate::Bot::set_state_store), the state returned by [`RaceHandler::save_state`](crate::RaceHandler::save_state) is saved whenever it changes, and handlers for race rooms with saved state are created using [`RaceHandler::restore`](crate::RaceHandler::restore) instead of [`RaceHandler::new`](crate::RaceHandler::new). The saved state of a race room is removed when its handler stops.
+
+use {
+    std::{
+        io,
+        path::PathBuf,
+    },
+    async_trait::async_trait,
+    serde_json::Value as Json,
+    tokio::fs,
+    crate::Error,
+};
+
+/// Storage for race handler state, keyed by [race name](crate::model::RaceData::name).
+///
+/// This trait should be implemented using the [`macro@async_trait`] attribute.
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Returns the saved state of the given race room, if any.
+    async fn load(&self, race: &str) -> Result<Option<Json>, Error>;
+    /// Saves the state of the given race room, replacing any previously saved state.
+    async fn save(&self, race: &str, state: &Json) -> Result<(), Error>;
+    /// Removes the saved state of the given race room. Does nothing if there is none.
+    async fn remove(&self, race: &str) -> Result<(), Error>;
+}
+
+/// A [`StateStore`] which saves the state of each race room as a JSON file in a directory, e.g. `ootr/clever-link-1234.json` for the race `ootr/clever-link-1234`.
+///
+/// Race names must consist of a category slug and a race slug separated by a `/`, each containing only ASCII letters, digits, `-`, and `_`. Other names are rejected with an [`io::ErrorKind::InvalidInput`] error so they can't refer to files outside of the directory.
+#[derive(Debug, Clone)]
+pub struct JsonFileStore {
+    dir: PathBuf,
+}
+
+impl JsonFileStore {
+    /// Creates a store which saves files in the given directory. The directory is created if it doesn't exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, race: &str) -> Result<PathBuf, Error> {
+        let is_valid_slug = |slug: &str| !slug.is_empty() && slug.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        match race.split_once('/') {
+            Some((category, slug)) if is_valid_slug(category) && is_valid_slug(slug) => Ok(self.dir.join(category).join(format!("{slug}.json"))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid race name for a JSON file store: {race:?}")).into()),
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for JsonFileStore {
+    async fn load(&self, race: &str) -> Result<Option<Json>, Error> {
+        match fs::read(self.path(race)?).await {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, race: &str, state: &Json) -> Result<(), Error> {
+        let path = self.path(race)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // write to a temporary file first so a crash can't leave a partially written file behind
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(state)?).await?;
+        fs::rename(tmp_path, path).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, race: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(race)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde_json::json,
+        uuid::Uuid,
+        super::*,
+    };
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("racetime-test-{}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<(), Error> {
+        let dir = temp_dir();
+        let store = JsonFileStore::new(&dir);
+        assert_eq!(store.load("test/clever-link-1234").await?, None);
+        store.save("test/clever-link-1234", &json!({"seed": 1234})).await?;
+        assert_eq!(store.load("test/clever-link-1234").await?, Some(json!({"seed": 1234})));
+        store.save("test/clever-link-1234", &json!({"seed": 5678})).await?;
+        assert_eq!(store.load("test/clever-link-1234").await?, Some(json!({"seed": 5678})));
+        // the temporary file has been renamed to the final one
+        let mut files = Vec::default();
+        let mut entries = fs::read_dir(dir.join("test")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            files.push(entry.file_name());
+        }
+        assert_eq!(files, ["clever-link-1234.json"]);
+        assert_eq!(store.load("test/other-race-5678").await?, None);
+        store.remove("test/clever-link-1234").await?;
+        assert_eq!(store.load("test/clever-link-1234").await?, None);
+        store.remove("test/clever-link-1234").await?;
+        fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_race_names() -> Result<(), Error> {
+        let dir = temp_dir();
+        let store = JsonFileStore::new(&dir);
+        for race in ["clever-link-1234", "test/", "/clever-link-1234", "test/../secret", "../test/clever-link-1234", "test/nested/clever-link-1234", "test\\clever-link-1234", "test/..", "test/clever.link"] {
+            assert!(matches!(store.load(race).await, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput), "{race:?} was accepted");
+            assert!(matches!(store.save(race, &json!(null)).await, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput), "{race:?} was accepted");
+            assert!(matches!(store.remove(race).await, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidInput), "{race:?} was accepted");
+        }
+        assert!(!fs::try_exists(&dir).await?);
+        Ok(())
+    }
+}
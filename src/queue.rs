@@ -0,0 +1,539 @@
+//! Rate limiting and prioritization of outgoing race room actions.
+//!
+//! All actions sent via [`RaceContext`](crate::handler::RaceContext) go through a per-room send queue which is drained by a background task, subject to the rate limits configured using [`Bot::set_room_rate_limit`](crate::Bot::set_room_rate_limit) and [`Bot::set_global_rate_limit`](crate::Bot::set_global_rate_limit).
+//!
+//! The background task owns the race room's WebSocket connection. While the bot is reconnecting to a race room, actions stay queued and are sent once the connection has been reestablished. If the connection is lost while an action is being sent, that action and any actions sent before the bot starts reconnecting fail with the error instead, since the race room's task may be waiting for them.
+
+use {
+    std::{
+        collections::VecDeque,
+        future::{
+            self,
+            Future,
+        },
+        pin::Pin,
+        sync::Arc,
+        task::{
+            Context,
+            Poll,
+        },
+        time::Duration,
+    },
+    futures::SinkExt as _,
+    serde_json::Value as Json,
+    tokio::{
+        sync::{
+            Notify,
+            mpsc,
+            oneshot,
+        },
+        time::{
+            Instant,
+            sleep_until,
+        },
+    },
+    tokio_tungstenite::tungstenite,
+    crate::{
+        Error,
+        transport::MessageSink,
+    },
+};
+
+/// A token bucket rate limit: up to `burst` actions can be sent at once, and one more becomes available every `interval`. A `burst` of 0 is treated as 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, interval: Duration) -> Self {
+        Self { burst, interval }
+    }
+}
+
+/// The priority of a queued action. Queued actions with [`High`](Priority::High) priority are sent before any queued actions with [`Normal`](Priority::Normal) priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Chat messages and race info updates.
+    Normal,
+    /// Moderation actions such as [`force_start`](crate::handler::RaceContext::force_start) or [`remove_entrant`](crate::handler::RaceContext::remove_entrant).
+    High,
+}
+
+impl Priority {
+    /// Returns the default priority of a raw action: [`Normal`](Priority::Normal) for `message` and `setinfo`, [`High`](Priority::High) for everything else.
+    pub fn of(message: &Json) -> Self {
+        match message["action"].as_str() {
+            Some("message" | "setinfo") => Self::Normal,
+            _ => Self::High,
+        }
+    }
+}
+
+/// A future that resolves once a queued action has been written to the race room's WebSocket connection, or fails if it couldn't be.
+///
+/// Dropping this does not remove the action from the queue.
+#[must_use = "dropping a Delivery does not cancel sending, but any error will be lost"]
+pub struct Delivery {
+    rx: oneshot::Receiver<Result<(), Arc<Error>>>,
+}
+
+impl Future for Delivery {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|res| match res {
+            Ok(Ok(())) => Ok(()),
+            // coalesced actions share the error, so it can only be unwrapped once
+            Ok(Err(e)) => Err(Arc::try_unwrap(e).unwrap_or_else(Error::Delivery)),
+            Err(_) => Err(Error::QueueClosed),
+        })
+    }
+}
+
+struct Queued {
+    message: Json,
+    waiters: Vec<oneshot::Sender<Result<(), Arc<Error>>>>,
+}
+
+impl Queued {
+    fn resolve(mut self, res: Result<(), Arc<Error>>) {
+        // the last waiter gets the original so the error can usually be unwrapped
+        let last = self.waiters.pop();
+        for waiter in self.waiters {
+            let _ = waiter.send(res.clone());
+        }
+        if let Some(last) = last {
+            let _ = last.send(res);
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    high: VecDeque<Queued>,
+    normal: VecDeque<Queued>,
+    sending: bool,
+    closed: bool,
+}
+
+/// The outgoing action queue of a race room.
+#[derive(Default)]
+pub(crate) struct SendQueue {
+    state: std::sync::Mutex<QueueState>,
+    notify: Notify,
+    idle: Notify,
+}
+
+impl SendQueue {
+    /// Adds an action to the queue.
+    ///
+    /// A `setinfo` action replaces a queued `setinfo` action that sets the same fields, so that only the latest info is sent.
+    pub(crate) fn push(&self, message: Json, priority: Priority) -> Delivery {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().expect("send queue lock poisoned");
+        let state = &mut *state;
+        if state.closed {
+            let _ = tx.send(Err(Arc::new(Error::QueueClosed)));
+            return Delivery { rx }
+        }
+        if message["action"] == "setinfo" {
+            if let Some(queued) = state.high.iter_mut().chain(&mut state.normal).find(|queued| queued.message["action"] == "setinfo" && same_keys(&queued.message["data"], &message["data"])) {
+                queued.message = message;
+                queued.waiters.push(tx);
+                return Delivery { rx }
+            }
+        }
+        let queued = Queued { message, waiters: vec![tx] };
+        match priority {
+            Priority::High => state.high.push_back(queued),
+            Priority::Normal => state.normal.push_back(queued),
+        }
+        self.notify.notify_one();
+        Delivery { rx }
+    }
+
+    /// Waits until the queue is nonempty. Returns `false` if the queue has been closed.
+    async fn wait(&self) -> bool {
+        loop {
+            {
+                let state = self.state.lock().expect("send queue lock poisoned");
+                if state.closed { return false }
+                if !state.high.is_empty() || !state.normal.is_empty() { return true }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<Queued> {
+        let mut state = self.state.lock().expect("send queue lock poisoned");
+        let queued = state.high.pop_front().or_else(|| state.normal.pop_front());
+        state.sending = queued.is_some();
+        queued
+    }
+
+    fn finish_sending(&self) {
+        self.state.lock().expect("send queue lock poisoned").sending = false;
+        self.idle.notify_waiters();
+    }
+
+    /// Waits until all queued actions have been sent or the queue has been closed.
+    pub(crate) async fn drain(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            {
+                let state = self.state.lock().expect("send queue lock poisoned");
+                if state.closed || !state.sending && state.high.is_empty() && state.normal.is_empty() { return }
+            }
+            idle.await;
+        }
+    }
+
+    /// Returns the actions which are waiting to be sent, in the order they will be sent.
+    #[cfg(test)]
+    pub(crate) fn pending(&self) -> Vec<Json> {
+        let state = self.state.lock().expect("send queue lock poisoned");
+        state.high.iter().chain(&state.normal).map(|queued| queued.message.clone()).collect()
+    }
+
+    /// Closes the queue, failing all pending and future actions with [`Error::QueueClosed`].
+    pub(crate) fn close(&self) {
+        let pending = {
+            let mut state = self.state.lock().expect("send queue lock poisoned");
+            let state = &mut *state;
+            state.closed = true;
+            state.high.drain(..).chain(state.normal.drain(..)).collect::<Vec<_>>()
+        };
+        let error = Arc::new(Error::QueueClosed);
+        for queued in pending {
+            queued.resolve(Err(Arc::clone(&error)));
+        }
+        self.notify.notify_one();
+        self.idle.notify_waiters();
+    }
+}
+
+fn same_keys(a: &Json, b: &Json) -> bool {
+    match (a.as_object(), b.as_object()) {
+        (Some(a), Some(b)) => a.len() == b.len() && a.keys().all(|key| b.contains_key(key)),
+        (_, _) => false,
+    }
+}
+
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            tokens: limit.burst.max(1).into(),
+            last_refill: Instant::now(),
+            limit,
+        }
+    }
+
+    /// Returns how long to wait until a token is available, or zero if one is available now.
+    fn wait_time(&mut self) -> Duration {
+        let now = Instant::now();
+        let capacity = self.limit.burst.max(1).into();
+        if self.limit.interval.is_zero() {
+            self.tokens = capacity;
+        } else {
+            self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() / self.limit.interval.as_secs_f64()).min(capacity);
+        }
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            self.limit.interval.mul_f64(1.0 - self.tokens)
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+enum WriterCommand {
+    Connect(MessageSink),
+    Disconnect,
+    Frame(tungstenite::Message, oneshot::Sender<Result<(), Error>>),
+    Close(oneshot::Sender<Result<(), Error>>),
+}
+
+/// A handle to the background task which owns the sending half of a race room's WebSocket connection.
+pub(crate) struct Writer {
+    commands: mpsc::UnboundedSender<WriterCommand>,
+    lost: mpsc::UnboundedReceiver<(u64, Arc<Error>)>,
+    /// The number of times [`connect`](Writer::connect) has been called, used to ignore lost connections which have already been replaced.
+    generation: u64,
+}
+
+impl Writer {
+    /// Returns the handle along with the writer task, which sends queued actions of the race room until the queue is closed and all handles have been dropped.
+    pub(crate) fn new(queue: Arc<SendQueue>, sink: MessageSink, room_limit: Option<RateLimit>, global_limit: Option<Arc<std::sync::Mutex<TokenBucket>>>) -> (Self, impl Future<Output = ()>) {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (lost_tx, lost) = mpsc::unbounded_channel();
+        (Self { commands, lost, generation: 0 }, run_writer(queue, commands_rx, lost_tx, sink, room_limit, global_limit))
+    }
+
+    /// Replaces the connection after reconnecting. Actions which were queued while disconnected are sent afterwards.
+    pub(crate) fn connect(&mut self, sink: MessageSink) {
+        self.generation += 1;
+        let _ = self.commands.send(WriterCommand::Connect(sink));
+    }
+
+    /// Waits until sending an action fails because the current connection was lost, and returns the error.
+    ///
+    /// Until the writer is [disconnected](Writer::disconnect) or [connected](Writer::connect) again, queued actions fail with the same error rather than waiting for a reconnect, since the race room's task may be waiting for one of them to be sent.
+    pub(crate) async fn connection_lost(&mut self) -> Arc<Error> {
+        loop {
+            match self.lost.recv().await {
+                Some((generation, e)) => if generation == self.generation { break e },
+                None => future::pending().await,
+            }
+        }
+    }
+
+    /// Stops sending queued actions until [`connect`](Writer::connect) is called.
+    pub(crate) fn disconnect(&self) {
+        let _ = self.commands.send(WriterCommand::Disconnect);
+    }
+
+    /// Sends a message directly, bypassing the queue and rate limits. Used for pings and pongs.
+    pub(crate) async fn send_frame(&self, message: tungstenite::Message) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(WriterCommand::Frame(message, tx)).map_err(|_| Error::QueueClosed)?;
+        rx.await.map_err(|_| Error::QueueClosed)?
+    }
+
+    /// Closes the connection. Queued actions are not sent unless the writer is [connected](Writer::connect) again.
+    pub(crate) async fn close(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(WriterCommand::Close(tx)).map_err(|_| Error::QueueClosed)?;
+        rx.await.map_err(|_| Error::QueueClosed)?
+    }
+}
+
+/// Whether sending failed because the connection was lost, in which case the race room's task is notified so it can reconnect.
+fn is_connection_error(e: &Error) -> bool {
+    matches!(e,
+        Error::Io(_)
+        | Error::Tungstenite(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed | tungstenite::Error::Io(_))
+    )
+}
+
+async fn run_writer(queue: Arc<SendQueue>, mut commands: mpsc::UnboundedReceiver<WriterCommand>, lost_tx: mpsc::UnboundedSender<(u64, Arc<Error>)>, sink: MessageSink, room_limit: Option<RateLimit>, global_limit: Option<Arc<std::sync::Mutex<TokenBucket>>>) {
+    let mut sink = Some(sink);
+    let mut generation = 0;
+    // set if the connection was lost while sending and the race room's task hasn't started reconnecting yet
+    let mut lost = None::<Arc<Error>>;
+    let mut queue_open = true;
+    let mut room_bucket = room_limit.map(TokenBucket::new);
+    let mut rate_limited_until = None;
+    loop {
+        tokio::select! {
+            biased;
+            command = commands.recv() => match command {
+                Some(WriterCommand::Connect(new_sink)) => {
+                    generation += 1;
+                    sink = Some(new_sink);
+                    lost = None;
+                }
+                Some(WriterCommand::Disconnect) => {
+                    sink = None;
+                    lost = None;
+                }
+                Some(WriterCommand::Frame(message, reply)) => {
+                    let res = match sink {
+                        Some(ref mut sink) => sink.send(message).await,
+                        None => Err(tungstenite::Error::AlreadyClosed.into()),
+                    };
+                    let _ = reply.send(res);
+                }
+                Some(WriterCommand::Close(reply)) => {
+                    let res = match sink.take() {
+                        Some(mut sink) => sink.close().await,
+                        None => Ok(()),
+                    };
+                    let _ = reply.send(res);
+                }
+                None => break,
+            },
+            () = sleep_until(rate_limited_until.unwrap_or_else(Instant::now)), if rate_limited_until.is_some() => rate_limited_until = None,
+            open = queue.wait(), if queue_open && (lost.is_some() || sink.is_some() && rate_limited_until.is_none()) => {
+                if !open {
+                    // keep handling pings and the closing handshake until the race room's task is done
+                    queue_open = false;
+                    continue
+                }
+                if let Some(ref e) = lost {
+                    if let Some(queued) = queue.pop() {
+                        queued.resolve(Err(Arc::clone(e)));
+                        queue.finish_sending();
+                    }
+                    continue
+                }
+                let mut wait = room_bucket.as_mut().map_or(Duration::ZERO, TokenBucket::wait_time);
+                if wait.is_zero() {
+                    if let Some(ref global_bucket) = global_limit {
+                        let mut global_bucket = global_bucket.lock().expect("global rate limit lock poisoned");
+                        wait = global_bucket.wait_time();
+                        if wait.is_zero() {
+                            global_bucket.take();
+                        }
+                    }
+                }
+                if !wait.is_zero() {
+                    // pings and other commands keep being handled while waiting for the rate limits
+                    rate_limited_until = Some(Instant::now() + wait);
+                    continue
+                }
+                if let Some(ref mut room_bucket) = room_bucket {
+                    room_bucket.take();
+                }
+                // the queue may have been closed in the meantime
+                let Some(queued) = queue.pop() else { continue };
+                match serde_json::to_string(&queued.message) {
+                    Ok(text) => match sink.as_mut().expect("the writer should still be connected").send(tungstenite::Message::Text(text)).await {
+                        Ok(()) => queued.resolve(Ok(())),
+                        Err(e) if is_connection_error(&e) => {
+                            // the race room's task may be waiting for this action, so it's failed rather than kept until the race room's task reconnects
+                            let e = Arc::new(e);
+                            sink = None;
+                            lost = Some(Arc::clone(&e));
+                            let _ = lost_tx.send((generation, Arc::clone(&e)));
+                            queued.resolve(Err(e));
+                        }
+                        Err(e) => queued.resolve(Err(Arc::new(e))),
+                    },
+                    Err(e) => queued.resolve(Err(Arc::new(e.into()))),
+                }
+                queue.finish_sending();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        futures::{
+            StreamExt as _,
+            channel::mpsc::UnboundedReceiver,
+        },
+        serde_json::json,
+        super::*,
+    };
+
+    fn message(text: &str) -> Json {
+        json!({"action": "message", "data": {"message": text}})
+    }
+
+    /// Returns a sink which passes the sent messages to the returned receiver.
+    fn channel_sink() -> (MessageSink, UnboundedReceiver<tungstenite::Message>) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        (Box::pin(tx.sink_map_err(|_| Error::QueueClosed)), rx)
+    }
+
+    /// Waits for the next text message and returns it along with the number of whole seconds since `start`.
+    async fn next_sent(rx: &mut UnboundedReceiver<tungstenite::Message>, start: Instant) -> (Json, u64) {
+        let tungstenite::Message::Text(text) = rx.next().await.expect("writer stopped") else { panic!("expected a text message") };
+        (serde_json::from_str(&text).expect("sent invalid JSON"), start.elapsed().as_secs_f64().round() as u64)
+    }
+
+    #[test]
+    fn priorities() {
+        let queue = SendQueue::default();
+        drop(queue.push(message("first"), Priority::Normal));
+        drop(queue.push(json!({"action": "remove_entrant", "data": {"user": "user-alice"}}), Priority::High));
+        drop(queue.push(message("second"), Priority::Normal));
+        drop(queue.push(json!({"action": "force_start"}), Priority::High));
+        let actions = queue.pending().into_iter().map(|message| message["action"].as_str().map(str::to_owned)).collect::<Vec<_>>();
+        assert_eq!(actions, ["remove_entrant", "force_start", "message", "message"].map(|action| Some(action.to_owned())));
+        assert_eq!(queue.pending()[2]["data"]["message"], "first");
+        assert_eq!(Priority::of(&message("hi")), Priority::Normal);
+        assert_eq!(Priority::of(&json!({"action": "setinfo"})), Priority::Normal);
+        assert_eq!(Priority::of(&json!({"action": "cancel_race"})), Priority::High);
+    }
+
+    #[tokio::test]
+    async fn setinfo_coalescing() -> Result<(), Error> {
+        let queue = Arc::new(SendQueue::default());
+        let first = queue.push(json!({"action": "setinfo", "data": {"info_bot": "a"}}), Priority::Normal);
+        drop(queue.push(message("hi"), Priority::Normal));
+        let second = queue.push(json!({"action": "setinfo", "data": {"info_bot": "b"}}), Priority::Normal);
+        drop(queue.push(json!({"action": "setinfo", "data": {"info_user": "c"}}), Priority::Normal));
+        let pending = queue.pending();
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0]["data"], json!({"info_bot": "b"}));
+        assert_eq!(pending[2]["data"], json!({"info_user": "c"}));
+        let (sink, mut rx) = channel_sink();
+        let (_writer, task) = Writer::new(Arc::clone(&queue), sink, None, None);
+        tokio::spawn(task);
+        first.await?;
+        second.await?;
+        let (sent, _) = next_sent(&mut rx, Instant::now()).await;
+        assert_eq!(sent["data"], json!({"info_bot": "b"}));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn room_rate_limit() -> Result<(), Error> {
+        let start = Instant::now();
+        let queue = Arc::new(SendQueue::default());
+        let (sink, mut rx) = channel_sink();
+        let (writer, task) = Writer::new(Arc::clone(&queue), sink, Some(RateLimit::new(2, Duration::from_secs(1))), None);
+        tokio::spawn(task);
+        for text in ["1", "2", "3", "4"] {
+            drop(queue.push(message(text), Priority::Normal));
+        }
+        assert_eq!(next_sent(&mut rx, start).await, (message("1"), 0));
+        assert_eq!(next_sent(&mut rx, start).await, (message("2"), 0));
+        // control frames aren't held up by the rate limit
+        writer.send_frame(tungstenite::Message::Pong(Vec::default())).await?;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(rx.next().await, Some(tungstenite::Message::Pong(Vec::default())));
+        assert_eq!(next_sent(&mut rx, start).await, (message("3"), 1));
+        assert_eq!(next_sent(&mut rx, start).await, (message("4"), 2));
+        // tokens refill while idle, up to the burst size
+        sleep_until(start + Duration::from_secs(10)).await;
+        for text in ["5", "6", "7"] {
+            drop(queue.push(message(text), Priority::Normal));
+        }
+        assert_eq!(next_sent(&mut rx, start).await, (message("5"), 10));
+        assert_eq!(next_sent(&mut rx, start).await, (message("6"), 10));
+        assert_eq!(next_sent(&mut rx, start).await, (message("7"), 11));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn global_rate_limit() {
+        let start = Instant::now();
+        let global_limit = Arc::new(std::sync::Mutex::new(TokenBucket::new(RateLimit::new(1, Duration::from_secs(1)))));
+        let (queue_a, queue_b) = (Arc::new(SendQueue::default()), Arc::new(SendQueue::default()));
+        // both writers send to the same channel so the order in which messages are sent is kept
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let (_writer_a, task_a) = Writer::new(Arc::clone(&queue_a), Box::pin(tx.clone().sink_map_err(|_| Error::QueueClosed)), None, Some(Arc::clone(&global_limit)));
+        let (_writer_b, task_b) = Writer::new(Arc::clone(&queue_b), Box::pin(tx.sink_map_err(|_| Error::QueueClosed)), None, Some(global_limit));
+        drop(queue_a.push(message("a1"), Priority::Normal));
+        drop(queue_a.push(message("a2"), Priority::Normal));
+        drop(queue_b.push(message("b1"), Priority::Normal));
+        tokio::spawn(task_a);
+        tokio::spawn(task_b);
+        let mut sent = Vec::default();
+        for _ in 0..3 {
+            let (message, secs) = next_sent(&mut rx, start).await;
+            sent.push((message["data"]["message"].as_str().expect("message text").to_owned(), secs));
+        }
+        assert_eq!(sent.iter().map(|&(_, secs)| secs).collect::<Vec<_>>(), [0, 1, 2]);
+        // each room's own messages stay in order
+        assert!(sent.iter().position(|(text, _)| text == "a1") < sent.iter().position(|(text, _)| text == "a2"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Access to the race rooms handled by a [`Bot`](crate::Bot) from outside of race handlers, e.g. from a web dashboard or a scheduler.
+//!
+//! Get a [`RoomRegistry`] using [`Bot::rooms`](crate::Bot::rooms) before running the bot.
+
+use {
+    std::{
+        collections::BTreeMap,
+        mem,
+        sync::Arc,
+    },
+    serde_json::Value as Json,
+    tokio::sync::broadcast,
+    crate::{
+        Error,
+        handler::RaceContext,
+        model::RaceData,
+    },
+};
+
+/// A notification about the set of race rooms handled by the bot, received using [`RoomRegistry::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomEvent {
+    /// The bot has started handling the race room with this name.
+    Added(String),
+    /// The bot has stopped handling the race room with this name.
+    Removed(String),
+}
+
+/// A handle to the race rooms currently handled by a [`Bot`](crate::Bot). Cloning it returns a handle to the same rooms.
+///
+/// Rooms can be referred to by their full [name](RaceData::name), e.g. `ootr/clever-link-1234`, or just their slug, e.g. `clever-link-1234`.
+pub struct RoomRegistry<S: Send + Sync + ?Sized + 'static> {
+    rooms: Arc<std::sync::Mutex<BTreeMap<String, RaceContext<S>>>>,
+    events: broadcast::Sender<RoomEvent>,
+}
+
+impl<S: Send + Sync + ?Sized + 'static> RoomRegistry<S> {
+    pub(crate) fn new() -> Self {
+        let (events, _) = broadcast::channel(1_024);
+        Self {
+            rooms: Arc::default(),
+            events,
+        }
+    }
+
+    pub(crate) fn insert(&self, name: String, ctx: RaceContext<S>) {
+        self.rooms.lock().expect("room registry lock poisoned").insert(name.clone(), ctx);
+        let _ = self.events.send(RoomEvent::Added(name));
+    }
+
+    pub(crate) fn remove(&self, name: &str) {
+        if self.rooms.lock().expect("room registry lock poisoned").remove(name).is_some() {
+            let _ = self.events.send(RoomEvent::Removed(name.to_owned()));
+        }
+    }
+
+    /// Removes all rooms, failing their queued actions. Used for rooms whose tasks were aborted.
+    pub(crate) fn clear(&self) {
+        let rooms = mem::take(&mut *self.rooms.lock().expect("room registry lock poisoned"));
+        for (name, ctx) in rooms {
+            ctx.queue.close();
+            let _ = self.events.send(RoomEvent::Removed(name));
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.rooms.lock().expect("room registry lock poisoned").len()
+    }
+
+    /// Returns the names of the race rooms currently handled by the bot.
+    pub fn names(&self) -> Vec<String> {
+        self.rooms.lock().expect("room registry lock poisoned").keys().cloned().collect()
+    }
+
+    /// Returns the context of the given race room, which can be used to check its data and send messages or actions, or [`None`] if the bot isn't currently handling it.
+    pub fn get(&self, room: &str) -> Option<RaceContext<S>> {
+        let rooms = self.rooms.lock().expect("room registry lock poisoned");
+        rooms.get(room)
+            .or_else(|| rooms.iter().find(|(name, _)| name.split_once('/').is_some_and(|(_, slug)| slug == room)).map(|(_, ctx)| ctx))
+            .cloned()
+    }
+
+    /// Returns the latest data of all race rooms currently handled by the bot.
+    pub async fn races(&self) -> Vec<RaceData> {
+        let contexts = self.rooms.lock().expect("room registry lock poisoned").values().cloned().collect::<Vec<_>>();
+        let mut races = Vec::with_capacity(contexts.len());
+        for ctx in contexts {
+            races.push(ctx.data().await.clone());
+        }
+        races
+    }
+
+    /// Returns the latest data of the given race room, or [`None`] if the bot isn't currently handling it.
+    pub async fn race(&self, room: &str) -> Option<RaceData> {
+        let ctx = self.get(room)?;
+        let data = ctx.data().await.clone();
+        Some(data)
+    }
+
+    /// Sends a chat message to the given race room. See [`RaceContext::send_message`].
+    pub async fn send_message(&self, room: &str, message: &str) -> Result<(), Error> {
+        self.get(room).ok_or_else(|| Error::UnknownRoom(room.to_owned()))?.send_message(message).await
+    }
+
+    /// Sends a raw JSON message, such as an action, to the given race room. See [`RaceContext::send_raw`].
+    pub async fn send_raw(&self, room: &str, message: &Json) -> Result<(), Error> {
+        self.get(room).ok_or_else(|| Error::UnknownRoom(room.to_owned()))?.send_raw(message).await
+    }
+
+    /// Returns a receiver of notifications for race rooms being added to or removed from the registry.
+    ///
+    /// Only rooms which are added or removed after this is called are included, so [`names`](RoomRegistry::names) should be called afterwards to get the initial set of rooms. If the receiver falls too far behind, it will skip some notifications, see [`broadcast::Receiver::recv`].
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
+        self.events.subscribe()
+    }
+}
+
+impl<S: Send + Sync + ?Sized + 'static> Clone for RoomRegistry<S> {
+    fn clone(&self) -> Self {
+        Self {
+            rooms: Arc::clone(&self.rooms),
+            events: self.events.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde_json::json,
+        tokio::sync::broadcast::error::TryRecvError,
+        crate::{
+            model::RaceStatusValue,
+            test_util,
+        },
+        super::*,
+    };
+
+    fn room(slug: &str) -> RaceContext<()> {
+        let mut race = test_util::race(RaceStatusValue::Open);
+        race.name = format!("test/{slug}");
+        race.slug = slug.to_owned();
+        test_util::context(race)
+    }
+
+    #[tokio::test]
+    async fn add_and_remove() {
+        let registry = RoomRegistry::new();
+        let mut events = registry.subscribe();
+        registry.insert("test/clever-link-1234".to_owned(), room("clever-link-1234"));
+        registry.insert("test/wise-zelda-5678".to_owned(), room("wise-zelda-5678"));
+        assert_eq!(registry.names(), ["test/clever-link-1234", "test/wise-zelda-5678"]);
+        assert_eq!(registry.len(), 2);
+        registry.remove("test/clever-link-1234");
+        // removing a room which isn't handled is a no-op
+        registry.remove("test/clever-link-1234");
+        assert_eq!(registry.names(), ["test/wise-zelda-5678"]);
+        assert_eq!(registry.races().await.into_iter().map(|race| race.slug).collect::<Vec<_>>(), ["wise-zelda-5678"]);
+        assert_eq!(events.try_recv(), Ok(RoomEvent::Added("test/clever-link-1234".to_owned())));
+        assert_eq!(events.try_recv(), Ok(RoomEvent::Added("test/wise-zelda-5678".to_owned())));
+        assert_eq!(events.try_recv(), Ok(RoomEvent::Removed("test/clever-link-1234".to_owned())));
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[tokio::test]
+    async fn lookup() {
+        let registry = RoomRegistry::new();
+        registry.insert("test/clever-link-1234".to_owned(), room("clever-link-1234"));
+        // clones of the registry share its rooms
+        let clone = registry.clone();
+        assert_eq!(clone.race("test/clever-link-1234").await.map(|race| race.slug), Some("clever-link-1234".to_owned()));
+        assert_eq!(clone.race("clever-link-1234").await.map(|race| race.name), Some("test/clever-link-1234".to_owned()));
+        assert!(clone.get("test").is_none());
+        assert!(clone.get("other/clever-link-1234").is_none());
+        assert!(clone.race("wise-zelda-5678").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unknown_room() {
+        let registry = RoomRegistry::<()>::new();
+        match registry.send_message("clever-link-1234", "hello").await {
+            Err(Error::UnknownRoom(room)) => assert_eq!(room, "clever-link-1234"),
+            res => panic!("expected an unknown room error, got {res:?}"),
+        }
+        match registry.send_raw("test/clever-link-1234", &json!({"action": "ping"})).await {
+            Err(Error::UnknownRoom(room)) => assert_eq!(room, "test/clever-link-1234"),
+            res => panic!("expected an unknown room error, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn clear_fails_queued_actions() {
+        let registry = RoomRegistry::new();
+        let mut events = registry.subscribe();
+        let ctx = room("clever-link-1234");
+        registry.insert("test/clever-link-1234".to_owned(), ctx.clone());
+        let send = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.send_message("clever-link-1234", "hello").await }
+        });
+        while ctx.queue.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        registry.clear();
+        assert!(matches!(send.await.expect("send task panicked"), Err(Error::QueueClosed)));
+        assert!(registry.names().is_empty());
+        assert_eq!(events.try_recv(), Ok(RoomEvent::Added("test/clever-link-1234".to_owned())));
+        assert_eq!(events.try_recv(), Ok(RoomEvent::Removed("test/clever-link-1234".to_owned())));
+    }
+}
@@ -0,0 +1,222 @@
+//! A [`Stream`]-based alternative to implementing [`RaceHandler`].
+//!
+//! [`Bot::into_stream`](crate::Bot::into_stream) runs the bot in the background and returns a [`BotEvents`] stream of the messages received in all handled race rooms, which can be consumed in a single task. This is useful for bots which coordinate across race rooms.
+//!
+//! ```ignore
+//! let mut events = bot.into_stream();
+//! while let Some(event) = events.try_next().await? {
+//!     match event {
+//!         BotEvent::RoomAdded(ctx) => ctx.send_message("Hello!").await?,
+//!         BotEvent::Message(ctx, Message::ChatMessage { message }) => { /* ... */ }
+//!         BotEvent::Message(_, _) | BotEvent::RoomRemoved(_) => {}
+//!     }
+//! }
+//! ```
+
+use {
+    std::{
+        future::Future,
+        pin::Pin,
+        task::{
+            Context,
+            Poll,
+            ready,
+        },
+    },
+    async_trait::async_trait,
+    futures::Stream,
+    tokio::{
+        sync::{
+            mpsc,
+            oneshot,
+        },
+        task::JoinHandle,
+    },
+    crate::{
+        Error,
+        handler::{
+            RaceContext,
+            RaceHandler,
+        },
+        message::ActionInvocation,
+        model::*,
+    },
+};
+
+/// An item of a [`BotEvents`] stream.
+pub enum BotEvent<S: Send + Sync + ?Sized + 'static> {
+    /// The bot has started handling a race room. The context can be used to check the race data and send messages or actions, including after the room has been removed (in which case sending fails).
+    RoomAdded(RaceContext<S>),
+    /// A message was received in a race room.
+    ///
+    /// Chat messages which have already been received in the room, e.g. because the server replayed them after a reconnect, are not included. Messages sent by [action buttons](crate::message::MessageAction::command) are included as regular chat messages.
+    Message(RaceContext<S>, Message),
+    /// The bot has stopped handling the race room with this name, e.g. because the race has finished.
+    RoomRemoved(String),
+}
+
+/// A [`Stream`] of the events of all race rooms handled by a bot, returned by [`Bot::into_stream`](crate::Bot::into_stream).
+///
+/// The stream yields an error and ends if the bot stops due to an error. Dropping the stream shuts down the bot in the background.
+pub struct BotEvents<S: Send + Sync + ?Sized + 'static> {
+    pub(crate) rx: mpsc::Receiver<BotEvent<S>>,
+    pub(crate) shutdown: Option<oneshot::Sender<()>>,
+    pub(crate) task: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl<S: Send + Sync + ?Sized + 'static> BotEvents<S> {
+    /// Shuts down the bot as if the future passed to [`Bot::run_until`](crate::Bot::run_until) had completed. The stream yields the remaining events, including a [`BotEvent::RoomRemoved`] for each room, and then ends.
+    pub fn shutdown(&mut self) {
+        self.shutdown = None;
+    }
+}
+
+impl<S: Send + Sync + ?Sized + 'static> Stream for BotEvents<S> {
+    type Item = Result<BotEvent<S>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(Some(event)) = self.rx.poll_recv(cx) {
+            return Poll::Ready(Some(Ok(event)))
+        }
+        if let Some(ref mut task) = self.task {
+            let res = ready!(Pin::new(task).poll(cx));
+            self.task = None;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+        // the bot has stopped, so only deliver the events which are already queued
+        Poll::Ready(self.rx.try_recv().ok().map(Ok))
+    }
+}
+
+/// The race handler used by [`Bot::into_stream`](crate::Bot::into_stream), which forwards all messages to the stream.
+pub(crate) struct StreamHandler;
+
+impl StreamHandler {
+    async fn forward<S: Send + Sync + ?Sized + 'static>(ctx: &RaceContext<S>, message: Message) {
+        ctx.emit(|| BotEvent::Message(ctx.clone(), message)).await;
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync + ?Sized + 'static> RaceHandler<S> for StreamHandler {
+    async fn new(_ctx: &RaceContext<S>) -> Result<Self, Error> { Ok(Self) }
+
+    async fn chat_history(&mut self, ctx: &RaceContext<S>, messages: Vec<ChatMessage>) -> Result<(), Error> {
+        Self::forward(ctx, Message::ChatHistory { messages }).await;
+        Ok(())
+    }
+
+    async fn chat_message(&mut self, ctx: &RaceContext<S>, message: ChatMessage) -> Result<(), Error> {
+        Self::forward(ctx, Message::ChatMessage { message }).await;
+        Ok(())
+    }
+
+    async fn message_action(&mut self, ctx: &RaceContext<S>, _action: ActionInvocation, msg: &ChatMessage) -> Result<(), Error> {
+        Self::forward(ctx, Message::ChatMessage { message: msg.clone() }).await;
+        Ok(())
+    }
+
+    async fn chat_delete(&mut self, ctx: &RaceContext<S>, delete: ChatDelete) -> Result<(), Error> {
+        Self::forward(ctx, Message::ChatDelete { delete }).await;
+        Ok(())
+    }
+
+    async fn chat_purge(&mut self, ctx: &RaceContext<S>, purge: ChatPurge) -> Result<(), Error> {
+        Self::forward(ctx, Message::ChatPurge { purge }).await;
+        Ok(())
+    }
+
+    async fn error(&mut self, ctx: &RaceContext<S>, errors: Vec<String>) -> Result<(), Error> {
+        Self::forward(ctx, Message::Error { errors }).await;
+        Ok(())
+    }
+
+    async fn pong(&mut self, ctx: &RaceContext<S>) -> Result<(), Error> {
+        Self::forward(ctx, Message::Pong).await;
+        Ok(())
+    }
+
+    async fn race_data(&mut self, ctx: &RaceContext<S>, _old_race_data: RaceData) -> Result<(), Error> {
+        let race = ctx.data().await.clone();
+        Self::forward(ctx, Message::RaceData { race }).await;
+        Ok(())
+    }
+
+    async fn race_renders(&mut self, ctx: &RaceContext<S>) -> Result<(), Error> {
+        Self::forward(ctx, Message::RaceRenders).await;
+        Ok(())
+    }
+
+    async fn race_split(&mut self, ctx: &RaceContext<S>) -> Result<(), Error> {
+        Self::forward(ctx, Message::RaceSplit).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        futures::StreamExt as _,
+        super::*,
+    };
+
+    fn events(task: JoinHandle<Result<(), Error>>) -> (mpsc::Sender<BotEvent<()>>, BotEvents<()>) {
+        let (tx, rx) = mpsc::channel(16);
+        (tx, BotEvents { rx, shutdown: None, task: Some(task) })
+    }
+
+    fn removed(item: Option<Result<BotEvent<()>, Error>>) -> String {
+        match item {
+            Some(Ok(BotEvent::RoomRemoved(name))) => name,
+            Some(Ok(_)) => panic!("expected a removed room"),
+            Some(Err(e)) => panic!("unexpected error: {e}"),
+            None => panic!("stream ended early"),
+        }
+    }
+
+    #[tokio::test]
+    async fn yields_events_in_order_then_ends() {
+        let (finish, finished) = oneshot::channel::<()>();
+        let (tx, mut events) = events(tokio::spawn(async move {
+            let _ = finished.await;
+            Ok(())
+        }));
+        for name in ["a", "b", "c"] {
+            tx.send(BotEvent::RoomRemoved(name.to_owned())).await.expect("stream dropped");
+        }
+        assert_eq!(removed(events.next().await), "a");
+        let _ = finish.send(());
+        // events sent before the bot stopped are still delivered
+        assert_eq!(removed(events.next().await), "b");
+        assert_eq!(removed(events.next().await), "c");
+        drop(tx);
+        assert!(events.next().await.is_none());
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn yields_bot_error() {
+        let (tx, mut events) = events(tokio::spawn(async { Err(Error::EndOfStream) }));
+        tx.send(BotEvent::RoomRemoved("a".to_owned())).await.expect("stream dropped");
+        assert_eq!(removed(events.next().await), "a");
+        assert!(matches!(events.next().await, Some(Err(Error::EndOfStream))));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_signals_bot() {
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let (_tx, mut events) = events(tokio::spawn(async move {
+            let _ = shutdown_rx.await;
+            Ok(())
+        }));
+        events.shutdown = Some(shutdown);
+        events.shutdown();
+        // the sender is still alive, so the stream only ends because the bot task does
+        assert!(events.next().await.is_none());
+    }
+}
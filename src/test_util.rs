@@ -0,0 +1,127 @@
+//! Fixtures for unit tests.
+
+use {
+    std::sync::Arc,
+    chrono::{
+        Duration,
+        prelude::*,
+    },
+    tokio::sync::RwLock,
+    crate::{
+        handler::RaceContext,
+        model::*,
+        queue::SendQueue,
+    },
+};
+
+/// Returns a context for the given race which isn't connected, so actions stay in its send queue.
+pub(crate) fn context(race: RaceData) -> RaceContext<()> {
+    RaceContext {
+        global_state: Arc::default(),
+        data: Arc::new(RwLock::new(race)),
+        queue: Arc::new(SendQueue::default()),
+        actions: Arc::default(),
+        timers: Arc::default(),
+        events: None,
+        #[cfg(feature = "tracing")]
+        span: tracing::Span::none(),
+    }
+}
+
+pub(crate) fn user(name: &str) -> UserData {
+    UserData {
+        id: format!("user-{}", name.to_lowercase()),
+        full_name: format!("{name}#0000"),
+        name: name.to_owned(),
+        discriminator: Some("0000".to_owned()),
+        url: format!("/user/user-{}", name.to_lowercase()),
+        avatar: None,
+        pronouns: None,
+        flair: String::default(),
+        twitch_name: None,
+        twitch_display_name: None,
+        twitch_channel: None,
+        can_moderate: false,
+    }
+}
+
+pub(crate) fn entrant(name: &str, status: EntrantStatusValue) -> Entrant {
+    Entrant {
+        user: user(name),
+        status: EntrantStatus {
+            value: status,
+            verbose_value: format!("{status:?}"),
+            help_text: String::default(),
+        },
+        finish_time: None,
+        finished_at: None,
+        place: None,
+        place_ordinal: None,
+        score: None,
+        score_change: None,
+        comment: None,
+        has_comment: false,
+        stream_live: false,
+        stream_override: false,
+        team: None,
+    }
+}
+
+pub(crate) fn race_status(value: RaceStatusValue) -> RaceStatus {
+    RaceStatus {
+        verbose_value: format!("{value:?}"),
+        help_text: String::default(),
+        value,
+    }
+}
+
+pub(crate) fn race(status: RaceStatusValue) -> RaceData {
+    RaceData {
+        version: 1,
+        name: "test/clever-link-1234".to_owned(),
+        slug: "clever-link-1234".to_owned(),
+        category: CategorySummary {
+            name: "Test".to_owned(),
+            short_name: "test".to_owned(),
+            slug: "test".to_owned(),
+            url: "/test".to_owned(),
+            data_url: "/test/data".to_owned(),
+        },
+        status: race_status(status),
+        url: "/test/clever-link-1234".to_owned(),
+        data_url: "/test/clever-link-1234/data".to_owned(),
+        websocket_url: "/ws/race/clever-link-1234".to_owned(),
+        websocket_bot_url: "/ws/o/bot/clever-link-1234".to_owned(),
+        websocket_oauth_url: "/ws/o/race/clever-link-1234".to_owned(),
+        goal: Goal {
+            name: "Beat the game".to_owned(),
+            custom: false,
+        },
+        info: String::default(),
+        info_bot: None,
+        info_user: None,
+        entrants_count: 0,
+        entrants_count_finished: 0,
+        entrants_count_inactive: 0,
+        entrants: Vec::default(),
+        opened_at: Utc::now(),
+        start_delay: Duration::seconds(15),
+        started_at: None,
+        ended_at: None,
+        cancelled_at: None,
+        unlisted: false,
+        time_limit: Duration::hours(24),
+        streaming_required: false,
+        auto_start: true,
+        opened_by: Some(user("Owner")),
+        monitors: Vec::default(),
+        recordable: true,
+        recorded: false,
+        recorded_by: None,
+        allow_comments: true,
+        hide_comments: false,
+        allow_midrace_chat: true,
+        allow_non_entrant_chat: true,
+        chat_message_delay: Duration::zero(),
+    }
+}
@@ -0,0 +1,175 @@
+//! Per-room timers set using [`RaceContext::set_timer`](crate::handler::RaceContext::set_timer) and [`RaceContext::set_interval`](crate::handler::RaceContext::set_interval).
+
+use {
+    std::{
+        collections::HashMap,
+        time::Duration,
+    },
+    tokio::{
+        sync::Notify,
+        time::Instant,
+    },
+};
+
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+}
+
+#[derive(Default)]
+struct TimersState {
+    timers: HashMap<String, Timer>,
+    closed: bool,
+}
+
+/// The pending timers of a race room, delivered to [`RaceHandler::timer`](crate::RaceHandler::timer) by the room's event loop.
+#[derive(Default)]
+pub(crate) struct Timers {
+    state: std::sync::Mutex<TimersState>,
+    changed: Notify,
+}
+
+impl Timers {
+    /// Adds a timer, replacing any timer with the same name. Has no effect if the timers have been closed.
+    pub(crate) fn set(&self, name: String, delay: Duration, period: Option<Duration>) {
+        let mut state = self.state.lock().expect("timers lock poisoned");
+        if state.closed { return }
+        state.timers.insert(name, Timer { deadline: Instant::now() + delay, period });
+        self.changed.notify_one();
+    }
+
+    pub(crate) fn cancel(&self, name: &str) -> bool {
+        let removed = self.state.lock().expect("timers lock poisoned").timers.remove(name).is_some();
+        if removed {
+            self.changed.notify_one();
+        }
+        removed
+    }
+
+    /// Cancels all timers. If `close` is true, timers set from now on are ignored.
+    pub(crate) fn clear(&self, close: bool) {
+        let mut state = self.state.lock().expect("timers lock poisoned");
+        state.timers.clear();
+        state.closed |= close;
+        self.changed.notify_one();
+    }
+
+    /// Returns when the next timer is due.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.state.lock().expect("timers lock poisoned").timers.values().map(|timer| timer.deadline).min()
+    }
+
+    /// Waits until a timer is added or removed.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Returns the names of all timers which are due, in order of their deadlines. One-shot timers are removed and repeating timers are rescheduled.
+    pub(crate) fn take_due(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("timers lock poisoned");
+        let mut due = state.timers.iter()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .map(|(name, timer)| (timer.deadline, name.clone()))
+            .collect::<Vec<_>>();
+        due.sort();
+        for (_, name) in &due {
+            let timer = state.timers.get_mut(name).expect("due timer should exist");
+            if let Some(period) = timer.period {
+                // skip missed ticks rather than delivering them in a burst
+                let missed = (now - timer.deadline).as_nanos() / period.as_nanos();
+                timer.deadline += period * (u32::try_from(missed).unwrap_or(u32::MAX).saturating_add(1));
+            } else {
+                state.timers.remove(name);
+            }
+        }
+        due.into_iter().map(|(_, name)| name).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tokio::time::{
+            advance,
+            timeout,
+        },
+        super::*,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn fires_in_order() {
+        let timers = Timers::default();
+        timers.set("c".to_owned(), Duration::from_secs(3), None);
+        timers.set("a".to_owned(), Duration::from_secs(1), None);
+        timers.set("b".to_owned(), Duration::from_secs(2), None);
+        assert_eq!(timers.next_deadline(), Some(Instant::now() + Duration::from_secs(1)));
+        assert!(timers.take_due().is_empty());
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(timers.take_due(), ["a"]);
+        advance(Duration::from_secs(5)).await;
+        // overdue timers are delivered together, earliest deadline first
+        assert_eq!(timers.take_due(), ["b", "c"]);
+        assert_eq!(timers.next_deadline(), None);
+        assert!(timers.take_due().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replace_and_cancel() {
+        let timers = Timers::default();
+        timers.set("a".to_owned(), Duration::from_secs(1), None);
+        timers.set("a".to_owned(), Duration::from_secs(5), None);
+        timers.set("b".to_owned(), Duration::from_secs(2), None);
+        assert!(timers.cancel("b"));
+        assert!(!timers.cancel("b"));
+        advance(Duration::from_secs(2)).await;
+        assert!(timers.take_due().is_empty());
+        advance(Duration::from_secs(3)).await;
+        assert_eq!(timers.take_due(), ["a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn intervals() {
+        let timers = Timers::default();
+        timers.set("tick".to_owned(), Duration::from_secs(1), Some(Duration::from_secs(2)));
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(timers.take_due(), ["tick"]);
+        assert_eq!(timers.next_deadline(), Some(Instant::now() + Duration::from_secs(2)));
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(timers.take_due(), ["tick"]);
+        // missed ticks are skipped without shifting the schedule
+        advance(Duration::from_secs(7)).await;
+        assert_eq!(timers.take_due(), ["tick"]);
+        assert!(timers.take_due().is_empty());
+        assert_eq!(timers.next_deadline(), Some(Instant::now() + Duration::from_secs(1)));
+        assert!(timers.cancel("tick"));
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clear_and_close() {
+        let timers = Timers::default();
+        timers.set("a".to_owned(), Duration::from_secs(1), None);
+        timers.clear(false);
+        assert_eq!(timers.next_deadline(), None);
+        timers.set("b".to_owned(), Duration::from_secs(1), None);
+        timers.clear(true);
+        timers.set("c".to_owned(), Duration::from_secs(1), None);
+        advance(Duration::from_secs(1)).await;
+        assert!(timers.take_due().is_empty());
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn notifies_changes() {
+        let timers = Timers::default();
+        assert!(timeout(Duration::from_secs(1), timers.changed()).await.is_err());
+        timers.set("a".to_owned(), Duration::from_secs(1), None);
+        // the notification is kept until the event loop waits for it
+        assert!(timeout(Duration::from_secs(1), timers.changed()).await.is_ok());
+        assert!(!timers.cancel("b"));
+        assert!(timeout(Duration::from_secs(1), timers.changed()).await.is_err());
+        assert!(timers.cancel("a"));
+        assert!(timeout(Duration::from_secs(1), timers.changed()).await.is_ok());
+    }
+}
@@ -0,0 +1,52 @@
+//! Pluggable transports for race room WebSocket connections.
+//!
+//! By default, the bot connects to race rooms using [`TungsteniteConnector`]. A different [`Connector`] can be set using [`Bot::set_connector`](crate::Bot::set_connector), e.g. to route connections through a proxy, to use in-memory streams in tests, or to use a different WebSocket library.
+
+use {
+    std::pin::Pin,
+    async_trait::async_trait,
+    futures::{
+        Sink,
+        SinkExt as _,
+        Stream,
+        StreamExt as _,
+        TryStreamExt as _,
+    },
+    tokio::net::TcpStream,
+    tokio_tungstenite::tungstenite,
+    crate::{
+        Error,
+        HostInfo,
+    },
+};
+
+/// The receiving half of a race room connection.
+pub type MessageStream = Pin<Box<dyn Stream<Item = Result<tungstenite::Message, Error>> + Send>>;
+/// The sending half of a race room connection.
+pub type MessageSink = Pin<Box<dyn Sink<tungstenite::Message, Error = Error> + Send>>;
+
+/// Opens WebSocket connections to race rooms.
+///
+/// Errors should be reported as [`Error::Io`] or [`Error::Tungstenite`] where possible, since other errors are never considered transient by the [`ReconnectPolicy`](crate::bot::ReconnectPolicy).
+///
+/// This trait should be implemented using the [`macro@async_trait`] attribute.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    /// Opens a connection to the race room on the given host. `request` is the WebSocket handshake request, including the URL and the `Authorization` header.
+    async fn connect(&self, host_info: &HostInfo, request: http::Request<()>) -> Result<(MessageSink, MessageStream), Error>;
+}
+
+/// The default [`Connector`], which connects directly via TCP using [`tokio_tungstenite`], with TLS if the [`HostInfo`] is secure.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TungsteniteConnector;
+
+#[async_trait]
+impl Connector for TungsteniteConnector {
+    async fn connect(&self, host_info: &HostInfo, request: http::Request<()>) -> Result<(MessageSink, MessageStream), Error> {
+        let (ws_conn, _) = tokio_tungstenite::client_async_tls(
+            request, TcpStream::connect(host_info.websocket_socketaddrs()).await?,
+        ).await?;
+        let (sink, stream) = ws_conn.split();
+        Ok((Box::pin(sink.sink_map_err(Error::from)), Box::pin(stream.map_err(Error::from))))
+    }
+}
@@ -0,0 +1,219 @@
+#![cfg(feature = "mock")]
+#![allow(clippy::result_large_err)]
+
+use {
+    std::{
+        future,
+        sync::Arc,
+        time::Duration,
+    },
+    async_trait::async_trait,
+    tokio::{
+        sync::{
+            mpsc,
+            oneshot,
+        },
+        time::timeout,
+    },
+    racetime::{
+        Bot,
+        Error,
+        RaceHandler,
+        bot::{
+            BotBuilder,
+            ErrorContext,
+            ReconnectPolicy,
+        },
+        handler::{
+            ErrorAction,
+            RaceContext,
+        },
+        mock::{
+            MockServer,
+            mock_user,
+        },
+        model::ChatMessage,
+        queue::RateLimit,
+    },
+};
+
+const CATEGORY: &str = "test";
+const SLUG: &str = "clever-link-1234";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A race handler which reports its callbacks to the test.
+struct Handler;
+
+#[async_trait]
+impl RaceHandler<mpsc::UnboundedSender<&'static str>> for Handler {
+    async fn new(ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<Self, Error> {
+        let _ = ctx.global_state.send("new");
+        Ok(Self)
+    }
+
+    async fn disconnected(&mut self, ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>, _reason: &str) -> Result<(), Error> {
+        let _ = ctx.global_state.send("disconnected");
+        Ok(())
+    }
+
+    async fn reconnected(&mut self, ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<(), Error> {
+        let _ = ctx.global_state.send("reconnected");
+        Ok(())
+    }
+
+    async fn end(self, ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<(), Error> {
+        let _ = ctx.global_state.send("end");
+        Ok(())
+    }
+}
+
+/// A race handler whose chat message callback never returns.
+struct StuckHandler;
+
+#[async_trait]
+impl RaceHandler<mpsc::UnboundedSender<&'static str>> for StuckHandler {
+    async fn new(ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<Self, Error> {
+        let _ = ctx.global_state.send("new");
+        Ok(Self)
+    }
+
+    async fn chat_message(&mut self, ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>, _message: ChatMessage) -> Result<(), Error> {
+        let _ = ctx.global_state.send("stuck");
+        future::pending().await
+    }
+
+    async fn end(self, ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<(), Error> {
+        let _ = ctx.global_state.send("end");
+        Ok(())
+    }
+}
+
+/// A race handler which can't be created.
+struct FailingHandler;
+
+#[async_trait]
+impl RaceHandler<mpsc::UnboundedSender<&'static str>> for FailingHandler {
+    async fn new(ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>) -> Result<Self, Error> {
+        let _ = ctx.global_state.send("new");
+        Err(Error::Custom("simulated failure".into()))
+    }
+
+    async fn on_error(_ctx: &RaceContext<mpsc::UnboundedSender<&'static str>>, _error: Error, _context: ErrorContext) -> ErrorAction {
+        ErrorAction::Continue
+    }
+}
+
+async fn next_callback(rx: &mut mpsc::UnboundedReceiver<&'static str>) -> &'static str {
+    timeout(TIMEOUT, rx.recv()).await.expect("timed out waiting for race handler callback").expect("bot state dropped")
+}
+
+#[tokio::test]
+async fn shutdown_while_reconnecting() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_secs(60 * 60),
+            max_delay: Duration::from_secs(60 * 60),
+            max_attempts: None,
+            ..ReconnectPolicy::default()
+        })
+        .build().await?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let bot = tokio::spawn(bot.run_until::<Handler, _, _>(async move { let _ = shutdown_rx.await; }));
+    assert_eq!(next_callback(&mut rx).await, "new");
+    server.disconnect(SLUG).await;
+    assert_eq!(next_callback(&mut rx).await, "disconnected");
+    drop(shutdown_tx);
+    timeout(TIMEOUT, bot).await.expect("bot did not shut down while reconnecting")??;
+    assert_eq!(next_callback(&mut rx).await, "end");
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_aborts_stuck_rooms() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .shutdown_timeout(Duration::from_millis(200))
+        .build().await?;
+    let rooms = bot.rooms();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let bot = tokio::spawn(bot.run_until::<StuckHandler, _, _>(async move { let _ = shutdown_rx.await; }));
+    assert_eq!(next_callback(&mut rx).await, "new");
+    server.chat(SLUG, &mock_user("Alice"), "hello").await;
+    assert_eq!(next_callback(&mut rx).await, "stuck");
+    drop(shutdown_tx);
+    timeout(Duration::from_secs(1), bot).await.expect("bot did not shut down within its shutdown timeout")??;
+    assert!(rooms.names().is_empty());
+    assert!(rx.try_recv().is_err(), "stuck race handler was ended");
+    Ok(())
+}
+
+#[tokio::test]
+async fn failing_handler_is_retried_with_backoff() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(100),
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        })
+        .build().await?;
+    let bot = tokio::spawn(bot.run::<FailingHandler>());
+    assert_eq!(next_callback(&mut rx).await, "new");
+    tokio::time::sleep(Duration::from_millis(450)).await;
+    bot.abort();
+    let mut attempts = 1;
+    while rx.try_recv().is_ok() {
+        attempts += 1;
+    }
+    assert!((3..=6).contains(&attempts), "race handler was created {attempts} times");
+    Ok(())
+}
+
+#[tokio::test]
+async fn builder_rejects_invalid_config() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let builder = || Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(())).host_info(server.host_info());
+    type Configure = fn(BotBuilder<()>) -> BotBuilder<()>;
+    let invalid: [(&str, Configure); 8] = [
+        ("zero scan interval", |builder| builder.scan_interval(Duration::ZERO)),
+        ("zero extra room capacity", |builder| builder.extra_room_capacity(0)),
+        ("zero concurrent rooms", |builder| builder.max_concurrent_rooms(Some(0))),
+        ("zero ping interval", |builder| builder.ping_interval(Some(Duration::ZERO))),
+        ("zero pong timeout", |builder| builder.pong_timeout(Duration::ZERO)),
+        ("reconnect delays", |builder| builder.reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(1),
+            ..ReconnectPolicy::default()
+        })),
+        ("user agent suffix with custom client", |builder| builder.client(reqwest::Client::new()).user_agent_suffix("test")),
+        ("invalid user agent suffix", |builder| builder.user_agent_suffix("line\nbreak")),
+    ];
+    // if any of the invalid configurations contacted the server, it would receive this failure instead of the valid one below
+    server.fail_requests(503, 1).await;
+    for (name, configure) in invalid {
+        match configure(builder()).build().await {
+            Err(Error::InvalidConfig(_)) => {}
+            Err(e) => panic!("expected an invalid config error for {name}, got {e:?}"),
+            Ok(_) => panic!("expected an invalid config error for {name}, got a bot"),
+        }
+    }
+    assert!(matches!(builder().build().await, Err(Error::Reqwest(_))));
+    builder()
+        .max_concurrent_rooms(Some(1))
+        .ping_interval(None)
+        .room_rate_limit(Some(RateLimit::new(1, Duration::from_secs(1))))
+        .user_agent_suffix("test")
+        .build().await?;
+    Ok(())
+}
@@ -0,0 +1,210 @@
+#![allow(clippy::large_enum_variant)]
+
+use {
+    std::time::Duration as StdDuration,
+    chrono::{
+        Duration,
+        prelude::*,
+    },
+    racetime::{
+        command::{
+            CommandInfo,
+            FromArg,
+            Permission,
+            RaceCommand,
+        },
+        model::*,
+    },
+};
+
+#[derive(Debug, PartialEq, Eq, FromArg)]
+enum Preset {
+    Standard,
+    #[command(name = "hard", alias = "h")]
+    Difficult,
+}
+
+#[derive(Debug, PartialEq, Eq, RaceCommand)]
+enum Cmd {
+    /// Roll a seed
+    /// with the given preset
+    #[command(alias = "roll", alias = "r")]
+    Seed { preset: Option<Preset> },
+    #[command(entrant)]
+    Ready,
+    #[command(monitor)]
+    Lock,
+    #[command(moderator, name = "kick")]
+    Remove { user: UserData },
+    #[command(anyone)]
+    Say {
+        #[command(rest)]
+        text: String,
+    },
+    Note(u32, #[command(rest, name = "text")] Option<String>),
+    Wait {
+        #[command(name = "duration")]
+        delay: StdDuration,
+        again: Option<bool>,
+    },
+}
+
+fn user(name: &str) -> UserData {
+    UserData {
+        id: format!("user-{}", name.to_lowercase()),
+        full_name: format!("{name}#0000"),
+        name: name.to_owned(),
+        discriminator: Some("0000".to_owned()),
+        url: format!("/user/user-{}", name.to_lowercase()),
+        avatar: None,
+        pronouns: None,
+        flair: String::default(),
+        twitch_name: None,
+        twitch_display_name: None,
+        twitch_channel: None,
+        can_moderate: false,
+    }
+}
+
+/// Returns race data with Alice entered and Owner as the race monitor who opened the room.
+fn race() -> RaceData {
+    RaceData {
+        version: 1,
+        name: "test/clever-link-1234".to_owned(),
+        slug: "clever-link-1234".to_owned(),
+        category: CategorySummary {
+            name: "Test".to_owned(),
+            short_name: "test".to_owned(),
+            slug: "test".to_owned(),
+            url: "/test".to_owned(),
+            data_url: "/test/data".to_owned(),
+        },
+        status: RaceStatus {
+            value: RaceStatusValue::Open,
+            verbose_value: "Open".to_owned(),
+            help_text: String::default(),
+        },
+        url: "/test/clever-link-1234".to_owned(),
+        data_url: "/test/clever-link-1234/data".to_owned(),
+        websocket_url: "/ws/race/clever-link-1234".to_owned(),
+        websocket_bot_url: "/ws/o/bot/clever-link-1234".to_owned(),
+        websocket_oauth_url: "/ws/o/race/clever-link-1234".to_owned(),
+        goal: Goal {
+            name: "Beat the game".to_owned(),
+            custom: false,
+        },
+        info: String::default(),
+        info_bot: None,
+        info_user: None,
+        entrants_count: 1,
+        entrants_count_finished: 0,
+        entrants_count_inactive: 0,
+        entrants: vec![Entrant {
+            user: user("Alice"),
+            status: EntrantStatus {
+                value: EntrantStatusValue::NotReady,
+                verbose_value: "Not ready".to_owned(),
+                help_text: String::default(),
+            },
+            finish_time: None,
+            finished_at: None,
+            place: None,
+            place_ordinal: None,
+            score: None,
+            score_change: None,
+            comment: None,
+            has_comment: false,
+            stream_live: false,
+            stream_override: false,
+            team: None,
+        }],
+        opened_at: Utc::now(),
+        start_delay: Duration::seconds(15),
+        started_at: None,
+        ended_at: None,
+        cancelled_at: None,
+        unlisted: false,
+        time_limit: Duration::hours(24),
+        streaming_required: false,
+        auto_start: true,
+        opened_by: Some(user("Owner")),
+        monitors: Vec::default(),
+        recordable: true,
+        recorded: false,
+        recorded_by: None,
+        allow_comments: true,
+        hide_comments: false,
+        allow_midrace_chat: true,
+        allow_non_entrant_chat: true,
+        chat_message_delay: Duration::zero(),
+    }
+}
+
+fn parse(name: &str, args: &[&str]) -> Result<Cmd, String> {
+    Cmd::parse_args(&race(), name, args.iter().map(|&arg| arg.to_owned()).collect())
+}
+
+#[test]
+fn command_info() {
+    let info = |name: &str, aliases: &[&str], description: Option<&str>, usage: &str, permission| CommandInfo {
+        name: name.to_owned(),
+        aliases: aliases.iter().map(|&alias| alias.to_owned()).collect(),
+        description: description.map(str::to_owned),
+        usage: usage.to_owned(),
+        permission,
+    };
+    assert_eq!(Cmd::commands(), [
+        info("seed", &["roll", "r"], Some("Roll a seed with the given preset"), "!seed [standard|hard]", Permission::Anyone),
+        info("ready", &[], None, "!ready", Permission::Entrant),
+        info("lock", &[], None, "!lock", Permission::Monitor),
+        info("kick", &[], None, "!kick <user>", Permission::Moderator),
+        info("say", &[], None, "!say <text…>", Permission::Anyone),
+        info("note", &[], None, "!note <arg1> [text…]", Permission::Anyone),
+        info("wait", &[], None, "!wait <duration> [yes|no]", Permission::Anyone),
+    ]);
+}
+
+#[test]
+fn optional_args() {
+    assert_eq!(parse("seed", &[]), Ok(Cmd::Seed { preset: None }));
+    assert_eq!(parse("seed", &["Standard"]), Ok(Cmd::Seed { preset: Some(Preset::Standard) }));
+    assert_eq!(parse("seed", &["standard", "hard"]), Err("too many arguments".to_owned()));
+    assert_eq!(parse("wait", &["1m30s"]), Ok(Cmd::Wait { delay: StdDuration::from_secs(90), again: None }));
+    assert_eq!(parse("wait", &["90", "yes"]), Ok(Cmd::Wait { delay: StdDuration::from_secs(90), again: Some(true) }));
+    assert_eq!(parse("wait", &[]), Err("missing <duration>".to_owned()));
+    assert_eq!(parse("wait", &["soon"]), Err(r#""soon" is not a valid duration"#.to_owned()));
+}
+
+#[test]
+fn rest_args() {
+    assert_eq!(parse("say", &["hello", "world"]), Ok(Cmd::Say { text: "hello world".to_owned() }));
+    assert_eq!(parse("say", &[]), Err("missing <text…>".to_owned()));
+    assert_eq!(parse("note", &["5"]), Ok(Cmd::Note(5, None)));
+    assert_eq!(parse("note", &["5", "good", "seed"]), Ok(Cmd::Note(5, Some("good seed".to_owned()))));
+    assert_eq!(parse("note", &["five"]), Err(r#""five" is not a valid number"#.to_owned()));
+}
+
+#[test]
+fn unit_and_user_args() {
+    assert_eq!(parse("ready", &[]), Ok(Cmd::Ready));
+    assert_eq!(parse("lock", &["now"]), Err("too many arguments".to_owned()));
+    assert_eq!(parse("kick", &["@alice"]), Ok(Cmd::Remove { user: user("Alice") }));
+    assert_eq!(parse("kick", &["Bob"]), Err(r#"could not find user "Bob" in this race"#.to_owned()));
+    assert_eq!(parse("kick", &[]), Err("missing <user>".to_owned()));
+    assert_eq!(parse("remove", &[]), Err("unknown command !remove".to_owned()));
+}
+
+#[test]
+fn from_arg_enum() {
+    let race = race();
+    assert_eq!(Preset::usage().as_deref(), Some("standard|hard"));
+    assert_eq!(Preset::from_arg(&race, "standard"), Ok(Preset::Standard));
+    assert_eq!(Preset::from_arg(&race, "HARD"), Ok(Preset::Difficult));
+    assert_eq!(Preset::from_arg(&race, "h"), Ok(Preset::Difficult));
+    assert_eq!(Preset::from_arg(&race, "difficult"), Err(r#""difficult" is not one of standard, hard"#.to_owned()));
+}
+
+#[test]
+fn compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
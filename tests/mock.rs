@@ -0,0 +1,482 @@
+#![cfg(feature = "mock")]
+#![allow(clippy::result_large_err)]
+
+use {
+    std::{
+        io,
+        sync::{
+            Arc,
+            atomic::{
+                AtomicBool,
+                AtomicUsize,
+                Ordering::SeqCst,
+            },
+        },
+        time::Duration,
+    },
+    async_trait::async_trait,
+    futures::{
+        SinkExt as _,
+        StreamExt as _,
+    },
+    tokio::{
+        sync::mpsc,
+        task::JoinHandle,
+        time::timeout,
+    },
+    tokio_tungstenite::tungstenite,
+    racetime::{
+        Bot,
+        Error,
+        HostInfo,
+        RaceHandler,
+        StartRace,
+        auth::{
+            ClientCredentials,
+            TokenProvider,
+        },
+        authorize_with_host,
+        bot::ReconnectPolicy,
+        handler::RaceContext,
+        mock::{
+            MockServer,
+            mock_user,
+        },
+        model::*,
+        stream::{
+            BotEvent,
+            BotEvents,
+        },
+        transport::{
+            Connector,
+            MessageSink,
+            MessageStream,
+            TungsteniteConnector,
+        },
+    },
+};
+
+const CATEGORY: &str = "test";
+const SLUG: &str = "clever-link-1234";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+type Events = mpsc::UnboundedSender<String>;
+
+/// A race handler which reports its callbacks, non-empty chat histories, and failed echoes to the test and supports the commands `!echo <text>`, `!info <text>`, and `!note <text>`.
+struct Handler;
+
+#[async_trait]
+impl RaceHandler<Events> for Handler {
+    async fn new(ctx: &RaceContext<Events>) -> Result<Self, Error> {
+        let _ = ctx.global_state.send(format!("new {}", ctx.data().await.slug));
+        Ok(Self)
+    }
+
+    async fn command(&mut self, ctx: &RaceContext<Events>, cmd_name: String, args: Vec<String>, _is_moderator: bool, _is_monitor: bool, _msg: &ChatMessage) -> Result<(), Error> {
+        match &*cmd_name {
+            "echo" => {
+                if let Err(e) = ctx.send_message(&args.join(" ")).await {
+                    let _ = ctx.global_state.send(format!("echo failed: {e}"));
+                }
+                Ok(())
+            }
+            "info" => ctx.set_bot_raceinfo(&args.join(" ")).await,
+            "note" => {
+                let _ = ctx.global_state.send(format!("note {}", args.join(" ")));
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn chat_history(&mut self, ctx: &RaceContext<Events>, history: Vec<ChatMessage>) -> Result<(), Error> {
+        if !history.is_empty() {
+            let _ = ctx.global_state.send(format!("history {}", history.iter().map(|message| &*message.message).collect::<Vec<_>>().join(", ")));
+        }
+        Ok(())
+    }
+
+    async fn race_data(&mut self, ctx: &RaceContext<Events>, _old_race_data: RaceData) -> Result<(), Error> {
+        let _ = ctx.global_state.send(format!("info {}", ctx.data().await.info));
+        Ok(())
+    }
+
+    async fn disconnected(&mut self, ctx: &RaceContext<Events>, reason: &str) -> Result<(), Error> {
+        let _ = ctx.global_state.send(format!("disconnected: {reason}"));
+        Ok(())
+    }
+
+    async fn reconnected(&mut self, ctx: &RaceContext<Events>) -> Result<(), Error> {
+        let _ = ctx.global_state.send("reconnected".to_owned());
+        Ok(())
+    }
+}
+
+/// A token provider which counts how often it's asked for a token.
+struct CountingTokenProvider {
+    inner: ClientCredentials,
+    calls: AtomicUsize,
+}
+
+#[async_trait]
+impl TokenProvider for CountingTokenProvider {
+    async fn access_token(&self) -> Result<String, Error> {
+        self.calls.fetch_add(1, SeqCst);
+        self.inner.access_token().await
+    }
+}
+
+/// A connector which fails to send the first chat message as if the connection had been lost, while still receiving messages from the server.
+#[derive(Default)]
+struct BrokenPipeConnector {
+    failed: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl Connector for BrokenPipeConnector {
+    async fn connect(&self, host_info: &HostInfo, request: http::Request<()>) -> Result<(MessageSink, MessageStream), Error> {
+        let (sink, stream) = TungsteniteConnector.connect(host_info, request).await?;
+        let failed = Arc::clone(&self.failed);
+        let sink = sink.with(move |message: tungstenite::Message| {
+            let fail = matches!(message, tungstenite::Message::Text(ref text) if text.contains(r#""action":"message""#)) && !failed.swap(true, SeqCst);
+            async move { if fail { Err(Error::Io(io::ErrorKind::BrokenPipe.into())) } else { Ok(message) } }
+        });
+        Ok((Box::pin(sink), stream))
+    }
+}
+
+/// A reconnect policy without delays, so the tests don't have to wait.
+fn fast_reconnect() -> ReconnectPolicy {
+    ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+        ..ReconnectPolicy::default()
+    }
+}
+
+async fn start_bot(server: &MockServer, ping_interval: Option<Duration>) -> Result<(JoinHandle<Result<(), Error>>, mpsc::UnboundedReceiver<String>), Error> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .scan_interval(Duration::from_millis(100))
+        .ping_interval(ping_interval)
+        .pong_timeout(Duration::from_millis(100))
+        .reconnect_policy(fast_reconnect())
+        .build().await?;
+    let task = tokio::spawn(async move { match bot.run::<Handler>().await? {} });
+    Ok((task, rx))
+}
+
+async fn next_event(rx: &mut mpsc::UnboundedReceiver<String>) -> String {
+    timeout(TIMEOUT, rx.recv()).await.expect("timed out waiting for race handler callback").expect("bot state dropped")
+}
+
+/// Waits for an event matching the predicate, skipping others such as `race.data` updates.
+async fn wait_for(rx: &mut mpsc::UnboundedReceiver<String>, pred: impl Fn(&str) -> bool) -> String {
+    loop {
+        let event = next_event(rx).await;
+        if pred(&event) { break event }
+    }
+}
+
+async fn next_stream_event(events: &mut BotEvents<()>) -> Option<Result<BotEvent<()>, Error>> {
+    timeout(TIMEOUT, events.next()).await.expect("timed out waiting for stream event")
+}
+
+/// Sends a chat message and waits for the bot's reply.
+async fn assert_echo(server: &MockServer, text: &str) {
+    server.chat(SLUG, &mock_user("Alice"), &format!("!echo {text}")).await;
+    let action = timeout(TIMEOUT, async {
+        loop {
+            let action = server.next_action().await;
+            if action.action == "message" { break action }
+        }
+    }).await.expect("timed out waiting for reply");
+    assert_eq!(action.race, SLUG);
+    assert_eq!(action.data["message"], text);
+}
+
+#[tokio::test]
+async fn token_endpoint() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let client = reqwest::Client::new();
+    let (first_token, lifetime) = authorize_with_host(&server.host_info(), "client-id", "client-secret", &client).await?;
+    assert_eq!(lifetime, Duration::from_secs(10 * 60 * 60));
+    server.set_token_lifetime(Duration::from_secs(60)).await;
+    let (second_token, lifetime) = authorize_with_host(&server.host_info(), "client-id", "client-secret", &client).await?;
+    assert_eq!(lifetime, Duration::from_secs(60));
+    assert_ne!(first_token, second_token);
+    Ok(())
+}
+
+#[tokio::test]
+async fn fail_requests() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let client = reqwest::Client::new();
+    server.fail_requests(503, 2).await;
+    for _ in 0..2 {
+        match authorize_with_host(&server.host_info(), "client-id", "client-secret", &client).await {
+            Err(Error::Reqwest(e)) => assert_eq!(e.status(), Some(reqwest::StatusCode::SERVICE_UNAVAILABLE)),
+            res => panic!("expected a server error, got {res:?}"),
+        }
+    }
+    authorize_with_host(&server.host_info(), "client-id", "client-secret", &client).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn start_and_edit_with_token_provider() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let client = reqwest::Client::new();
+    let token_provider = ClientCredentials::new_with_host(server.host_info(), "client-id", "client-secret", client.clone());
+    let mut race = StartRace {
+        goal: "Beat the game".to_owned(),
+        goal_is_custom: false,
+        team_race: false,
+        invitational: false,
+        unlisted: false,
+        info_user: String::default(),
+        info_bot: "Seed: 1234".to_owned(),
+        require_even_teams: false,
+        start_delay: 15,
+        time_limit: 24,
+        time_limit_auto_complete: false,
+        streaming_required: false,
+        auto_start: true,
+        allow_comments: true,
+        hide_comments: false,
+        allow_prerace_chat: true,
+        allow_midrace_chat: true,
+        allow_non_entrant_chat: true,
+        chat_message_delay: 0,
+    };
+    let slug = race.start_with_token_provider_and_host(&server.host_info(), &token_provider, &client, CATEGORY).await?;
+    assert_eq!(server.race(&slug).await.map(|race| race.info), Some("Seed: 1234".to_owned()));
+    race.info_bot = "Seed: 5678".to_owned();
+    race.edit_with_token_provider_and_host(&server.host_info(), &token_provider, &client, CATEGORY, &slug).await?;
+    assert_eq!(server.race(&slug).await.map(|race| race.info), Some("Seed: 5678".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn shared_token_provider() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let new_provider = || Arc::new(CountingTokenProvider {
+        inner: ClientCredentials::new_with_host(server.host_info(), "client-id", "client-secret", reqwest::Client::new()),
+        calls: AtomicUsize::default(),
+    });
+    let token_provider = new_provider();
+    let mut bot = Bot::builder_with_token_provider(CATEGORY, token_provider.clone(), Arc::new(()))
+        .host_info(server.host_info())
+        .build().await?;
+    assert_eq!(token_provider.calls.load(SeqCst), 1);
+    assert!(Arc::ptr_eq(&bot.token_provider().await, &(token_provider.clone() as Arc<dyn TokenProvider>)));
+    bot.add_category_with_token_provider("other", token_provider.clone()).await?;
+    assert_eq!(token_provider.calls.load(SeqCst), 1);
+    let other_provider = new_provider();
+    bot.add_category_with_token_provider("third", other_provider.clone()).await?;
+    assert_eq!(token_provider.calls.load(SeqCst), 1);
+    assert_eq!(other_provider.calls.load(SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_picks_up_room() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    let (bot, mut rx) = start_bot(&server, None).await?;
+    server.open_race("unlisted-race-1234").await;
+    server.update_race("unlisted-race-1234", |race| race.unlisted = true).await;
+    server.open_race(SLUG).await;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    assert_eq!(server.connection_count(SLUG).await, 1);
+    assert_eq!(server.connection_count("unlisted-race-1234").await, 0);
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn chat_and_setinfo_round_trip() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (bot, mut rx) = start_bot(&server, None).await?;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    assert_echo(&server, "hello").await;
+    server.chat(SLUG, &mock_user("Alice"), "!info Seed: 1234").await;
+    assert_eq!(wait_for(&mut rx, |event| event.starts_with("info ")).await, "info Seed: 1234");
+    assert_eq!(server.race(SLUG).await.and_then(|race| race.info_bot).as_deref(), Some("Seed: 1234"));
+    let setinfo = server.actions().await.into_iter().find(|action| action.action == "setinfo").expect("no setinfo action recorded");
+    assert_eq!(setinfo.data["info_bot"], "Seed: 1234");
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_disconnect() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (bot, mut rx) = start_bot(&server, None).await?;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    server.disconnect(SLUG).await;
+    assert!(next_event(&mut rx).await.starts_with("disconnected: "));
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert_eq!(server.connection_count(SLUG).await, 1);
+    assert_echo(&server, "still here").await;
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn chat_history_after_reconnect() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    server.chat(SLUG, &mock_user("Alice"), "!note before").await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .scan_interval(Duration::from_millis(100))
+        // slow enough to post a message while the bot is disconnected
+        .reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_millis(500),
+            ..fast_reconnect()
+        })
+        .build().await?;
+    let bot = tokio::spawn(bot.run::<Handler>());
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    assert_eq!(next_event(&mut rx).await, "history !note before");
+    server.chat(SLUG, &mock_user("Alice"), "!note live").await;
+    assert_eq!(next_event(&mut rx).await, "note live");
+    server.disconnect(SLUG).await;
+    assert!(next_event(&mut rx).await.starts_with("disconnected: "));
+    server.chat(SLUG, &mock_user("Bob"), "missed").await;
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    // the replayed history only contains the message posted while disconnected
+    assert_eq!(next_event(&mut rx).await, "history missed");
+    server.chat(SLUG, &mock_user("Alice"), "!note after").await;
+    assert_eq!(next_event(&mut rx).await, "note after");
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_failed_handshake() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (bot, mut rx) = start_bot(&server, None).await?;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    server.fail_requests(503, 2).await;
+    server.disconnect(SLUG).await;
+    assert!(next_event(&mut rx).await.starts_with("disconnected: "));
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert_echo(&server, "still here").await;
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_failed_token_request() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    // expired tokens are refreshed before every connection attempt
+    server.set_token_lifetime(Duration::ZERO).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        // room scans would otherwise compete for the simulated failures
+        .scan_interval(Duration::from_secs(60))
+        .reconnect_policy(fast_reconnect())
+        .build().await?;
+    let bot = tokio::spawn(bot.run::<Handler>());
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    server.fail_requests(503, 2).await;
+    server.disconnect(SLUG).await;
+    assert!(next_event(&mut rx).await.starts_with("disconnected: "));
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert_echo(&server, "still here").await;
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_close() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (bot, mut rx) = start_bot(&server, None).await?;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    server.close(SLUG, 1012, "service restart").await;
+    assert_eq!(next_event(&mut rx).await, "disconnected: WebSocket connection closed by server with code 1012: service restart");
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert_echo(&server, "still here").await;
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_missing_pong() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    server.ignore_pings(true).await;
+    let (bot, mut rx) = start_bot(&server, Some(Duration::from_millis(100))).await?;
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    assert_eq!(next_event(&mut rx).await, "disconnected: no pong received within 100ms");
+    server.ignore_pings(false).await;
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert!(server.actions().await.iter().any(|action| action.action == "ping"));
+    // with pings answered, the connection stays up
+    assert!(timeout(Duration::from_millis(500), rx.recv()).await.is_err());
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_after_failed_send() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(tx))
+        .host_info(server.host_info())
+        .scan_interval(Duration::from_millis(100))
+        .reconnect_policy(fast_reconnect())
+        .connector(Arc::new(BrokenPipeConnector::default()))
+        .build().await?;
+    let bot = tokio::spawn(bot.run::<Handler>());
+    assert_eq!(next_event(&mut rx).await, format!("new {SLUG}"));
+    server.chat(SLUG, &mock_user("Alice"), "!echo lost").await;
+    // the callback waiting for the message must not keep the bot from noticing the lost connection
+    assert!(next_event(&mut rx).await.starts_with("echo failed: "));
+    assert!(next_event(&mut rx).await.starts_with("disconnected: "));
+    assert_eq!(next_event(&mut rx).await, "reconnected");
+    assert_echo(&server, "still here").await;
+    bot.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn event_stream() -> Result<(), Error> {
+    let server = MockServer::start(CATEGORY).await?;
+    server.open_race(SLUG).await;
+    server.chat(SLUG, &mock_user("Alice"), "first").await;
+    let bot = Bot::builder(CATEGORY, "client-id", "client-secret", Arc::new(()))
+        .host_info(server.host_info())
+        .scan_interval(Duration::from_millis(100))
+        .build().await?;
+    let mut events = bot.into_stream();
+    let Some(Ok(BotEvent::RoomAdded(ctx))) = next_stream_event(&mut events).await else { panic!("expected the room to be added") };
+    assert_eq!(ctx.data().await.slug, SLUG);
+    let Some(Ok(BotEvent::Message(_, Message::ChatHistory { messages }))) = next_stream_event(&mut events).await else { panic!("expected the chat history") };
+    assert_eq!(messages.into_iter().map(|message| message.message).collect::<Vec<_>>(), ["first"]);
+    server.chat(SLUG, &mock_user("Alice"), "second").await;
+    server.chat(SLUG, &mock_user("Bob"), "third").await;
+    for expected in ["second", "third"] {
+        let Some(Ok(BotEvent::Message(_, Message::ChatMessage { message }))) = next_stream_event(&mut events).await else { panic!("expected a chat message") };
+        assert_eq!(message.message, expected);
+    }
+    events.shutdown();
+    let Some(Ok(BotEvent::RoomRemoved(name))) = next_stream_event(&mut events).await else { panic!("expected the room to be removed") };
+    assert_eq!(name, format!("{CATEGORY}/{SLUG}"));
+    assert!(timeout(TIMEOUT, events.next()).await.expect("timed out waiting for the stream to end").is_none());
+    // sending to a removed room fails instead of hanging
+    assert!(timeout(TIMEOUT, ctx.send_message("too late")).await.expect("timed out sending").is_err());
+    Ok(())
+}
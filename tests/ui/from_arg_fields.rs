@@ -0,0 +1,9 @@
+use racetime::command::FromArg;
+
+#[derive(FromArg)]
+enum Preset {
+    Standard,
+    Custom(String),
+}
+
+fn main() {}
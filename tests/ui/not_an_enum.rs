@@ -0,0 +1,8 @@
+use racetime::command::RaceCommand;
+
+#[derive(RaceCommand)]
+struct Cmd {
+    preset: String,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use racetime::command::RaceCommand;
+
+#[derive(RaceCommand)]
+enum Cmd {
+    Say {
+        #[command(rest)]
+        text: String,
+        count: u32,
+    },
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use racetime::command::RaceCommand;
+
+#[derive(RaceCommand)]
+enum Cmd {
+    #[command(admin)]
+    Lock,
+}
+
+fn main() {}